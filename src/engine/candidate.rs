@@ -0,0 +1,30 @@
+use std::cmp::Ordering;
+
+/// An entry found by an exhaustive or frozen-tree search, ordered by distance
+/// and then by position so a `BinaryHeap` of them keeps the farthest on top.
+pub(crate) struct Candidate {
+    pub distance: f32,
+    pub position: usize,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.position.cmp(&other.position))
+    }
+}
@@ -0,0 +1,154 @@
+use crate::engine::{format::Format, types::*};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The collection used when no name is given.
+pub const DEFAULT_COLLECTION: &str = "default";
+
+/// Named indexes sharing one database, each with its own options.
+///
+/// Cloning is cheap: the clone shares the tree and maps of every index with
+/// the original until one side writes to them, which copies only the parts
+/// written to (copy-on-write). Each clone keeps its own usage counters. A clone
+/// works as a consistent snapshot for searches or serialization while the
+/// original keeps changing, or as a fork for changes that may be discarded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Collections {
+    pub indexes: HashMap<String, Index>,
+}
+
+impl Collections {
+    /// Creates a database holding only an empty default collection.
+    pub fn new() -> Self {
+        let mut indexes = HashMap::new();
+        indexes.insert(DEFAULT_COLLECTION.to_string(), Index::new());
+
+        Collections { indexes }
+    }
+
+    // A database holding `index` as its default collection.
+    pub(crate) fn from_legacy(index: Index) -> Self {
+        let mut indexes = HashMap::new();
+        indexes.insert(DEFAULT_COLLECTION.to_string(), index);
+
+        Collections { indexes }
+    }
+
+    pub fn create(&mut self, name: &str, options: IndexOptions) -> Result<&mut Index, EngineError> {
+        if self.indexes.contains_key(name) {
+            return Err(EngineError::new(format!(
+                "Collection {} already exists",
+                name
+            )));
+        }
+
+        let index = Index::with_options(options)?;
+
+        Ok(self.indexes.entry(name.to_string()).or_insert(index))
+    }
+
+    pub fn drop(&mut self, name: &str) -> Result<Index, EngineError> {
+        self.indexes
+            .remove(name)
+            .ok_or_else(|| EngineError::new(format!("Collection {} not found", name)))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Index> {
+        self.indexes.get(name)
+    }
+
+    /// Returns the named collection for writing. The parts a clone of the
+    /// collections still shares are copied when written to.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Index> {
+        self.indexes.get_mut(name)
+    }
+
+    // Returns the named collection for writing like `get_mut`, failing when
+    // it is missing.
+    pub(crate) fn find_mut(&mut self, name: &str) -> Result<&mut Index, EngineError> {
+        self.get_mut(name)
+            .ok_or_else(|| EngineError::new(format!("Collection {} not found", name)))
+    }
+
+    /// Returns the named collection for writing like `get_mut`, creating it
+    /// with default options when missing.
+    pub fn get_or_create(&mut self, name: &str) -> &mut Index {
+        self.indexes.entry(name.to_string()).or_default()
+    }
+
+    /// Runs `update` on the named collection, creating it with default options
+    /// when missing. A collection created for an `update` that fails is
+    /// removed again, so a failed write leaves no empty collection behind.
+    pub fn update_or_create<T, E>(
+        &mut self,
+        name: &str,
+        update: impl FnOnce(&mut Index) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let created = !self.indexes.contains_key(name);
+        let result = update(self.get_or_create(name));
+
+        if created && result.is_err() {
+            self.indexes.remove(name);
+        }
+
+        result
+    }
+
+    /// Whether the named collection shares any part with a clone, see
+    /// `Index::is_shared`.
+    pub fn is_shared(&self, name: &str) -> bool {
+        self.indexes.get(name).is_some_and(Index::is_shared)
+    }
+
+    /// Returns the collection names in sorted order.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.indexes.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+}
+
+impl Default for Collections {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serializes all collections, or only the named one, into a gzip compressed
+/// bincode snapshot.
+pub fn dump_collections(collections: &Collections, name: Option<&str>) -> Result<Vec<u8>, EngineError> {
+    let selected = match name {
+        Some(name) => {
+            let index = collections
+                .indexes
+                .get(name)
+                .ok_or_else(|| EngineError::new(format!("Collection {} not found", name)))?;
+
+            let mut indexes = HashMap::new();
+            indexes.insert(name.to_string(), index.clone());
+            Some(Collections { indexes })
+        }
+        None => None,
+    };
+
+    let mut encoder = GzEncoder::new(super::format::header(), Compression::default());
+    bincode::serialize_into(&mut encoder, selected.as_ref().unwrap_or(collections))?;
+
+    Ok(encoder.finish()?)
+}
+
+/// Restores collections from a snapshot created by `dump_collections`. The
+/// single index of a snapshot from a version without a format header becomes
+/// the default collection.
+pub fn load_collections(data: &[u8]) -> Result<Collections, EngineError> {
+    let (format, body) = super::format::read_header(data)?;
+    let mut decoder = GzDecoder::new(std::io::Cursor::new(body));
+
+    match format {
+        Format::Current => Ok(bincode::deserialize_from::<_, Collections>(&mut decoder)?),
+        Format::Legacy => Ok(Collections::from_legacy(super::format::read_legacy(decoder)?)),
+    }
+}
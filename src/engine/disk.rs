@@ -0,0 +1,526 @@
+use crate::engine::{types::*, Collections, MetadataFilter, Operation};
+use flate2::Crc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Directory layout:
+//
+//   MANIFEST-{sequence}       JSON `Manifest`, the segments of the checkpoint
+//   segment-{sequence}-{n}    one collection dumped by `engine::dump`
+//   log-{sequence}            the changes made since the checkpoint
+//
+// The log is a sequence of frames, numbers little-endian:
+//
+//   length   u32, of the payload
+//   crc      u32, CRC-32 of the payload
+//   payload  JSON `LogRecord`
+//
+// A checkpoint writes the segments of the changed collections and the next
+// manifest, renames the manifest into place and starts an empty log. Until
+// the rename the previous manifest and log stay current, so a crash at any
+// point recovers either state. A frame torn by a crash fails its length or
+// crc check and is dropped with everything after it. A frame passing both
+// checks whose payload doesn't parse is not torn, opening the store fails
+// instead of dropping it and the records after it.
+const MANIFEST: &str = "MANIFEST-";
+const SEGMENT: &str = "segment-";
+const LOG: &str = "log-";
+const TMP: &str = ".tmp";
+
+/// How a `DiskStore` writes its checkpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskStoreOptions {
+    /// Checkpoint once the log holds this many records, 1000 by default. With
+    /// 0 only `DiskStore::checkpoint` does.
+    pub checkpoint_after: usize,
+}
+
+impl Default for DiskStoreOptions {
+    fn default() -> Self {
+        DiskStoreOptions {
+            checkpoint_after: 1000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    sequence: u64,
+    // Collection name -> segment file.
+    segments: BTreeMap<String, String>,
+}
+
+// One change, replayed on open.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum LogRecord {
+    Create {
+        name: String,
+        options: IndexOptions,
+    },
+    Drop {
+        name: String,
+    },
+    // The ids evicted by the transaction, replay removes them instead of
+    // evicting as usage is not logged.
+    Transact {
+        collection: String,
+        operations: Vec<Operation>,
+        evicted: Vec<String>,
+    },
+    Clear {
+        collection: String,
+    },
+    Freeze {
+        collection: String,
+    },
+}
+
+impl LogRecord {
+    fn collection(&self) -> &str {
+        match self {
+            LogRecord::Create { name, .. } | LogRecord::Drop { name } => name,
+            LogRecord::Transact { collection, .. }
+            | LogRecord::Clear { collection }
+            | LogRecord::Freeze { collection } => collection,
+        }
+    }
+
+    fn replay(self, collections: &mut Collections) -> Result<(), EngineError> {
+        match self {
+            LogRecord::Create { name, options } => {
+                collections.create(&name, options)?;
+            }
+            LogRecord::Drop { name } => {
+                collections.drop(&name)?;
+            }
+            LogRecord::Transact {
+                collection,
+                operations,
+                evicted,
+            } => {
+                let index = collections.get_or_create(&collection);
+
+                super::apply_all(index, operations)
+                    .map_err(|err| EngineError::new(err.to_string()))?;
+                super::remove(index, &evicted)?;
+            }
+            LogRecord::Clear { collection } => super::clear(collections.find_mut(&collection)?)?,
+            LogRecord::Freeze { collection } => super::freeze(collections.find_mut(&collection)?),
+        }
+
+        Ok(())
+    }
+}
+
+/// Collections persisted in a directory on the local filesystem.
+///
+/// Every change is appended to a log and synced before the call returns, so it
+/// survives a crash. Once the log is long enough the changed collections are
+/// written as segments, see `checkpoint`. Opening the directory after a crash
+/// recovers every change that completed, a change interrupted by the crash is
+/// lost as a whole.
+///
+/// Changes are applied in memory before they are logged. If logging fails the
+/// store refuses further changes, reopen it to continue from what was logged.
+pub struct DiskStore {
+    dir: PathBuf,
+    options: DiskStoreOptions,
+    collections: Collections,
+    sequence: u64,
+    // The segments of the current manifest.
+    segments: BTreeMap<String, String>,
+    // Collections changed since the current manifest.
+    dirty: HashSet<String>,
+    log: File,
+    records: usize,
+    failed: bool,
+}
+
+impl DiskStore {
+    /// Opens the store in `dir`, creating it when missing. A new store holds
+    /// only an empty default collection, like `Collections::new`.
+    pub fn open(dir: impl AsRef<Path>, options: DiskStoreOptions) -> Result<Self, EngineError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut sequence = None;
+
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+
+            if let Some(Ok(found)) = name.strip_prefix(MANIFEST).map(str::parse::<u64>) {
+                sequence = sequence.max(Some(found));
+            }
+        }
+
+        let (sequence, segments, mut collections) = match sequence {
+            Some(sequence) => {
+                let data = fs::read(dir.join(manifest_file(sequence)))?;
+                let manifest = serde_json::from_slice::<Manifest>(&data)
+                    .map_err(|err| EngineError::new(format!("Invalid manifest: {}", err)))?;
+
+                let mut collections = Collections {
+                    indexes: Default::default(),
+                };
+
+                for (name, file) in &manifest.segments {
+                    let index = super::load(&fs::read(dir.join(file))?)?;
+                    collections.indexes.insert(name.to_owned(), index);
+                }
+
+                (manifest.sequence, manifest.segments, collections)
+            }
+            None => (0, BTreeMap::new(), Collections::new()),
+        };
+
+        let path = dir.join(log_file(sequence));
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err.into()),
+        };
+
+        let (records, length) = read_log(&data)?;
+        let mut dirty = HashSet::new();
+        let count = records.len();
+
+        for (position, record) in records.into_iter().enumerate() {
+            dirty.insert(record.collection().to_string());
+
+            record.replay(&mut collections).map_err(|err| {
+                EngineError::new(format!(
+                    "Log record {} can not be replayed: {}",
+                    position, err
+                ))
+            })?;
+        }
+
+        // Drop a torn tail so new records follow the last complete one.
+        let log = OpenOptions::new().create(true).append(true).open(&path)?;
+        if length < data.len() {
+            log.set_len(length as u64)?;
+        }
+        log.sync_all()?;
+        sync_dir(&dir)?;
+
+        let store = DiskStore {
+            dir,
+            options,
+            collections,
+            sequence,
+            segments,
+            dirty,
+            log,
+            records: count,
+            failed: false,
+        };
+        store.remove_stale();
+
+        Ok(store)
+    }
+
+    pub fn collections(&self) -> &Collections {
+        &self.collections
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Index> {
+        self.collections.get(name)
+    }
+
+    pub fn create_collection(
+        &mut self,
+        name: &str,
+        options: IndexOptions,
+    ) -> Result<(), EngineError> {
+        self.check_healthy()?;
+        self.collections.create(name, options.to_owned())?;
+
+        self.append(LogRecord::Create {
+            name: name.to_string(),
+            options,
+        })
+    }
+
+    pub fn drop_collection(&mut self, name: &str) -> Result<(), EngineError> {
+        self.check_healthy()?;
+        self.collections.drop(name)?;
+
+        self.append(LogRecord::Drop {
+            name: name.to_string(),
+        })
+    }
+
+    /// Applies `operations` to the named collection like `transact`, creating
+    /// it with default options when missing, and returns the evicted ids.
+    /// A `ttl` is resolved to `expires_at` so replaying keeps the expiry.
+    pub fn transact(
+        &mut self,
+        collection: &str,
+        mut operations: Vec<Operation>,
+    ) -> Result<Vec<String>, EngineError> {
+        self.check_healthy()?;
+
+        super::resolve_ttl(&mut operations, super::now());
+
+        let evicted = self
+            .collections
+            .update_or_create(collection, |index| {
+                super::transact(index, operations.to_owned())
+            })
+            .map_err(|err| EngineError::new(err.to_string()))?;
+
+        self.append(LogRecord::Transact {
+            collection: collection.to_string(),
+            operations,
+            evicted: evicted.to_owned(),
+        })?;
+
+        Ok(evicted)
+    }
+
+    /// Removes the entries of the named collection matching `filter`, see
+    /// `remove_where`.
+    pub fn remove_where(
+        &mut self,
+        collection: &str,
+        filter: &MetadataFilter,
+    ) -> Result<Vec<String>, EngineError> {
+        self.check_healthy()?;
+
+        let ids = super::remove_where(self.collections.find_mut(collection)?, filter)?;
+        self.log_removed(collection, &ids)?;
+
+        Ok(ids)
+    }
+
+    /// Removes the expired entries of the named collection, see
+    /// `purge_expired`.
+    pub fn purge_expired(
+        &mut self,
+        collection: &str,
+        now: f64,
+    ) -> Result<Vec<String>, EngineError> {
+        self.check_healthy()?;
+
+        let ids = super::purge_expired(self.collections.find_mut(collection)?, now)?;
+        self.log_removed(collection, &ids)?;
+
+        Ok(ids)
+    }
+
+    pub fn clear(&mut self, collection: &str) -> Result<(), EngineError> {
+        self.check_healthy()?;
+        super::clear(self.collections.find_mut(collection)?)?;
+
+        self.append(LogRecord::Clear {
+            collection: collection.to_string(),
+        })
+    }
+
+    pub fn freeze(&mut self, collection: &str) -> Result<(), EngineError> {
+        self.check_healthy()?;
+        super::freeze(self.collections.find_mut(collection)?);
+
+        self.append(LogRecord::Freeze {
+            collection: collection.to_string(),
+        })
+    }
+
+    /// Writes the collections changed since the last checkpoint as segments
+    /// and starts an empty log. Unchanged collections keep their segments.
+    pub fn checkpoint(&mut self) -> Result<(), EngineError> {
+        self.check_healthy()?;
+
+        let sequence = self.sequence + 1;
+        let mut segments = BTreeMap::new();
+
+        for name in self.collections.names() {
+            let file = match self.segments.get(&name) {
+                Some(file) if !self.dirty.contains(&name) => file.to_owned(),
+                _ => {
+                    let file = format!("{}{:020}-{}", SEGMENT, sequence, segments.len());
+                    let index = &self.collections.indexes[&name];
+
+                    write_synced(&self.dir.join(&file), &super::dump(index)?)?;
+                    file
+                }
+            };
+
+            segments.insert(name, file);
+        }
+
+        let manifest = Manifest { sequence, segments };
+        let data =
+            serde_json::to_vec(&manifest).map_err(|err| EngineError::new(err.to_string()))?;
+
+        let path = self.dir.join(manifest_file(sequence));
+        let tmp = self.dir.join(format!("{}{}", manifest_file(sequence), TMP));
+        write_synced(&tmp, &data)?;
+
+        // Once renamed the new manifest may be current while the old log is
+        // still open, refuse changes until the new log is in place.
+        self.failed = true;
+        fs::rename(&tmp, &path)?;
+        sync_dir(&self.dir)?;
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(log_file(sequence)))?;
+        log.sync_all()?;
+        sync_dir(&self.dir)?;
+
+        self.failed = false;
+        self.sequence = sequence;
+        self.segments = manifest.segments;
+        self.dirty.clear();
+        self.log = log;
+        self.records = 0;
+        self.remove_stale();
+
+        Ok(())
+    }
+
+    fn check_healthy(&self) -> Result<(), EngineError> {
+        match self.failed {
+            true => Err(EngineError::new(
+                "The store failed to persist a change, reopen it".to_string(),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    fn log_removed(&mut self, collection: &str, ids: &[String]) -> Result<(), EngineError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        self.append(LogRecord::Transact {
+            collection: collection.to_string(),
+            operations: vec![Operation::Remove(ids.to_vec())],
+            evicted: vec![],
+        })
+    }
+
+    // Appends `record` to the log and syncs it, checkpointing once the log is
+    // long enough.
+    fn append(&mut self, record: LogRecord) -> Result<(), EngineError> {
+        self.failed = true;
+        self.dirty.insert(record.collection().to_string());
+
+        let payload =
+            serde_json::to_vec(&record).map_err(|err| EngineError::new(err.to_string()))?;
+
+        let mut crc = Crc::new();
+        crc.update(&payload);
+
+        let mut frame = Vec::with_capacity(payload.len() + 8);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&crc.sum().to_le_bytes());
+        frame.extend_from_slice(&payload);
+
+        self.log.write_all(&frame)?;
+        self.log.sync_data()?;
+        self.failed = false;
+        self.records += 1;
+
+        // The change is durable either way. A checkpoint failing before its
+        // manifest is renamed is retried on the next change.
+        if self.options.checkpoint_after > 0 && self.records >= self.options.checkpoint_after {
+            let _ = self.checkpoint();
+        }
+
+        Ok(())
+    }
+
+    // Deletes leftovers of interrupted checkpoints and files replaced by the
+    // current checkpoint. Failures are ignored, the next call retries.
+    fn remove_stale(&self) {
+        let current = [manifest_file(self.sequence), log_file(self.sequence)];
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            let stale = if name.ends_with(TMP) {
+                true
+            } else if name.starts_with(MANIFEST) || name.starts_with(LOG) {
+                !current.contains(&name)
+            } else if name.starts_with(SEGMENT) {
+                !self.segments.values().any(|file| *file == name)
+            } else {
+                false
+            };
+
+            if stale {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+fn manifest_file(sequence: u64) -> String {
+    format!("{}{:020}", MANIFEST, sequence)
+}
+
+fn log_file(sequence: u64) -> String {
+    format!("{}{:020}", LOG, sequence)
+}
+
+// Returns the records of the complete frames at the start of `data` and their
+// length in bytes. Fails on a complete frame holding an invalid record.
+fn read_log(data: &[u8]) -> Result<(Vec<LogRecord>, usize), EngineError> {
+    let mut records = vec![];
+    let mut offset = 0;
+
+    while let Some(header) = data.get(offset..offset + 8) {
+        let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+        let Some(payload) = data.get(offset + 8..offset + 8 + length) else {
+            break;
+        };
+
+        let mut crc = Crc::new();
+        crc.update(payload);
+
+        if crc.sum() != sum {
+            break;
+        }
+
+        let record = serde_json::from_slice::<LogRecord>(payload).map_err(|err| {
+            EngineError::new(format!("Log record {} is invalid: {}", records.len(), err))
+        })?;
+
+        records.push(record);
+        offset += 8 + length;
+    }
+
+    Ok((records, offset))
+}
+
+fn write_synced(path: &Path, data: &[u8]) -> Result<(), EngineError> {
+    let mut file = File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+// Makes created, renamed and deleted files durable. Only Unix can open and
+// sync a directory.
+fn sync_dir(dir: &Path) -> Result<(), EngineError> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+
+    #[cfg(not(unix))]
+    let _ = dir;
+
+    Ok(())
+}
@@ -0,0 +1,284 @@
+use crate::engine::{types::*, Operation};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+/// A text to embed and store, see `add_texts`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct TextEntry {
+    pub id: String,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct TextResource {
+    pub texts: Vec<TextEntry>,
+}
+
+/// How texts are embedded, see `embed_texts`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct EmbedderOptions {
+    /// The most texts passed to one embedder call, 32 by default.
+    #[serde(default = "default_batch_size")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub batch_size: usize,
+    /// The most embeddings kept in the `EmbeddingCache`, 0 (no cache) by
+    /// default.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub cache_size: usize,
+}
+
+impl Default for EmbedderOptions {
+    fn default() -> Self {
+        EmbedderOptions {
+            batch_size: default_batch_size(),
+            cache_size: 0,
+        }
+    }
+}
+
+fn default_batch_size() -> usize {
+    32
+}
+
+/// Embeddings of recently embedded texts, so embedding the same text again
+/// does not call the embedder. The oldest text is dropped once `capacity` texts
+/// are cached, a capacity of 0 disables caching.
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingCache {
+    capacity: usize,
+    // Text hash -> embedding.
+    entries: HashMap<u64, Embedding>,
+    order: VecDeque<u64>,
+}
+
+impl EmbeddingCache {
+    pub fn new(capacity: usize) -> Self {
+        EmbeddingCache {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, text: &str) -> Option<&Embedding> {
+        self.entries.get(&super::hash(&text))
+    }
+
+    pub fn insert(&mut self, text: &str, embedding: Embedding) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let hash = super::hash(&text);
+
+        if self.entries.insert(hash, embedding).is_none() {
+            self.order.push_back(hash);
+        }
+
+        while self.entries.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Splits the distinct texts of `texts` that are not cached into batches of at
+/// most `batch_size` texts, in first appearance order.
+pub fn pending_batches(
+    texts: &[String],
+    cache: &EmbeddingCache,
+    batch_size: usize,
+) -> Vec<Vec<String>> {
+    let mut seen = HashSet::new();
+
+    let pending = texts
+        .iter()
+        .filter(|text| cache.get(text).is_none() && seen.insert(text.as_str()))
+        .cloned()
+        .collect::<Vec<String>>();
+
+    pending
+        .chunks(batch_size.max(1))
+        .map(|batch| batch.to_vec())
+        .collect()
+}
+
+/// Checks that an embedder returned one non-empty vector per text of `batch`,
+/// all of the same length.
+pub fn check_embeddings(batch: &[String], embeddings: &[Embedding]) -> Result<(), EngineError> {
+    if embeddings.len() != batch.len() {
+        return Err(EngineError::new(format!(
+            "The embedder returned {} embeddings for {} texts",
+            embeddings.len(),
+            batch.len()
+        )));
+    }
+
+    let dimension = embeddings.first().map_or(0, |embedding| embedding.len());
+
+    if embeddings
+        .iter()
+        .any(|embedding| embedding.is_empty() || embedding.len() != dimension)
+    {
+        return Err(EngineError::new(
+            "The embedder returned empty embeddings or embeddings of different lengths".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the embedding of every text of `texts`, taken from `computed` or
+/// from the cache, and caches the computed ones.
+pub fn resolve_embeddings(
+    texts: &[String],
+    computed: HashMap<String, Embedding>,
+    cache: &mut EmbeddingCache,
+) -> Result<Vec<Embedding>, EngineError> {
+    let embeddings = texts
+        .iter()
+        .map(|text| {
+            computed
+                .get(text)
+                .or_else(|| cache.get(text))
+                .cloned()
+                .ok_or_else(|| EngineError::new(format!("The text {:?} was not embedded", text)))
+        })
+        .collect::<Result<Vec<Embedding>, EngineError>>()?;
+
+    // In text order, so the cache drops the earliest texts first.
+    for (text, embedding) in texts.iter().zip(&embeddings) {
+        if computed.contains_key(text) {
+            cache.insert(text, embedding.to_owned());
+        }
+    }
+
+    Ok(embeddings)
+}
+
+/// Embeds `texts` with `embed`, calling it once per batch of at most
+/// `batch_size` texts that are not cached.
+pub fn embed_texts<F>(
+    texts: &[String],
+    batch_size: usize,
+    cache: &mut EmbeddingCache,
+    mut embed: F,
+) -> Result<Vec<Embedding>, EngineError>
+where
+    F: FnMut(&[String]) -> Result<Vec<Embedding>, EngineError>,
+{
+    let mut computed = HashMap::new();
+
+    for batch in pending_batches(texts, cache, batch_size) {
+        let embeddings = embed(&batch)?;
+        check_embeddings(&batch, &embeddings)?;
+
+        computed.extend(batch.into_iter().zip(embeddings));
+    }
+
+    resolve_embeddings(texts, computed, cache)
+}
+
+/// Pairs the texts with their embeddings as resources for `add`, keeping each
+/// text as the entry content.
+pub fn text_resources(texts: Vec<TextEntry>, embeddings: Vec<Embedding>) -> Vec<EmbeddedResource> {
+    texts
+        .into_iter()
+        .zip(embeddings)
+        .map(|(entry, embeddings)| EmbeddedResource {
+            id: entry.id,
+            embeddings,
+            metadata: entry.metadata,
+            content: Some(entry.text),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// Embeds `texts` with `embed` and adds them, keeping each text as the entry
+/// content. Nothing is added if embedding fails, see `add_text_embeddings`.
+pub fn add_texts<F>(
+    index: &mut Index,
+    texts: Vec<TextEntry>,
+    batch_size: usize,
+    cache: &mut EmbeddingCache,
+    embed: F,
+) -> Result<Vec<String>, EngineError>
+where
+    F: FnMut(&[String]) -> Result<Vec<Embedding>, EngineError>,
+{
+    index.check_mutable()?;
+
+    let strings = texts
+        .iter()
+        .map(|entry| entry.text.to_owned())
+        .collect::<Vec<String>>();
+    let embeddings = embed_texts(&strings, batch_size, cache, embed)?;
+
+    add_text_embeddings(index, texts, embeddings)
+}
+
+/// Adds `texts` with their `embeddings` in one transaction, keeping each text
+/// as the entry content. Nothing is added if an embedding does not match the
+/// index dimension or a text fails to add. Returns the evicted ids, see
+/// `transact`.
+pub fn add_text_embeddings(
+    index: &mut Index,
+    texts: Vec<TextEntry>,
+    embeddings: Vec<Embedding>,
+) -> Result<Vec<String>, EngineError> {
+    index.check_mutable()?;
+
+    if let Some(dimension) = index.options.dimension {
+        if let Some(embedding) = embeddings
+            .iter()
+            .find(|embedding| embedding.len() != dimension)
+        {
+            return Err(EngineError::new(format!(
+                "The embedder returned dimension {}, expected {}",
+                embedding.len(),
+                dimension
+            )));
+        }
+    }
+
+    let operations = text_resources(texts, embeddings)
+        .into_iter()
+        .map(Operation::Add)
+        .collect();
+
+    super::transact(index, operations).map_err(|err| EngineError::new(err.to_string()))
+}
+
+/// Embeds `query` with `embed` and searches for it.
+pub fn search_text<F>(
+    index: &Index,
+    query: &str,
+    k: usize,
+    options: SearchOptions,
+    cache: &mut EmbeddingCache,
+    embed: F,
+) -> Result<SearchResult, EngineError>
+where
+    F: FnMut(&[String]) -> Result<Vec<Embedding>, EngineError>,
+{
+    let embedding = embed_texts(&[query.to_string()], 1, cache, embed)?.remove(0);
+
+    Ok(super::search_with(index, &embedding, k, options))
+}
@@ -1,139 +1,802 @@
-use crate::{engine::types::*, Neighbor, SearchResult};
+use crate::engine::{format::Format, types::*, ExportRecord, TreeBuilder};
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use kiddo::float::{distance::SquaredEuclidean, kdtree::KdTree};
-use std::{collections::HashMap, convert::TryInto};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::sync::Arc;
+
+/// Builds a new index from `resources`, constructing the tree in one pass
+/// instead of inserting the vectors one by one.
+pub fn index(resources: &[EmbeddedResource], options: IndexOptions) -> Result<Index, EngineError> {
+    let mut index = Index::with_options(options)?;
+    let mut entries = Vec::with_capacity(resources.len());
+
+    for resource in resources {
+        entries.extend(register(&mut index, resource.to_owned())?);
+    }
 
-pub fn index(data: &Vec<Embedding>, ids: &Vec<String>) -> Index {
-    let mut tree = KdTree::with_capacity(100);
-    let mut doc = HashMap::new();
+    build(&mut index, entries)?;
 
-    for i in 0..data.len() {
-        let mut embedding: Vec<f32> = data[i].to_owned();
-        let id = ids[i].to_owned();
+    Ok(index)
+}
 
-        if embedding.len() != EMBEDDING_DIMENSION {
-            embedding.resize(EMBEDDING_DIMENSION, 0.0);
-        }
+/// Replaces the tree of `index` with one built from `entries` returned by
+/// `register`.
+pub(crate) fn build(index: &mut Index, entries: Vec<(u64, Embedding)>) -> Result<(), EngineError> {
+    index.tree = Arc::new(builder(index, entries)?.finish());
 
-        let hash = super::hash(&id);
+    Ok(())
+}
 
-        let embedding: [f32; EMBEDDING_DIMENSION] = embedding.try_into().unwrap();
+// Checks that `entries` returned by `register` fit the index and returns the
+// builder of its tree.
+pub(crate) fn builder(
+    index: &Index,
+    entries: Vec<(u64, Embedding)>,
+) -> Result<TreeBuilder, EngineError> {
+    if index.is_limited() && !evict_order(index, 0).is_empty() {
+        return Err(EngineError::new(
+            "The resources exceed the max_vectors or max_bytes limit of the index".to_string(),
+        ));
+    }
 
-        tree.add(&embedding, hash);
-        doc.insert(hash, id);
+    // Bulk built kd-trees pick their node index width from the number of
+    // vectors, so only the widest tree limits them.
+    if index.options.kind == IndexKind::KdTree {
+        let bucket_size = index.options.bucket_size.unwrap_or(BUCKET_SIZE);
+        check_capacity(super::kdtree_capacity(bucket_size, true), entries.len())?;
     }
 
-    Index { tree, hash: doc }
+    Ok(TreeBuilder::new(&index.options, entries))
 }
 
-pub fn search(index: &Index, query: &Embedding, k: usize) -> SearchResult {
-    let mut query: Vec<f32> = query.to_owned();
+/// Returns the `k` nearest entries to `query` by the index metric, closest
+/// first. Multi-vector documents are returned once.
+pub fn search(index: &Index, query: &[f32], k: usize) -> SearchResult {
+    search_with(index, query, k, SearchOptions::default())
+}
+
+/// Like `search`, also returning what `options` asks for with each neighbour.
+pub fn search_with(index: &Index, query: &[f32], k: usize, options: SearchOptions) -> SearchResult {
+    let result = peek_with(index, query, k, options);
+    touch(index, &result);
+
+    result
+}
 
-    if query.len() != EMBEDDING_DIMENSION {
-        query.resize(EMBEDDING_DIMENSION, 0.0);
+// Like `search_with` without counting as a retrieval for `Eviction::Lru` and
+// `Eviction::Lfu`.
+pub(crate) fn peek_with(
+    index: &Index,
+    query: &[f32],
+    k: usize,
+    options: SearchOptions,
+) -> SearchResult {
+    let start = super::profile::clock();
+    let mut explain = SearchExplain::default();
+
+    let (mut neighbors, work) = match options.explain {
+        true => super::profile::measure(|| neighbors(index, query, k, &mut explain)),
+        false => (neighbors(index, query, k, &mut explain), Default::default()),
+    };
+
+    if options.with_content {
+        for neighbor in neighbors.iter_mut() {
+            neighbor.content = index
+                .content
+                .get(&super::hash(&neighbor.id))
+                .map(|content| content.to_string());
+        }
     }
 
-    let query: &[f32; EMBEDDING_DIMENSION] = &query.try_into().unwrap();
+    let explain = options.explain.then(|| SearchExplain {
+        elapsed: super::profile::clock() - start,
+        nodes: work.nodes,
+        buckets: work.buckets,
+        distances: work.distances,
+        ..explain
+    });
+
+    SearchResult { neighbors, explain }
+}
 
+// The neighbours returned by `search`, recording the rounds and the filtered
+// candidates in `explain`.
+fn neighbors(
+    index: &Index,
+    query: &[f32],
+    k: usize,
+    explain: &mut SearchExplain,
+) -> Vec<Neighbor> {
+    let query = prepare(index, query);
+    let now = now();
+
+    if index.chunks.is_empty() && index.expires.is_empty() {
+        explain.rounds = 1;
+        return collect_neighbors(index, nearest(index, &query, k), k, now, explain);
+    }
 
-    let neighbors = index.tree.nearest_n::<SquaredEuclidean>(&query, k);
+    if index.options.aggregation == Aggregation::Mean && !index.chunks.is_empty() {
+        explain.rounds = 1;
+        return mean_neighbors(index, &query, k, now, explain);
+    }
 
-    let mut result: Vec<Neighbor> = vec![];
+    // Chunks of one document and expired entries can crowd out others, widen
+    // the search until `k` live documents are found or the whole tree has been
+    // visited.
+    let total = index.tree.size() as usize;
+    let mut n = k.saturating_mul(4).min(total);
 
-    for neighbor in &neighbors {
-        let id = index.hash.get(&neighbor.item);
+    loop {
+        explain.rounds += 1;
 
-        if id.is_some() {
-            result.push(Neighbor {
-                id: id.unwrap().to_owned(),
-                distance: neighbor.distance,
-            });
+        let neighbors = collect_neighbors(index, nearest(index, &query, n), k, now, explain);
+
+        if neighbors.len() >= k || n >= total {
+            return neighbors;
         }
+
+        n = n.saturating_mul(2).min(total);
     }
+}
 
-    SearchResult { neighbors: result }
+/// Returns the exact `k` nearest entries by computing the distance to every
+/// vector, the ground truth `evaluate` compares `search` with.
+pub fn search_exact(index: &Index, query: &[f32], k: usize) -> SearchResult {
+    let result = peek_exact(index, query, k);
+    touch(index, &result);
+
+    result
 }
 
-pub fn add(index: &mut Index, id: String, query: &Embedding) -> Result<(), EngineError> {
-    let hash = super::hash(&id);
+// Like `search_exact` without counting as a retrieval, see `peek_with`.
+pub(crate) fn peek_exact(index: &Index, query: &[f32], k: usize) -> SearchResult {
+    let query = prepare(index, query);
+    let now = now();
 
-    if index.hash.contains_key(&hash) {
-        return Err(EngineError::new(format!("Id {} already exists", id)));
+    if index.options.aggregation == Aggregation::Mean && !index.chunks.is_empty() {
+        return SearchResult {
+            neighbors: mean_neighbors(index, &query, k, now, &mut SearchExplain::default()),
+            explain: None,
+        };
+    }
+
+    let mut nearest = super::parallel::map_iter(index.tree.iter(), |(item, vector)| {
+        (distance(index.options.metric, &query, &vector), item)
+    });
+    super::profile::count_distances(nearest.len());
+
+    nearest.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    SearchResult {
+        neighbors: collect_neighbors(index, nearest, k, now, &mut SearchExplain::default()),
+        explain: None,
+    }
+}
+
+// Records the neighbours of `result` as retrieved.
+fn touch(index: &Index, result: &SearchResult) {
+    for neighbor in &result.neighbors {
+        index.usage.touch(super::hash(&neighbor.id));
+    }
+}
+
+/// Runs `search` for every query, returning the results in query order.
+/// Queries are searched in parallel with the `threads` feature.
+pub fn search_batch(index: &Index, queries: &[Embedding], k: usize) -> Vec<SearchResult> {
+    super::parallel::map(queries, |query| search(index, query, k))
+}
+
+/// Adds one entry, failing if its id is already present, its length does not
+/// match the configured dimension or the index is frozen.
+///
+/// When the index is over `max_vectors` or `max_bytes` afterwards, entries
+/// chosen by `IndexOptions::eviction` are removed and their ids returned.
+pub fn add(index: &mut Index, resource: EmbeddedResource) -> Result<Vec<String>, EngineError> {
+    let hash = insert(index, resource)?;
+
+    evict(index, hash)
+}
+
+// Adds one entry without evicting and returns its id hash. Nothing is changed
+// on failure.
+pub(crate) fn insert(index: &mut Index, resource: EmbeddedResource) -> Result<u64, EngineError> {
+    index.check_mutable()?;
+
+    let count = resource.chunks.as_ref().map_or(1, |chunks| chunks.len());
+    check_capacity(index.tree.max_capacity(), index.tree.size() as usize + count)?;
+
+    let hash = super::hash(&resource.id);
+
+    for (item, vector) in register(index, resource)? {
+        Arc::make_mut(&mut index.tree).add(&pad(&vector), item);
     }
 
-    let mut query: Vec<f32> = query.to_owned();
+    Ok(hash)
+}
+
+/// Adds one entry, replacing the entry with the same id if there is one. The
+/// old entry is kept if the new one is invalid.
+pub fn upsert(index: &mut Index, resource: EmbeddedResource) -> Result<Vec<String>, EngineError> {
+    index.check_mutable()?;
+    validate(index, &resource)?;
 
-    if query.len() != EMBEDDING_DIMENSION {
-        query.resize(EMBEDDING_DIMENSION, 0.0);
+    if index.hash.contains_key(&super::hash(&resource.id)) {
+        remove(index, std::slice::from_ref(&resource.id))?;
     }
 
-    let query: &[f32; EMBEDDING_DIMENSION] = &query.try_into().unwrap();
+    add(index, resource)
+}
+
+/// Returns the entry with `id`, with its vectors, metadata and content. The
+/// tree has no lookup by item, so the vectors are found by walking it, a full
+/// pass in the worst case.
+pub fn get(index: &Index, id: &str) -> Option<ExportRecord> {
+    super::records_of(index, &[super::hash(&id)], true, true, true).pop()
+}
 
+/// Removes the entries with the given ids. Nothing is removed if any id is
+/// missing or the index is frozen.
+pub fn remove(index: &mut Index, ids: &[String]) -> Result<(), EngineError> {
+    index.check_mutable()?;
+    check_present(index, ids)?;
 
-    index.hash.insert(hash, id);
-    index.tree.add(query, hash);
+    detach(index, &ids.iter().map(super::hash).collect());
 
     Ok(())
 }
 
-pub fn remove(index: &mut Index, ids: &Vec<String>) -> Result<(), EngineError> {
-    let mut embeddings: Vec<(u64, [f32; EMBEDDING_DIMENSION])> = vec![];
+/// Entries taken out of an index by `detach`, with their tree vectors as
+/// stored so `attach` can put them back exactly.
+#[derive(Debug, Default)]
+pub(crate) struct Detached {
+    entries: Vec<DetachedEntry>,
+    chunks: Vec<(u64, Chunk)>,
+    vectors: Vec<(u64, [f32; EMBEDDING_DIMENSION])>,
+}
 
-    let hash_ids = ids.iter().map(|id| super::hash(id)).collect::<Vec<u64>>();
+#[derive(Debug)]
+struct DetachedEntry {
+    hash: u64,
+    id: String,
+    metadata: Option<Metadata>,
+    expires_at: Option<f64>,
+    content: Option<Box<str>>,
+}
 
-    for (vector_hash, vector) in index.tree.iter() {
-        if hash_ids.contains(&vector_hash) {
-            embeddings.push((vector_hash, vector));
+// Fails with the ids not present in the index.
+pub(crate) fn check_present(index: &Index, ids: &[String]) -> Result<(), EngineError> {
+    let not_found_ids = ids
+        .iter()
+        .filter(|id| !index.hash.contains_key(&super::hash(id)))
+        .map(|id| id.to_owned())
+        .collect::<Vec<String>>();
+
+    match not_found_ids.is_empty() {
+        true => Ok(()),
+        false => Err(EngineError::new(format!(
+            "The ids {} not found",
+            not_found_ids.join(",")
+        ))),
+    }
+}
+
+// Takes the entries with the id hashes `hashes` out of the index in a single
+// pass over the tree. Missing hashes are ignored.
+pub(crate) fn detach(index: &mut Index, hashes: &HashSet<u64>) -> Detached {
+    let mut detached = Detached::default();
+    index.revision = super::next_revision();
+
+    for (item, vector) in index.tree.iter() {
+        let document = index.chunks.get(&item).map_or(item, |chunk| chunk.document);
+
+        if hashes.contains(&document) {
+            detached.vectors.push((item, vector));
+        }
+    }
+
+    for (item, vector) in &detached.vectors {
+        if let Some(chunk) = super::take(&mut index.chunks, item) {
+            detached.chunks.push((*item, chunk));
+        }
+
+        Arc::make_mut(&mut index.tree).remove(vector, *item);
+    }
+
+    for hash in hashes {
+        let Some(id) = super::take(&mut index.hash, hash) else {
             continue;
+        };
+
+        index.usage.remove(*hash);
+        detached.entries.push(DetachedEntry {
+            hash: *hash,
+            id,
+            metadata: super::take(&mut index.metadata, hash),
+            expires_at: super::take(&mut index.expires, hash),
+            content: super::take(&mut index.content, hash),
+        });
+    }
+
+    detached
+}
+
+// Puts entries taken out by `detach` back. Their usage counters are not
+// restored.
+pub(crate) fn attach(index: &mut Index, detached: Detached) {
+    index.revision = super::next_revision();
+
+    for entry in detached.entries {
+        Arc::make_mut(&mut index.hash).insert(entry.hash, entry.id);
+
+        if let Some(metadata) = entry.metadata {
+            Arc::make_mut(&mut index.metadata).insert(entry.hash, metadata);
+        }
+
+        if let Some(expires_at) = entry.expires_at {
+            Arc::make_mut(&mut index.expires).insert(entry.hash, expires_at);
+        }
+
+        if let Some(content) = entry.content {
+            Arc::make_mut(&mut index.content).insert(entry.hash, content);
         }
     }
 
-    if hash_ids.len() != embeddings.len() {
-        let not_found_ids = hash_ids
-            .iter()
-            .filter(|id| {
-                !embeddings
-                    .iter()
-                    .any(|(vector_hash, _)| *vector_hash == **id)
-            })
-            .map(|id| index.hash.get(id).unwrap().to_owned())
-            .collect::<Vec<String>>();
+    if !detached.chunks.is_empty() {
+        Arc::make_mut(&mut index.chunks).extend(detached.chunks);
+    }
+
+    for (item, vector) in detached.vectors {
+        Arc::make_mut(&mut index.tree).add(&vector, item);
+    }
+}
+
+/// Removes the entries that expired at or before `now`, in milliseconds since
+/// the Unix epoch, and returns their ids sorted. Fails if the index is frozen.
+pub fn purge_expired(index: &mut Index, now: f64) -> Result<Vec<String>, EngineError> {
+    index.check_mutable()?;
+
+    let mut ids = index
+        .expires
+        .iter()
+        .filter(|(_, expires_at)| **expires_at <= now)
+        .filter_map(|(hash, _)| index.hash.get(hash).cloned())
+        .collect::<Vec<String>>();
+    ids.sort();
+
+    remove(index, &ids)?;
+
+    Ok(ids)
+}
+
+/// Rebuilds the tree from its current content. Many `add` and `remove` calls
+/// can leave the tree skewed, optimizing restores balanced searches.
+pub fn optimize(index: &mut Index) {
+    Arc::make_mut(&mut index.tree).optimize();
+}
+
+/// Returns the number of entries. A multi-vector document counts once.
+pub fn size(index: &Index) -> usize {
+    index.hash.len()
+}
+
+/// Removes every entry, keeping the index options. Fails if the index is
+/// frozen.
+pub fn clear(index: &mut Index) -> Result<(), EngineError> {
+    index.check_mutable()?;
+    *index = Index::with_options(index.options.to_owned()).unwrap_or_default();
+
+    Ok(())
+}
+
+/// Returns the most vectors the index can hold.
+pub fn max_capacity(index: &Index) -> usize {
+    index.tree.max_capacity()
+}
+
+/// Converts the index into an immutable, query-optimized form. Searching,
+/// exporting and serializing keep working, every mutating call fails.
+pub fn freeze(index: &mut Index) {
+    Arc::make_mut(&mut index.tree).freeze();
+}
+
+/// Serializes the index into a gzip compressed bincode snapshot, starting
+/// with the `FORMAT_VERSION` it is written in.
+pub fn dump(index: &Index) -> Result<Vec<u8>, EngineError> {
+    let mut encoder = GzEncoder::new(super::format::header(), Compression::default());
+    bincode::serialize_into(&mut encoder, &index)?;
+
+    Ok(encoder.finish()?)
+}
+
+/// Restores an index from a snapshot created by `dump`. Snapshots of versions
+/// without a format header are migrated.
+pub fn load(data: &[u8]) -> Result<Index, EngineError> {
+    let (format, body) = super::format::read_header(data)?;
+    let mut decoder = GzDecoder::new(std::io::Cursor::new(body));
+
+    match format {
+        Format::Current => Ok(bincode::deserialize_from::<_, Index>(&mut decoder)?),
+        Format::Legacy => super::format::read_legacy(decoder),
+    }
+}
+
+/// The current time in whole milliseconds since the Unix epoch, like
+/// `Date.now()`, used to resolve `EmbeddedResource::ttl` and to skip expired
+/// entries in searches. Whole numbers survive the JSON Lines export exactly.
+///
+/// wasm32 builds without the `wasm` feature have no clock and always return
+/// 0, so only entries expiring at or before the epoch are skipped.
+pub fn now() -> f64 {
+    #[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+    return js_sys::Date::now();
+
+    #[cfg(not(target_arch = "wasm32"))]
+    return std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_millis() as f64);
+
+    #[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+    return 0.0;
+}
+
+/// Pads `embedding` to the tree dimension, normalizing it for the cosine
+/// metric.
+pub(crate) fn prepare(index: &Index, embedding: &[f32]) -> [f32; EMBEDDING_DIMENSION] {
+    pad(&normalize(index, embedding.to_owned()))
+}
+
+// Truncates `embedding` to the tree dimension, normalizing it for the cosine
+// metric.
+fn normalize(index: &Index, mut embedding: Embedding) -> Embedding {
+    embedding.truncate(EMBEDDING_DIMENSION);
+
+    if index.options.metric == Metric::Cosine {
+        let norm = embedding.iter().map(|value| value * value).sum::<f32>().sqrt();
+
+        if norm > 0.0 {
+            embedding.iter_mut().for_each(|value| *value /= norm);
+        }
+    }
+
+    embedding
+}
+
+pub(crate) fn pad(embedding: &[f32]) -> [f32; EMBEDDING_DIMENSION] {
+    let mut embedding: Vec<f32> = embedding.to_owned();
+
+    if embedding.len() != EMBEDDING_DIMENSION {
+        embedding.resize(EMBEDDING_DIMENSION, 0.0);
+    }
+
+    embedding.try_into().unwrap()
+}
 
+/// Drops the trailing zeros of a padded vector.
+pub(crate) fn trim(vector: &[f32]) -> &[f32] {
+    let len = vector
+        .iter()
+        .rposition(|value| *value != 0.0)
+        .map_or(0, |position| position + 1);
+
+    &vector[..len]
+}
+
+/// The distance between two prepared vectors under `metric`. The shorter
+/// vector is treated as zero padded.
+pub(crate) fn distance(metric: Metric, a: &[f32], b: &[f32]) -> f32 {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+
+    let term = |diff: f32| match metric {
+        Metric::Manhattan => diff.abs(),
+        Metric::Euclidean | Metric::Cosine => diff * diff,
+    };
+
+    let sum = long
+        .iter()
+        .zip(short.iter().chain(std::iter::repeat(&0.0)))
+        .map(|(x, y)| term(x - y))
+        .sum::<f32>();
+
+    match metric {
+        // For unit vectors |a - b|^2 = 2 - 2cos(a, b).
+        Metric::Cosine => sum / 2.0,
+        _ => sum,
+    }
+}
+
+/// Validates `resource` and records its id, metadata and chunks. Returns the
+/// prepared `(tree item, vector)` pairs, which the caller inserts into the tree.
+pub(crate) fn register(
+    index: &mut Index,
+    resource: EmbeddedResource,
+) -> Result<Vec<(u64, Embedding)>, EngineError> {
+    let hash = super::hash(&resource.id);
+
+    if index.hash.contains_key(&hash) {
         return Err(EngineError::new(format!(
-            "The ids {} not found",
-            not_found_ids.join(",")
+            "Id {} already exists",
+            resource.id
         )));
     }
 
-    for (vector_hash, vector) in embeddings {
-        index.hash.remove(&vector_hash);
-        index.tree.remove(&vector, vector_hash);
+    validate(index, &resource)?;
+
+    let bytes = super::entry_bytes(index, &resource);
+    let chunked = resource.chunks.is_some();
+
+    let vectors: Vec<Embedding> = match resource.chunks {
+        Some(chunks) => chunks,
+        None => vec![resource.embeddings],
+    };
+
+    let mut entries = Vec::with_capacity(vectors.len());
+
+    for (position, vector) in vectors.into_iter().enumerate() {
+        index.dimension = index.dimension.max(vector.len().min(EMBEDDING_DIMENSION));
+
+        let item = match chunked {
+            true => {
+                let item = super::hash(&(&resource.id, position));
+                Arc::make_mut(&mut index.chunks).insert(
+                    item,
+                    Chunk {
+                        document: hash,
+                        position,
+                    },
+                );
+                item
+            }
+            false => hash,
+        };
+
+        entries.push((item, normalize(index, vector)));
+    }
+
+    Arc::make_mut(&mut index.hash).insert(hash, resource.id);
+    index.revision = super::next_revision();
+
+    if let Some(meta) = resource.metadata {
+        Arc::make_mut(&mut index.metadata).insert(hash, meta);
+    }
+
+    if let Some(expires_at) = resource.expires_at.or(resource.ttl.map(|ttl| now() + ttl)) {
+        Arc::make_mut(&mut index.expires).insert(hash, expires_at);
+    }
+
+    if index.is_limited() {
+        index.usage.insert(hash, entries.len(), bytes);
+    }
+
+    if let Some(content) = resource.content {
+        Arc::make_mut(&mut index.content).insert(hash, content.into_boxed_str());
+    }
+
+    Ok(entries)
+}
+
+// Checks everything about `resource` but whether its id already exists.
+pub(crate) fn validate(index: &Index, resource: &EmbeddedResource) -> Result<(), EngineError> {
+    let vectors = match &resource.chunks {
+        Some(_) if !resource.embeddings.is_empty() => {
+            return Err(EngineError::new(format!(
+                "Id {} has both embeddings and chunks",
+                resource.id
+            )))
+        }
+        Some(chunks) if chunks.is_empty() => {
+            return Err(EngineError::new(format!(
+                "Id {} has no chunks",
+                resource.id
+            )))
+        }
+        Some(chunks) => chunks.iter().collect::<Vec<&Embedding>>(),
+        None => vec![&resource.embeddings],
+    };
+
+    for vector in &vectors {
+        check_dimension(index, &resource.id, vector)?;
+
+        if vector.iter().any(|value| !value.is_finite()) {
+            return Err(EngineError::new(format!(
+                "Id {} has a NaN or infinite component",
+                resource.id
+            )));
+        }
+    }
+
+    if vectors.len() > index.options.max_vectors.unwrap_or(usize::MAX)
+        || super::entry_bytes(index, resource) > index.options.max_bytes.unwrap_or(usize::MAX)
+    {
+        return Err(EngineError::new(format!(
+            "Id {} alone exceeds the max_vectors or max_bytes limit of the index",
+            resource.id
+        )));
     }
 
     Ok(())
 }
 
-pub fn size(index: &Index) -> usize {
-    assert_eq!(index.tree.size(), index.hash.len() as u64);
-    index.hash.len()
+// Removes the entries `evict_order` picks, except `keep`, and returns their
+// ids.
+pub(crate) fn evict(index: &mut Index, keep: u64) -> Result<Vec<String>, EngineError> {
+    let ids = evict_order(index, keep)
+        .into_iter()
+        .filter_map(|hash| index.hash.get(&hash).cloned())
+        .collect::<Vec<String>>();
+
+    if !ids.is_empty() {
+        remove(index, &ids)?;
+    }
+
+    Ok(ids)
+}
+
+// The id hashes to remove, other than `keep`, to bring the index back within
+// its size limits.
+fn evict_order(index: &Index, keep: u64) -> Vec<u64> {
+    if !index.is_limited() {
+        return vec![];
+    }
+
+    index.usage.victims(
+        index.options.eviction,
+        index.options.max_vectors.unwrap_or(usize::MAX),
+        index.options.max_bytes.unwrap_or(usize::MAX),
+        keep,
+    )
+}
+
+fn check_capacity(max_capacity: usize, vectors: usize) -> Result<(), EngineError> {
+    match vectors > max_capacity {
+        true => Err(EngineError::new(format!(
+            "The index can hold at most {} vectors",
+            max_capacity
+        ))),
+        false => Ok(()),
+    }
 }
 
-pub fn clear(index: &mut Index) {
-    index.tree = Tree::new();
-    index.hash = HashMap::new();
+fn check_dimension(index: &Index, id: &str, embedding: &[f32]) -> Result<(), EngineError> {
+    match index.options.dimension {
+        Some(dimension) if embedding.len() != dimension => Err(EngineError::new(format!(
+            "Id {} has dimension {}, expected {}",
+            id,
+            embedding.len(),
+            dimension
+        ))),
+        _ => Ok(()),
+    }
 }
 
-pub fn dump(index: &mut Index) -> Result<Vec<u8>, std::io::Error> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    bincode::serialize_into(&mut encoder, &index).unwrap();
+// Returns `(distance, tree item)` pairs, closest first.
+fn nearest(index: &Index, query: &[f32; EMBEDDING_DIMENSION], n: usize) -> Vec<(f32, u64)> {
+    index.tree.nearest(query, n, index.options.metric)
+}
 
-    encoder.finish()
+// Whether the entry with id hash `document` expired at or before `now`.
+fn is_expired(index: &Index, document: u64, now: f64) -> bool {
+    index
+        .expires
+        .get(&document)
+        .is_some_and(|expires_at| *expires_at <= now)
 }
 
-pub fn load(data: &Vec<u8>) -> Index {
-    let mut decoder = GzDecoder::new(std::io::Cursor::new(data));
+// Maps tree items to live documents, keeping the closest chunk of each
+// document. The candidates and the filtered ones are recorded in `explain`.
+fn collect_neighbors(
+    index: &Index,
+    nearest: Vec<(f32, u64)>,
+    k: usize,
+    now: f64,
+    explain: &mut SearchExplain,
+) -> Vec<Neighbor> {
+    let mut seen = HashSet::new();
+    let mut result: Vec<Neighbor> = vec![];
+
+    explain.candidates = nearest.len();
+    explain.duplicate_chunks = 0;
+    explain.expired = 0;
+
+    for (distance, item) in nearest {
+        let (document, chunk) = match index.chunks.get(&item) {
+            Some(chunk) => (chunk.document, Some(chunk.position)),
+            None => (item, None),
+        };
+
+        if result.len() >= k {
+            continue;
+        }
+
+        if !seen.insert(document) {
+            explain.duplicate_chunks += 1;
+            continue;
+        }
+
+        if is_expired(index, document, now) {
+            explain.expired += 1;
+            continue;
+        }
+
+        if let Some(id) = index.hash.get(&document) {
+            result.push(Neighbor {
+                id: id.to_owned(),
+                distance,
+                chunk,
+                content: None,
+            });
+        }
+    }
+
+    result
+}
+
+#[derive(Default)]
+struct MeanScore {
+    sum: f32,
+    count: usize,
+    // The distance and position of the closest chunk.
+    best: Option<(f32, usize)>,
+}
+
+// Scores every document by the mean distance of its vectors. Every vector is
+// a candidate, the chunks beyond the first of each document count as
+// duplicates.
+fn mean_neighbors(
+    index: &Index,
+    query: &[f32; EMBEDDING_DIMENSION],
+    k: usize,
+    now: f64,
+    explain: &mut SearchExplain,
+) -> Vec<Neighbor> {
+    let mut scores: HashMap<u64, MeanScore> = HashMap::new();
+
+    let distances = super::parallel::map_iter(index.tree.iter(), |(item, vector)| {
+        (item, distance(index.options.metric, query, &vector))
+    });
+    let candidates = distances.len();
+    super::profile::count_distances(candidates);
+
+    for (item, distance) in distances {
+        let (document, chunk) = match index.chunks.get(&item) {
+            Some(chunk) => (chunk.document, Some(chunk.position)),
+            None => (item, None),
+        };
+
+        let score = scores.entry(document).or_default();
+        score.sum += distance;
+        score.count += 1;
+
+        if let Some(position) = chunk {
+            if score.best.is_none_or(|(best, _)| distance < best) {
+                score.best = Some((distance, position));
+            }
+        }
+    }
+
+    explain.candidates = candidates;
+    explain.duplicate_chunks = candidates - scores.len();
+    explain.expired = scores
+        .keys()
+        .filter(|document| is_expired(index, **document, now))
+        .count();
+
+    let mut result = scores
+        .into_iter()
+        .filter(|(document, _)| !is_expired(index, *document, now))
+        .filter_map(|(document, score)| {
+            Some(Neighbor {
+                id: index.hash.get(&document)?.to_owned(),
+                distance: score.sum / score.count as f32,
+                chunk: score.best.map(|(_, position)| position),
+                content: None,
+            })
+        })
+        .collect::<Vec<Neighbor>>();
+
+    result.sort_by(|a, b| a.distance.total_cmp(&b.distance).then(a.id.cmp(&b.id)));
+    result.truncate(k);
 
-    bincode::deserialize_from::<_, Index>(&mut decoder).unwrap()
+    result
 }
@@ -0,0 +1,111 @@
+use crate::engine::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+/// How `search` compares with an exact scan on a set of queries, see
+/// `evaluate`. Latencies are in milliseconds, distances are counted per query.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct Evaluation {
+    pub queries: usize,
+    pub k: usize,
+    /// The mean share of the exact `k` nearest ids that `search` returned, 1
+    /// when every search is exact.
+    pub recall: f64,
+    pub mean_latency: f64,
+    pub p95_latency: f64,
+    pub exact_mean_latency: f64,
+    pub exact_p95_latency: f64,
+    /// The mean number of distances `search` computed, including IVF
+    /// centroids.
+    pub mean_distances: f64,
+    /// The mean number of distances the exact scan computed, one per vector.
+    pub exact_mean_distances: f64,
+}
+
+/// Runs every query through `search` and `search_exact` and reports the recall
+/// at `k`, the latencies and the distance computations of both. Everything is
+/// 0 without queries.
+///
+/// Queries run one at a time, each search is timed apart from the run counting
+/// its distances so counting does not skew the latency. The queries do not
+/// count as retrievals for `Eviction::Lru` and `Eviction::Lfu`.
+pub fn evaluate(index: &Index, queries: &[Embedding], k: usize) -> Evaluation {
+    if queries.is_empty() {
+        return Evaluation::default();
+    }
+
+    let mut recall = 0.0;
+    let mut latencies = vec![];
+    let mut exact_latencies = vec![];
+    let mut distances = 0;
+    let mut exact_distances = 0;
+
+    for query in queries {
+        let search = || super::peek_with(index, query, k, SearchOptions::default());
+        let search_exact = || super::peek_exact(index, query, k);
+
+        let (result, latency) = timed(search);
+        let (exact, exact_latency) = timed(search_exact);
+
+        let (_, work) = super::profile::measure(search);
+        let (_, exact_work) = super::profile::measure(search_exact);
+        distances += work.distances;
+        exact_distances += exact_work.distances;
+
+        let expected = exact
+            .neighbors
+            .iter()
+            .map(|neighbor| neighbor.id.as_str())
+            .collect::<HashSet<&str>>();
+        let found = result
+            .neighbors
+            .iter()
+            .filter(|neighbor| expected.contains(neighbor.id.as_str()))
+            .count();
+
+        recall += match expected.len() {
+            0 => 1.0,
+            len => found as f64 / len as f64,
+        };
+        latencies.push(latency);
+        exact_latencies.push(exact_latency);
+    }
+
+    let count = queries.len() as f64;
+
+    Evaluation {
+        queries: queries.len(),
+        k,
+        recall: recall / count,
+        mean_latency: mean(&latencies),
+        p95_latency: percentile(&mut latencies, 0.95),
+        exact_mean_latency: mean(&exact_latencies),
+        exact_p95_latency: percentile(&mut exact_latencies, 0.95),
+        mean_distances: distances as f64 / count,
+        exact_mean_distances: exact_distances as f64 / count,
+    }
+}
+
+// Runs `f` and returns its result and duration in milliseconds.
+fn timed<T>(f: impl FnOnce() -> T) -> (T, f64) {
+    let start = super::profile::clock();
+    let result = f();
+
+    (result, super::profile::clock() - start)
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+// The nearest-rank percentile of non-empty `values`.
+fn percentile(values: &mut [f64], percentile: f64) -> f64 {
+    values.sort_by(f64::total_cmp);
+
+    let rank = (percentile * values.len() as f64).ceil() as usize;
+
+    values[rank.clamp(1, values.len()) - 1]
+}
@@ -0,0 +1,175 @@
+use crate::engine::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Insertion order, size and retrieval counters of the entries of an index
+/// with `IndexOptions::max_vectors` or `max_bytes` set. Searches update the
+/// counters through a shared reference.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Usage {
+    // Ticks on every insertion and every retrieval.
+    clock: AtomicU64,
+    entries: HashMap<u64, EntryUsage>,
+    vectors: usize,
+    bytes: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct EntryUsage {
+    inserted: u64,
+    last_used: AtomicU64,
+    hits: AtomicU64,
+    vectors: usize,
+    bytes: usize,
+}
+
+impl Clone for Usage {
+    fn clone(&self) -> Self {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(hash, entry)| {
+                let entry = EntryUsage {
+                    inserted: entry.inserted,
+                    last_used: AtomicU64::new(entry.last_used.load(Ordering::Relaxed)),
+                    hits: AtomicU64::new(entry.hits.load(Ordering::Relaxed)),
+                    vectors: entry.vectors,
+                    bytes: entry.bytes,
+                };
+
+                (*hash, entry)
+            })
+            .collect();
+
+        Usage {
+            clock: AtomicU64::new(self.clock.load(Ordering::Relaxed)),
+            entries,
+            vectors: self.vectors,
+            bytes: self.bytes,
+        }
+    }
+}
+
+impl Usage {
+    /// The number of vectors of the tracked entries.
+    pub fn vectors(&self) -> usize {
+        self.vectors
+    }
+
+    /// The estimated bytes of the tracked entries, see `entry_bytes`.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn insert(&mut self, hash: u64, vectors: usize, bytes: usize) {
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+
+        self.vectors += vectors;
+        self.bytes += bytes;
+        self.entries.insert(
+            hash,
+            EntryUsage {
+                inserted: tick,
+                last_used: AtomicU64::new(tick),
+                hits: AtomicU64::new(0),
+                vectors,
+                bytes,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, hash: u64) {
+        if let Some(entry) = self.entries.remove(&hash) {
+            self.vectors -= entry.vectors;
+            self.bytes -= entry.bytes;
+        }
+    }
+
+    /// Records that a search returned the entry.
+    pub fn touch(&self, hash: u64) {
+        if let Some(entry) = self.entries.get(&hash) {
+            let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+
+            entry.last_used.fetch_max(tick, Ordering::Relaxed);
+            entry.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the entries to evict, first to last by `policy`, so that at
+    /// most `max_vectors` vectors and `max_bytes` bytes remain. `keep` is
+    /// never chosen.
+    pub fn victims(
+        &self,
+        policy: Eviction,
+        max_vectors: usize,
+        max_bytes: usize,
+        keep: u64,
+    ) -> Vec<u64> {
+        if self.vectors <= max_vectors && self.bytes <= max_bytes {
+            return vec![];
+        }
+
+        let mut candidates = self
+            .entries
+            .iter()
+            .filter(|(hash, _)| **hash != keep)
+            .map(|(hash, entry)| {
+                let last_used = entry.last_used.load(Ordering::Relaxed);
+
+                let rank = match policy {
+                    Eviction::Oldest => (entry.inserted, 0),
+                    Eviction::Lru => (last_used, 0),
+                    Eviction::Lfu => (entry.hits.load(Ordering::Relaxed), last_used),
+                };
+
+                (rank, *hash)
+            })
+            .collect::<Vec<((u64, u64), u64)>>();
+
+        candidates.sort_unstable();
+
+        let (mut vectors, mut bytes) = (self.vectors, self.bytes);
+        let mut victims = vec![];
+
+        for (_, hash) in candidates {
+            if vectors <= max_vectors && bytes <= max_bytes {
+                break;
+            }
+
+            let entry = &self.entries[&hash];
+            vectors -= entry.vectors;
+            bytes -= entry.bytes;
+            victims.push(hash);
+        }
+
+        victims
+    }
+}
+
+/// The estimated bytes an entry takes: its vectors as stored by the tree, one
+/// tree item per vector, its id, its metadata and its content.
+pub fn entry_bytes(index: &Index, resource: &EmbeddedResource) -> usize {
+    let vector_bytes = |vector: &Embedding| match index.options.kind {
+        // kd-trees keep every vector zero padded.
+        IndexKind::KdTree => EMBEDDING_DIMENSION * size_of::<f32>(),
+        IndexKind::Ivf => vector.len().min(EMBEDDING_DIMENSION) * size_of::<f32>(),
+    } + size_of::<u64>();
+
+    let vectors = match &resource.chunks {
+        Some(chunks) => chunks.iter().map(vector_bytes).sum(),
+        None => vector_bytes(&resource.embeddings),
+    };
+
+    let metadata = resource.metadata.as_ref().map_or(0, |metadata| {
+        metadata
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum()
+    });
+
+    let content = resource.content.as_ref().map_or(0, String::len);
+
+    vectors + resource.id.len() + metadata + content
+}
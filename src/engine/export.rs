@@ -0,0 +1,374 @@
+use crate::engine::{types::*, Operation};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+// NumPy `.npy` format, version 1.0.
+// More detail: https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html
+const NPY_MAGIC: &[u8] = b"\x93NUMPY";
+
+/// One line of a JSON Lines export.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct ExportRecord {
+    pub id: String,
+    /// The vector of the entry, empty for multi-vector documents.
+    #[serde(default)]
+    pub vector: Vec<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<Vec<f32>>>,
+    /// When the entry expires, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// Returns every stored entry sorted by id, with vectors trimmed to the index
+/// dimension.
+pub fn records(index: &Index) -> Vec<ExportRecord> {
+    let hashes = index.hash.keys().copied().collect::<Vec<u64>>();
+
+    let mut records = records_of(index, &hashes, true, true, true);
+    records.sort_by(|a, b| a.id.cmp(&b.id));
+    records
+}
+
+/// Returns the entries with the id hashes `hashes`, in that order. Vectors,
+/// metadata and content are left out unless asked for. Vectors are read by
+/// walking the tree until all of them are found, which can take up to one pass
+/// over the whole tree.
+pub(crate) fn records_of(
+    index: &Index,
+    hashes: &[u64],
+    with_vectors: bool,
+    with_metadata: bool,
+    with_content: bool,
+) -> Vec<ExportRecord> {
+    let mut vectors: HashMap<u64, Vec<f32>> = HashMap::new();
+    let mut chunks: HashMap<u64, Vec<(usize, Vec<f32>)>> = HashMap::new();
+
+    if with_vectors {
+        // The tree items of the wanted entries: the id hash of a single-vector
+        // entry, or one item per chunk. The tree has no lookup by item, so it
+        // is walked until every one of them has been found.
+        let mut wanted: HashMap<u64, Option<Chunk>> = HashMap::new();
+
+        for hash in hashes {
+            let Some(id) = index.hash.get(hash) else {
+                continue;
+            };
+
+            for position in 0usize.. {
+                let item = super::hash(&(id, position));
+
+                match index.chunks.get(&item) {
+                    Some(chunk) if chunk.document == *hash => {
+                        wanted.insert(item, Some(*chunk));
+                    }
+                    _ if position == 0 => {
+                        wanted.insert(*hash, None);
+                        break;
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        for (item, vector) in index.tree.iter() {
+            if wanted.is_empty() {
+                break;
+            }
+
+            let Some(chunk) = wanted.remove(&item) else {
+                continue;
+            };
+
+            let vector = vector[..index.dimension].to_vec();
+
+            match chunk {
+                Some(chunk) => chunks
+                    .entry(chunk.document)
+                    .or_default()
+                    .push((chunk.position, vector)),
+                None => {
+                    vectors.insert(item, vector);
+                }
+            }
+        }
+    }
+
+    hashes
+        .iter()
+        .filter_map(|hash| {
+            let chunks = chunks.remove(hash).map(|mut chunks| {
+                chunks.sort_by_key(|(position, _)| *position);
+                chunks.into_iter().map(|(_, vector)| vector).collect()
+            });
+
+            Some(ExportRecord {
+                id: index.hash.get(hash)?.to_owned(),
+                vector: vectors.remove(hash).unwrap_or_default(),
+                metadata: match with_metadata {
+                    true => index.metadata.get(hash).cloned(),
+                    false => None,
+                },
+                chunks,
+                expires_at: index.expires.get(hash).copied(),
+                content: match with_content {
+                    true => index.content.get(hash).map(|content| content.to_string()),
+                    false => None,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Exports every entry as JSON Lines, one `ExportRecord` per line.
+pub fn export_jsonl(index: &Index) -> Result<String, EngineError> {
+    let mut output = String::new();
+
+    for record in records(index) {
+        let line = serde_json::to_string(&record)
+            .map_err(|err| EngineError::new(format!("Failed to export {}: {}", record.id, err)))?;
+
+        output.push_str(&line);
+        output.push('\n');
+    }
+
+    Ok(output)
+}
+
+/// Adds the entries of a JSON Lines export. Nothing is added if any line is
+/// invalid or any id already exists.
+pub fn import_jsonl(index: &mut Index, data: &str) -> Result<(), EngineError> {
+    let mut resources = vec![];
+    let mut lines = vec![];
+
+    for (line_number, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: ExportRecord = serde_json::from_str(line).map_err(|err| {
+            EngineError::new(format!("Invalid record at line {}: {}", line_number + 1, err))
+        })?;
+
+        lines.push(line_number + 1);
+        resources.push(EmbeddedResource {
+            id: record.id,
+            embeddings: record.vector,
+            metadata: record.metadata,
+            chunks: record.chunks,
+            expires_at: record.expires_at,
+            ttl: None,
+            content: record.content,
+        });
+    }
+
+    add_all(index, resources, |position| format!("line {}", lines[position]))
+}
+
+/// Exports the vectors as a little-endian float32 `.npy` matrix of shape
+/// `(vectors, dimension)`. Row `i` belongs to the `i`-th returned id, the id of
+/// a multi-vector document is repeated for each of its chunks.
+pub fn export_npy(index: &Index) -> (Vec<u8>, Vec<String>) {
+    let rows = rows(index);
+
+    let header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        rows.len(),
+        index.dimension
+    );
+
+    // The magic, version and header length take 10 bytes, the header is
+    // padded with spaces and terminated by a newline to a multiple of 64.
+    let padding = 64 - (10 + header.len() + 1) % 64;
+    let header = format!("{}{}\n", header, " ".repeat(padding % 64));
+
+    let mut output = Vec::with_capacity(10 + header.len() + rows.len() * index.dimension * 4);
+    output.extend_from_slice(NPY_MAGIC);
+    output.extend_from_slice(&[1, 0]);
+    output.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    output.extend_from_slice(header.as_bytes());
+
+    let mut ids = Vec::with_capacity(rows.len());
+
+    for (id, vector) in rows {
+        for value in vector {
+            output.extend_from_slice(&value.to_le_bytes());
+        }
+        ids.push(id);
+    }
+
+    (output, ids)
+}
+
+/// Returns the ids in the row order of `export_npy`.
+pub fn export_ids(index: &Index) -> Vec<String> {
+    rows(index).into_iter().map(|(id, _)| id).collect()
+}
+
+/// Adds the rows of a float32 `.npy` matrix, `ids` gives the id of each row.
+/// Rows sharing an id become the chunks of one multi-vector document.
+pub fn import_npy(index: &mut Index, data: &[u8], ids: &[String]) -> Result<(), EngineError> {
+    if data.len() < 10 || &data[..6] != NPY_MAGIC {
+        return Err(EngineError::new("Invalid npy file".to_string()));
+    }
+
+    let (header_len, header_start) = match data[6] {
+        1 => (u16::from_le_bytes([data[8], data[9]]) as usize, 10),
+        2 | 3 if data.len() >= 12 => (
+            u32::from_le_bytes([data[8], data[9], data[10], data[11]]) as usize,
+            12,
+        ),
+        version => {
+            return Err(EngineError::new(format!(
+                "Unsupported npy version {}",
+                version
+            )))
+        }
+    };
+
+    let body_start = usize::checked_add(header_start, header_len)
+        .ok_or_else(|| EngineError::new("Invalid npy header".to_string()))?;
+
+    let header = data
+        .get(header_start..body_start)
+        .and_then(|header| std::str::from_utf8(header).ok())
+        .ok_or_else(|| EngineError::new("Invalid npy header".to_string()))?;
+
+    if !header.contains("'descr': '<f4'") {
+        return Err(EngineError::new(
+            "Only little-endian float32 npy matrices are supported".to_string(),
+        ));
+    }
+
+    if !header.contains("'fortran_order': False") {
+        return Err(EngineError::new(
+            "Only C-ordered npy matrices are supported".to_string(),
+        ));
+    }
+
+    let (rows, columns) = parse_shape(header)?;
+
+    if rows != ids.len() {
+        return Err(EngineError::new(format!(
+            "The matrix has {} rows but {} ids were given",
+            rows,
+            ids.len()
+        )));
+    }
+
+    let body = &data[body_start..];
+    let expected = rows
+        .checked_mul(columns)
+        .and_then(|values| values.checked_mul(4))
+        .ok_or_else(|| EngineError::new("Invalid npy shape".to_string()))?;
+
+    if body.len() != expected {
+        return Err(EngineError::new(format!(
+            "Expected {} bytes of data, found {}",
+            expected,
+            body.len()
+        )));
+    }
+
+    let mut rows: Vec<(String, Vec<Vec<f32>>)> = vec![];
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+
+    for (row, id) in ids.iter().enumerate() {
+        let vector = body[row * columns * 4..(row + 1) * columns * 4]
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect();
+
+        match positions.get(id.as_str()) {
+            Some(&position) => rows[position].1.push(vector),
+            None => {
+                positions.insert(id, rows.len());
+                rows.push((id.to_owned(), vec![vector]));
+            }
+        }
+    }
+
+    let resources = rows
+        .into_iter()
+        .map(|(id, mut vectors)| match vectors.len() {
+            1 => EmbeddedResource {
+                id,
+                embeddings: vectors.remove(0),
+                ..Default::default()
+            },
+            _ => EmbeddedResource {
+                id,
+                chunks: Some(vectors),
+                ..Default::default()
+            },
+        })
+        .collect::<Vec<EmbeddedResource>>();
+    let ids = resources
+        .iter()
+        .map(|resource| resource.id.to_owned())
+        .collect::<Vec<String>>();
+
+    add_all(index, resources, |position| format!("id {}", ids[position]))
+}
+
+// One `(id, vector)` pair per stored vector, in record order.
+fn rows(index: &Index) -> Vec<(String, Vec<f32>)> {
+    records(index)
+        .into_iter()
+        .flat_map(|record| match record.chunks {
+            Some(chunks) => chunks
+                .into_iter()
+                .map(|vector| (record.id.to_owned(), vector))
+                .collect::<Vec<(String, Vec<f32>)>>(),
+            None => vec![(record.id, record.vector)],
+        })
+        .collect()
+}
+
+fn parse_shape(header: &str) -> Result<(usize, usize), EngineError> {
+    let invalid = || EngineError::new("Invalid npy shape".to_string());
+
+    let start = header.find("'shape':").ok_or_else(invalid)?;
+    let shape = &header[start..];
+    let shape = &shape[shape.find('(').ok_or_else(invalid)? + 1..shape.find(')').ok_or_else(invalid)?];
+
+    let dimensions = shape
+        .split(',')
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse::<usize>().map_err(|_| invalid()))
+        .collect::<Result<Vec<usize>, EngineError>>()?;
+
+    match dimensions[..] {
+        [rows, columns] => Ok((rows, columns)),
+        [rows] => Ok((rows, 1)),
+        _ => Err(EngineError::new(
+            "Only one or two dimensional npy matrices are supported".to_string(),
+        )),
+    }
+}
+
+// Adds `resources` in one transaction, so a failed import leaves the index
+// untouched. `source` names where the resource at a position came from.
+fn add_all(
+    index: &mut Index,
+    resources: Vec<EmbeddedResource>,
+    source: impl Fn(usize) -> String,
+) -> Result<(), EngineError> {
+    index.check_mutable()?;
+
+    let operations = resources.into_iter().map(Operation::Add).collect();
+
+    super::transact(index, operations).map_err(|err| {
+        EngineError::new(format!("Failed to import {}: {}", source(err.operation), err.error))
+    })?;
+
+    Ok(())
+}
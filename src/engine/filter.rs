@@ -0,0 +1,77 @@
+use crate::engine::types::*;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+/// A predicate over the metadata of an entry, e.g.
+/// `{ "and": [{ "eq": { "key": "chat", "value": "42" } }, { "exists": "pinned" }] }`.
+/// Entries without metadata have no keys.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataFilter {
+    /// The key is set to the value.
+    Eq {
+        key: String,
+        value: String,
+    },
+    /// The key is not set to the value, or not set at all.
+    Ne {
+        key: String,
+        value: String,
+    },
+    /// The key is set to one of the values.
+    In {
+        key: String,
+        values: Vec<String>,
+    },
+    /// The key is set.
+    Exists(String),
+    /// Every filter matches, always true when empty.
+    And(Vec<MetadataFilter>),
+    /// At least one filter matches, always false when empty.
+    Or(Vec<MetadataFilter>),
+    Not(Box<MetadataFilter>),
+}
+
+impl MetadataFilter {
+    pub fn matches(&self, metadata: Option<&Metadata>) -> bool {
+        let get = |key: &str| metadata.and_then(|metadata| metadata.get(key));
+
+        match self {
+            MetadataFilter::Eq { key, value } => get(key) == Some(value),
+            MetadataFilter::Ne { key, value } => get(key) != Some(value),
+            MetadataFilter::In { key, values } => get(key).is_some_and(|v| values.contains(v)),
+            MetadataFilter::Exists(key) => get(key).is_some(),
+            MetadataFilter::And(filters) => filters.iter().all(|f| f.matches(metadata)),
+            MetadataFilter::Or(filters) => filters.iter().any(|f| f.matches(metadata)),
+            MetadataFilter::Not(filter) => !filter.matches(metadata),
+        }
+    }
+}
+
+/// Removes the entries whose metadata matches `filter` and returns their ids
+/// sorted. The tree is updated in a single pass, like `remove`. Fails if the
+/// index is frozen.
+pub fn remove_where(
+    index: &mut Index,
+    filter: &MetadataFilter,
+) -> Result<Vec<String>, EngineError> {
+    index.check_mutable()?;
+
+    let mut ids = index
+        .hash
+        .iter()
+        .filter(|(hash, _)| filter.matches(index.metadata.get(hash)))
+        .map(|(_, id)| id.to_owned())
+        .collect::<Vec<String>>();
+    ids.sort();
+
+    if ids.is_empty() {
+        return Ok(ids);
+    }
+
+    super::remove(index, &ids)?;
+
+    Ok(ids)
+}
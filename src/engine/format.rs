@@ -0,0 +1,186 @@
+//! The layout of the snapshots written by `dump` and `dump_collections`.
+//!
+//! A snapshot starts with `MAGIC` and the little-endian `u32` format version,
+//! followed by the gzip compressed bincode encoding. Snapshots written before
+//! the header existed are a bare gzip stream holding one index, they are
+//! migrated when loaded.
+
+use crate::engine::types::*;
+use std::collections::HashMap;
+use std::io::Read;
+
+const MAGIC: &[u8; 4] = b"LVDB";
+const HEADER_SIZE: usize = 8;
+const GZIP_MAGIC: &[u8; 2] = b"\x1f\x8b";
+
+/// The format version written by this build.
+pub const FORMAT_VERSION: u32 = 1;
+
+// The bucket size of the kd-tree of legacy snapshots.
+const LEGACY_BUCKET_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Current,
+    Legacy,
+}
+
+/// The header starting every snapshot.
+pub(crate) fn header() -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+/// Returns the format of the snapshot `data` and its compressed body.
+pub(crate) fn read_header(data: &[u8]) -> Result<(Format, &[u8]), EngineError> {
+    if data.starts_with(GZIP_MAGIC) {
+        return Ok((Format::Legacy, data));
+    }
+
+    if data.len() < HEADER_SIZE || &data[..4] != MAGIC {
+        return Err(EngineError::new("Invalid snapshot".to_string()));
+    }
+
+    let version = u32::from_le_bytes(data[4..HEADER_SIZE].try_into().unwrap());
+
+    if version != FORMAT_VERSION {
+        return Err(EngineError::new(format!(
+            "Unsupported snapshot format version {}, expected {}",
+            version, FORMAT_VERSION
+        )));
+    }
+
+    Ok((Format::Current, &data[HEADER_SIZE..]))
+}
+
+// Legacy snapshots hold a bincode `{ tree, hash }` index, the tree being a
+// kiddo `KdTree<f32, u64, 2048, 32, u16>` keyed by id hash:
+//
+//   leaves   u64 count, then per leaf 32 * 2048 f32 points, 32 u64 items, u16 size
+//   stems    u64 count, then per stem u16 left, u16 right, f32 split value
+//   root     u16
+//   size     u64
+//   hash     u64 count, then per entry u64 id hash and the id as a u64 length
+//            and UTF-8 bytes
+//
+// Reading the points one leaf at a time avoids kiddo's deserializer, which
+// builds every leaf on the stack.
+
+/// Rebuilds the index of a decompressed legacy snapshot with the default
+/// options.
+pub(crate) fn read_legacy(reader: impl Read) -> Result<Index, EngineError> {
+    let (mut index, entries) = read_legacy_entries(reader)?;
+    super::build(&mut index, entries)?;
+
+    Ok(index)
+}
+
+/// Like `read_legacy`, returning the index with its entries registered and
+/// the `(tree item, vector)` pairs its tree is to be built from.
+pub(crate) fn read_legacy_entries(
+    mut reader: impl Read,
+) -> Result<(Index, Vec<(u64, Embedding)>), EngineError> {
+    let invalid = || EngineError::new("Invalid legacy snapshot".to_string());
+    let mut entries = vec![];
+
+    let leaves = read_u64(&mut reader)?;
+    let mut points = vec![0u8; LEGACY_BUCKET_SIZE * EMBEDDING_DIMENSION * 4];
+    let mut items = vec![0u8; LEGACY_BUCKET_SIZE * 8];
+
+    for _ in 0..leaves {
+        reader.read_exact(&mut points).map_err(|_| invalid())?;
+        reader.read_exact(&mut items).map_err(|_| invalid())?;
+        let size = read_u16(&mut reader)? as usize;
+
+        for position in 0..size.min(LEGACY_BUCKET_SIZE) {
+            let item = u64::from_le_bytes(items[position * 8..][..8].try_into().unwrap());
+            let vector = points[position * EMBEDDING_DIMENSION * 4..][..EMBEDDING_DIMENSION * 4]
+                .chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+                .collect::<Embedding>();
+
+            entries.push((item, vector));
+        }
+    }
+
+    let stems = read_u64(&mut reader)?;
+    skip(&mut reader, stems.saturating_mul(8))?;
+    // The root index and the size.
+    skip(&mut reader, 2 + 8)?;
+
+    let mut ids = HashMap::new();
+
+    for _ in 0..read_u64(&mut reader)? {
+        let hash = read_u64(&mut reader)?;
+        let len = read_u64(&mut reader)?;
+
+        let mut id = vec![];
+        (&mut reader)
+            .take(len)
+            .read_to_end(&mut id)
+            .map_err(|_| invalid())?;
+
+        if id.len() as u64 != len {
+            return Err(invalid());
+        }
+
+        ids.insert(hash, String::from_utf8(id).map_err(|_| invalid())?);
+    }
+
+    // Vectors were zero padded, they are cut back to the longest one.
+    let dimension = entries
+        .iter()
+        .map(|(_, vector)| super::trim(vector).len())
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    let mut index = Index::new();
+    let mut registered = Vec::with_capacity(entries.len());
+
+    for (item, mut vector) in entries {
+        let Some(id) = ids.remove(&item) else {
+            continue;
+        };
+        vector.truncate(dimension);
+
+        registered.extend(super::register(
+            &mut index,
+            EmbeddedResource {
+                id,
+                embeddings: vector,
+                ..Default::default()
+            },
+        )?);
+    }
+
+    Ok((index, registered))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, EngineError> {
+    let mut bytes = [0u8; 8];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(|_| EngineError::new("Invalid legacy snapshot".to_string()))?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_u16(reader: &mut impl Read) -> Result<u16, EngineError> {
+    let mut bytes = [0u8; 2];
+    reader
+        .read_exact(&mut bytes)
+        .map_err(|_| EngineError::new("Invalid legacy snapshot".to_string()))?;
+
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn skip(reader: &mut impl Read, len: u64) -> Result<(), EngineError> {
+    let skipped = std::io::copy(&mut reader.take(len), &mut std::io::sink())?;
+
+    match skipped == len {
+        true => Ok(()),
+        false => Err(EngineError::new("Invalid legacy snapshot".to_string())),
+    }
+}
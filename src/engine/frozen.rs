@@ -0,0 +1,269 @@
+use crate::engine::{candidate::Candidate, types::*};
+use serde::{Deserialize, Serialize};
+use std::collections::BinaryHeap;
+
+/// A read-optimized kd-tree built once from the content of another tree.
+///
+/// The vectors are stored once, cut to the longest of them and laid out row
+/// by row in tree order, so every leaf is one contiguous slice. Each stem
+/// splits its entries in half along their widest dimension, which balances
+/// the tree.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Frozen {
+    // The number of values stored per vector.
+    pub dimension: usize,
+    // `dimension` values per entry, in tree order.
+    pub points: Vec<f32>,
+    // The tree item of each entry.
+    pub items: Vec<u64>,
+    // The `(dimension, value)` split of each stem, stored as a binary heap:
+    // the children of stem `n` are `2n + 1` and `2n + 2`. The node covering
+    // at most `BUCKET_SIZE` entries is a leaf instead.
+    pub stems: Vec<(u32, f32)>,
+}
+
+impl Frozen {
+    pub fn new(entries: Vec<(u64, Embedding)>) -> Self {
+        let dimension = entries
+            .iter()
+            .map(|(_, vector)| super::trim(vector).len())
+            .max()
+            .unwrap_or(0);
+
+        let mut points = Vec::with_capacity(entries.len() * dimension);
+        let mut items = Vec::with_capacity(entries.len());
+
+        for (item, mut vector) in entries {
+            vector.resize(dimension, 0.0);
+            points.extend_from_slice(&vector);
+            items.push(item);
+        }
+
+        let mut frozen = Frozen {
+            dimension,
+            points,
+            items,
+            stems: vec![],
+        };
+        frozen.split(0, 0, frozen.items.len());
+
+        frozen
+    }
+
+    pub fn size(&self) -> usize {
+        self.items.len()
+    }
+
+    /// The number of leaves and the depth of the deepest one.
+    pub fn shape(&self) -> (usize, usize) {
+        fn walk(size: usize, depth: usize) -> (usize, usize) {
+            if size <= BUCKET_SIZE {
+                return (1, depth);
+            }
+
+            let (left, left_depth) = walk(left_size(size), depth + 1);
+            let (right, right_depth) = walk(size - left_size(size), depth + 1);
+            (left + right, left_depth.max(right_depth))
+        }
+
+        match self.size() {
+            0 => (0, 0),
+            size => walk(size, 0),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[f32])> + '_ {
+        self.items
+            .iter()
+            .enumerate()
+            .map(|(position, item)| (*item, self.point(position)))
+    }
+
+    pub fn nearest(
+        &self,
+        query: &[f32; EMBEDDING_DIMENSION],
+        n: usize,
+        metric: Metric,
+    ) -> Vec<(f32, u64)> {
+        if n == 0 {
+            return vec![];
+        }
+
+        // Stored vectors are zero past `dimension`, so that part of the
+        // distance is the same for every entry.
+        let rest = query[self.dimension..]
+            .iter()
+            .map(|value| term(metric, *value))
+            .sum::<f32>();
+
+        let mut search = Search {
+            query: &query[..self.dimension],
+            metric,
+            rest,
+            n,
+            best: BinaryHeap::with_capacity(n + 1),
+            distances: 0,
+            nodes: 0,
+        };
+        self.search(&mut search, 0, 0, self.size());
+
+        super::profile::count_distances(search.distances);
+        super::profile::count_nodes(search.nodes);
+
+        search
+            .best
+            .into_sorted_vec()
+            .into_iter()
+            .map(|candidate| {
+                let item = self.items[candidate.position];
+
+                match metric {
+                    // For unit vectors |a - b|^2 = 2 - 2cos(a, b).
+                    Metric::Cosine => (candidate.distance / 2.0, item),
+                    _ => (candidate.distance, item),
+                }
+            })
+            .collect()
+    }
+
+    fn point(&self, position: usize) -> &[f32] {
+        &self.points[position * self.dimension..][..self.dimension]
+    }
+
+    // Orders the entries `start..end` below `stem` along their widest
+    // dimension, the smallest `left_size` of them going to the left child,
+    // then splits both children the same way.
+    fn split(&mut self, stem: usize, start: usize, end: usize) {
+        if end - start <= BUCKET_SIZE {
+            return;
+        }
+
+        let split_dimension = (0..self.dimension)
+            .map(|dimension| (self.spread(dimension, start, end), dimension))
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map_or(0, |(_, dimension)| dimension);
+
+        let middle = start + left_size(end - start);
+        let value = |point: &[f32]| point.get(split_dimension).copied().unwrap_or(0.0);
+
+        let mut order = (start..end).collect::<Vec<usize>>();
+        order.select_nth_unstable_by(middle - start, |a, b| {
+            value(self.point(*a)).total_cmp(&value(self.point(*b)))
+        });
+
+        let points = order
+            .iter()
+            .flat_map(|position| self.point(*position).to_owned())
+            .collect::<Vec<f32>>();
+        let items = order
+            .iter()
+            .map(|position| self.items[*position])
+            .collect::<Vec<u64>>();
+        self.points[start * self.dimension..end * self.dimension].copy_from_slice(&points);
+        self.items[start..end].copy_from_slice(&items);
+
+        if self.stems.len() <= stem {
+            self.stems.resize(stem + 1, (0, 0.0));
+        }
+        self.stems[stem] = (split_dimension as u32, value(self.point(middle)));
+
+        self.split(2 * stem + 1, start, middle);
+        self.split(2 * stem + 2, middle, end);
+    }
+
+    // The range of the values of `dimension` among the entries `start..end`.
+    fn spread(&self, dimension: usize, start: usize, end: usize) -> f32 {
+        let (min, max) = (start..end)
+            .map(|position| self.point(position)[dimension])
+            .fold((f32::MAX, f32::MIN), |(min, max), value| {
+                (min.min(value), max.max(value))
+            });
+
+        max - min
+    }
+
+    fn search(&self, search: &mut Search, stem: usize, start: usize, end: usize) {
+        if end - start <= BUCKET_SIZE {
+            for position in start..end {
+                let distance = search.rest
+                    + self
+                        .point(position)
+                        .iter()
+                        .zip(search.query)
+                        .map(|(a, b)| term(search.metric, a - b))
+                        .sum::<f32>();
+                search.distances += 1;
+                search.offer(Candidate { distance, position });
+            }
+
+            return;
+        }
+
+        search.nodes += 1;
+
+        let (split_dimension, value) = self.stems[stem];
+        let middle = start + left_size(end - start);
+        let diff = search
+            .query
+            .get(split_dimension as usize)
+            .copied()
+            .unwrap_or(0.0)
+            - value;
+
+        let (near, far) = match diff < 0.0 {
+            true => ((2 * stem + 1, start, middle), (2 * stem + 2, middle, end)),
+            false => ((2 * stem + 2, middle, end), (2 * stem + 1, start, middle)),
+        };
+
+        self.search(search, near.0, near.1, near.2);
+
+        if search.reaches(search.rest + term(search.metric, diff)) {
+            self.search(search, far.0, far.1, far.2);
+        }
+    }
+}
+
+// The number of entries below the left child of a stem over `size` entries,
+// rounded to whole leaves so that only the last leaf is not full.
+fn left_size(size: usize) -> usize {
+    size.div_ceil(BUCKET_SIZE).div_ceil(2) * BUCKET_SIZE
+}
+
+// The contribution of a coordinate difference to the distance under `metric`.
+fn term(metric: Metric, diff: f32) -> f32 {
+    match metric {
+        Metric::Manhattan => diff.abs(),
+        Metric::Euclidean | Metric::Cosine => diff * diff,
+    }
+}
+
+struct Search<'a> {
+    query: &'a [f32],
+    metric: Metric,
+    rest: f32,
+    n: usize,
+    // The closest candidates so far, the farthest on top.
+    best: BinaryHeap<Candidate>,
+    distances: usize,
+    nodes: usize,
+}
+
+impl Search<'_> {
+    fn offer(&mut self, candidate: Candidate) {
+        if self.best.len() < self.n {
+            self.best.push(candidate);
+        } else if self.best.peek().is_some_and(|worst| candidate < *worst) {
+            self.best.pop();
+            self.best.push(candidate);
+        }
+    }
+
+    // Whether an entry at `distance` could still be among the closest.
+    fn reaches(&self, distance: f32) -> bool {
+        self.best.len() < self.n
+            || self
+                .best
+                .peek()
+                .is_some_and(|worst| distance <= worst.distance)
+    }
+}
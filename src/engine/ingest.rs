@@ -0,0 +1,479 @@
+use crate::engine::{format::Format, types::*, Collections, Operation, TreeBuilder, DEFAULT_COLLECTION};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Cursor, Read};
+use std::sync::Arc;
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+/// How far a job has come. `IngestJob` counts vectors, `LoadJob` counts bytes:
+/// those of the compressed snapshot, then those of the decompressed one.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+}
+
+/// Adds resources to an index a bounded number of vectors per `step`, so the
+/// caller can yield between steps instead of blocking on a large import.
+#[derive(Debug)]
+pub struct IngestJob {
+    pending: VecDeque<EmbeddedResource>,
+    replace: bool,
+    replacement: Option<Replacement>,
+    // Ids evicted by the added resources, see `add`.
+    evicted: Vec<String>,
+    done: usize,
+    total: usize,
+    finished: bool,
+}
+
+// Replacing jobs register into a new index, then build its tree in further
+// steps. Searches see the old content until the tree is built.
+#[derive(Debug)]
+struct Replacement {
+    index: Index,
+    entries: Vec<(u64, Embedding)>,
+    builder: Option<TreeBuilder>,
+    // The revision of the replaced index when the job started.
+    revision: u64,
+}
+
+impl IngestJob {
+    /// A job adding `resources` one by one, like calling `add` for each.
+    pub fn add(resources: Vec<EmbeddedResource>) -> Self {
+        IngestJob::new(resources, false)
+    }
+
+    /// A job replacing the content of the index with `resources`, like
+    /// `index` with the options of the index it is stepped with. Its progress
+    /// counts every vector twice, once registered and once built into the
+    /// tree.
+    pub fn index(resources: Vec<EmbeddedResource>) -> Self {
+        IngestJob::new(resources, true)
+    }
+
+    fn new(resources: Vec<EmbeddedResource>, replace: bool) -> Self {
+        let vectors = resources.iter().map(vector_count).sum::<usize>();
+
+        IngestJob {
+            pending: resources.into(),
+            replace,
+            replacement: None,
+            evicted: vec![],
+            done: 0,
+            total: if replace { vectors * 2 } else { vectors },
+            finished: false,
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        let built = self
+            .replacement
+            .as_ref()
+            .and_then(|replacement| replacement.builder.as_ref())
+            .map_or(0, |builder| builder.done());
+
+        Progress {
+            done: (self.done + built).min(self.total),
+            total: self.total,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.finished
+    }
+
+    /// Returns the ids evicted since the last call, when the index has size
+    /// limits.
+    pub fn take_evicted(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Processes resources until at least `max_vectors` vectors are done or
+    /// none are left. A multi-vector resource is always processed whole.
+    ///
+    /// The resources of a step are added in one transaction. A resource that
+    /// fails to add is dropped and its error returned, the others of the step
+    /// are not added and are retried by the next step. A replacing job fails and stops when the index
+    /// was modified between its steps, or when the resources do not fit the
+    /// index. Once done, further steps do nothing.
+    pub fn step(&mut self, index: &mut Index, max_vectors: usize) -> Result<Progress, EngineError> {
+        if self.finished {
+            return Ok(self.progress());
+        }
+
+        index.check_mutable()?;
+
+        if self.replace && self.replacement.is_none() {
+            self.replacement = Some(Replacement {
+                index: Index::with_options(index.options.to_owned())?,
+                entries: vec![],
+                builder: None,
+                revision: index.revision,
+            });
+        }
+
+        if let Err(err) = self.check_revision(index) {
+            self.stop();
+            return Err(err);
+        }
+
+        let mut budget = max_vectors.max(1);
+
+        match self.replacement.as_mut() {
+            Some(replacement) => {
+                while budget > 0 {
+                    let Some(resource) = self.pending.pop_front() else {
+                        break;
+                    };
+
+                    let count = vector_count(&resource);
+                    self.done += count;
+                    budget = budget.saturating_sub(count);
+
+                    let entries = super::register(&mut replacement.index, resource)?;
+                    replacement.entries.extend(entries);
+                }
+            }
+            None => budget = self.add_batch(index, budget)?,
+        }
+
+        if !self.pending.is_empty() {
+            return Ok(self.progress());
+        }
+
+        let Some(replacement) = self.replacement.as_mut() else {
+            self.finished = true;
+            return Ok(self.progress());
+        };
+
+        if replacement.builder.is_none() {
+            let entries = std::mem::take(&mut replacement.entries);
+
+            match super::builder(&replacement.index, entries) {
+                Ok(builder) => replacement.builder = Some(builder),
+                Err(err) => {
+                    self.stop();
+                    return Err(err);
+                }
+            }
+        }
+
+        let built = budget > 0
+            && replacement
+                .builder
+                .as_mut()
+                .is_some_and(|builder| builder.step(budget));
+
+        if built {
+            if let Some(Replacement {
+                index: mut replacement,
+                builder: Some(builder),
+                ..
+            }) = self.replacement.take()
+            {
+                replacement.tree = Arc::new(builder.finish());
+                *index = replacement;
+            }
+
+            self.done = self.total;
+            self.finished = true;
+        }
+
+        Ok(self.progress())
+    }
+
+    // Adds the next resources holding `budget` vectors in one transaction.
+    // Returns the budget left.
+    fn add_batch(&mut self, index: &mut Index, mut budget: usize) -> Result<usize, EngineError> {
+        let mut batch = vec![];
+
+        while budget > 0 {
+            let Some(resource) = self.pending.pop_front() else {
+                break;
+            };
+
+            budget = budget.saturating_sub(vector_count(&resource));
+            batch.push(resource);
+        }
+
+        if batch.is_empty() {
+            return Ok(budget);
+        }
+
+        let operations = batch.iter().cloned().map(Operation::Add).collect();
+
+        match super::transact(index, operations) {
+            Ok(evicted) => {
+                self.done += batch.iter().map(vector_count).sum::<usize>();
+                self.evicted.extend(evicted);
+                Ok(budget)
+            }
+            Err(err) => {
+                let failed = batch.remove(err.operation);
+                self.done += vector_count(&failed);
+
+                for resource in batch.into_iter().rev() {
+                    self.pending.push_front(resource);
+                }
+
+                Err(EngineError::new(format!(
+                    "Failed to add {}: {}",
+                    failed.id, err.error
+                )))
+            }
+        }
+    }
+
+    fn check_revision(&self, index: &Index) -> Result<(), EngineError> {
+        match &self.replacement {
+            Some(replacement) if replacement.revision != index.revision => Err(EngineError::new(
+                "The index was modified while the job was replacing its content".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Ends the job without touching the index.
+    fn stop(&mut self) {
+        self.pending.clear();
+        self.replacement = None;
+        self.finished = true;
+    }
+}
+
+/// Restores collections from a snapshot created by `dump_collections` a
+/// bounded number of bytes per `step`: the snapshot is decompressed first,
+/// then its collections are decoded and their trees built.
+///
+/// The total counts the decompressed bytes as recorded in the gzip trailer,
+/// it is corrected once the snapshot is decompressed if that was wrong.
+pub struct LoadJob {
+    decoder: GzDecoder<Cursor<Vec<u8>>>,
+    // The snapshot format, or why the header is invalid.
+    format: Result<Format, String>,
+    buffer: Vec<u8>,
+    // The size of the decompressed snapshot.
+    expected: usize,
+    decoding: Option<Decoding>,
+    collections: Option<Collections>,
+}
+
+// The state of a decompressed snapshot being decoded.
+struct Decoding {
+    // The collections left to decode.
+    remaining: u64,
+    // The start of the next collection in the buffer.
+    position: usize,
+    indexes: HashMap<String, Index>,
+    building: Option<Building>,
+}
+
+// A decoded collection whose tree is being built. Its tree is counted as the
+// bytes of the collection in the buffer.
+struct Building {
+    name: String,
+    index: Index,
+    builder: TreeBuilder,
+    start: usize,
+    len: usize,
+}
+
+impl LoadJob {
+    pub fn new(data: Vec<u8>) -> Self {
+        let (format, start) = match super::format::read_header(&data) {
+            Ok((format, body)) => (Ok(format), data.len() - body.len()),
+            Err(err) => (Err(err.message), 0),
+        };
+
+        // The gzip trailer ends with the decompressed size modulo 2^32.
+        let expected = match data.len() >= start + 4 {
+            true => u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap()) as usize,
+            false => 0,
+        };
+
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(start as u64);
+
+        LoadJob {
+            decoder: GzDecoder::new(cursor),
+            format,
+            buffer: vec![],
+            expected,
+            decoding: None,
+            collections: None,
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        let compressed = self.decoder.get_ref().get_ref().len();
+        let total = compressed + self.expected;
+
+        let done = match (&self.collections, &self.decoding) {
+            (Some(_), _) => total,
+            (None, Some(decoding)) => compressed + decoding.decoded(),
+            (None, None) => (self.decoder.get_ref().position() as usize).min(compressed),
+        };
+
+        Progress { done, total }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.collections.is_some()
+    }
+
+    /// Decompresses or decodes up to about `max_bytes` bytes of the snapshot.
+    /// A collection is always decoded whole, its tree is built over further
+    /// steps.
+    pub fn step(&mut self, max_bytes: usize) -> Result<Progress, EngineError> {
+        let mut budget = max_bytes.max(1);
+
+        while budget > 0 && self.collections.is_none() {
+            budget = match self.decoding.is_some() {
+                true => self.decode(budget)?,
+                false => self.decompress(budget)?,
+            };
+        }
+
+        Ok(self.progress())
+    }
+
+    /// Returns the restored collections, running the remaining steps at once
+    /// if the job is not done yet.
+    pub fn finish(mut self) -> Result<Collections, EngineError> {
+        while self.collections.is_none() {
+            self.step(usize::MAX)?;
+        }
+
+        Ok(self.collections.unwrap_or_default())
+    }
+
+    // Returns the budget left.
+    fn decompress(&mut self, budget: usize) -> Result<usize, EngineError> {
+        let format = self.format.to_owned().map_err(EngineError::new)?;
+
+        let read = (&mut self.decoder)
+            .take(budget as u64)
+            .read_to_end(&mut self.buffer)?;
+
+        if read == budget {
+            return Ok(0);
+        }
+
+        self.expected = self.buffer.len();
+        self.decoding = Some(match format {
+            Format::Current => {
+                let mut reader = &self.buffer[..];
+                let remaining = bincode::deserialize_from::<_, u64>(&mut reader)?;
+
+                Decoding {
+                    remaining,
+                    position: self.buffer.len() - reader.len(),
+                    indexes: HashMap::new(),
+                    building: None,
+                }
+            }
+            Format::Legacy => {
+                let (index, entries) = super::format::read_legacy_entries(&self.buffer[..])?;
+
+                Decoding {
+                    remaining: 0,
+                    position: self.buffer.len(),
+                    indexes: HashMap::new(),
+                    building: Some(Building {
+                        name: DEFAULT_COLLECTION.to_string(),
+                        builder: super::builder(&index, entries)?,
+                        index,
+                        start: 0,
+                        len: self.buffer.len(),
+                    }),
+                }
+            }
+        });
+
+        Ok(budget - read)
+    }
+
+    // Decodes the next collection or builds the tree of the current one.
+    // Returns the budget left.
+    fn decode(&mut self, budget: usize) -> Result<usize, EngineError> {
+        let Some(decoding) = self.decoding.as_mut() else {
+            return Ok(budget);
+        };
+
+        if let Some(building) = decoding.building.as_mut() {
+            let per_vector = building.len.div_ceil(building.builder.len().max(1)).max(1);
+            let vectors = budget.div_ceil(per_vector);
+
+            if building.builder.step(vectors) {
+                if let Some(Building {
+                    name,
+                    mut index,
+                    builder,
+                    ..
+                }) = decoding.building.take()
+                {
+                    index.tree = Arc::new(builder.finish());
+                    decoding.indexes.insert(name, index);
+                }
+            }
+
+            return Ok(budget.saturating_sub(vectors.saturating_mul(per_vector)));
+        }
+
+        if decoding.remaining == 0 {
+            let indexes = std::mem::take(&mut decoding.indexes);
+            self.collections = Some(Collections { indexes });
+            self.decoding = None;
+            self.buffer = vec![];
+            return Ok(budget);
+        }
+
+        let start = decoding.position;
+        let mut reader = &self.buffer[start..];
+        let (decoded, mut builders) =
+            super::defer_builds(|| bincode::deserialize_from::<_, (String, Index)>(&mut reader));
+        let (name, index) = decoded?;
+        let len = self.buffer.len() - start - reader.len();
+
+        decoding.position += len;
+        decoding.remaining -= 1;
+
+        match builders.pop() {
+            Some(builder) => {
+                decoding.building = Some(Building {
+                    name,
+                    index,
+                    builder: TreeBuilder::KdTree(builder),
+                    start,
+                    len,
+                });
+                Ok(budget)
+            }
+            None => {
+                decoding.indexes.insert(name, index);
+                Ok(budget.saturating_sub(len))
+            }
+        }
+    }
+}
+
+impl Decoding {
+    // The decompressed bytes decoded so far.
+    fn decoded(&self) -> usize {
+        match &self.building {
+            Some(building) => {
+                let vectors = building.builder.len().max(1);
+                building.start + building.len * building.builder.done() / vectors
+            }
+            None => self.position,
+        }
+    }
+}
+
+fn vector_count(resource: &EmbeddedResource) -> usize {
+    resource.chunks.as_ref().map_or(1, |chunks| chunks.len())
+}
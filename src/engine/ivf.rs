@@ -0,0 +1,356 @@
+use crate::engine::types::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const DEFAULT_NLIST: usize = 32;
+const DEFAULT_NPROBE: usize = 4;
+
+// The index trains once it holds this many vectors per cluster, before that
+// every search scans the pending vectors.
+const TRAINING_FACTOR: usize = 8;
+
+const KMEANS_ITERATIONS: usize = 20;
+
+type Entry = (u64, Vec<f32>);
+
+/// An inverted file index with a k-means coarse quantizer.
+///
+/// Vectors are stored with trailing zeros trimmed, shorter vectors are treated
+/// as zero padded when comparing.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ivf {
+    pub nlist: usize,
+    pub nprobe: usize,
+    pub centroids: Vec<Vec<f32>>,
+    pub lists: Vec<Vec<Entry>>,
+    // Vectors added before the first training.
+    pub pending: Vec<Entry>,
+    // Tree item -> position in `lists`.
+    pub assignments: HashMap<u64, usize>,
+}
+
+impl Ivf {
+    pub fn new(options: &IndexOptions) -> Self {
+        Ivf {
+            nlist: options.nlist.unwrap_or(DEFAULT_NLIST),
+            nprobe: options.nprobe.unwrap_or(DEFAULT_NPROBE),
+            centroids: vec![],
+            lists: vec![],
+            pending: vec![],
+            assignments: HashMap::new(),
+        }
+    }
+
+    /// Builds an index over `entries`, training once over all of them instead
+    /// of as soon as the first `nlist * TRAINING_FACTOR` have been added.
+    pub fn build(options: &IndexOptions, entries: Vec<Entry>) -> Self {
+        IvfBuilder::new(options, entries).finish()
+    }
+
+    pub fn is_trained(&self) -> bool {
+        !self.centroids.is_empty()
+    }
+
+    pub fn size(&self) -> usize {
+        self.pending.len() + self.assignments.len()
+    }
+
+    /// Adds a vector to its closest cluster without moving the centroids.
+    pub fn add(&mut self, point: &[f32], item: u64) {
+        let vector = super::trim(point).to_vec();
+
+        if !self.is_trained() {
+            self.pending.push((item, vector));
+
+            if self.pending.len() >= self.nlist * TRAINING_FACTOR {
+                self.train();
+            }
+
+            return;
+        }
+
+        let list = closest(&self.centroids, &vector);
+        self.lists[list].push((item, vector));
+        self.assignments.insert(item, list);
+    }
+
+    pub fn remove(&mut self, item: u64) {
+        match self.assignments.remove(&item) {
+            Some(list) => self.lists[list].retain(|(other, _)| *other != item),
+            None => self.pending.retain(|(other, _)| *other != item),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &[f32])> + '_ {
+        self.lists
+            .iter()
+            .flatten()
+            .chain(self.pending.iter())
+            .map(|(item, vector)| (*item, vector.as_slice()))
+    }
+
+    /// Runs k-means over every stored vector and rebuilds the lists.
+    pub fn train(&mut self) {
+        let mut entries = std::mem::take(&mut self.pending);
+        entries.extend(self.lists.drain(..).flatten());
+        self.assignments.clear();
+
+        if entries.is_empty() {
+            self.centroids = vec![];
+            return;
+        }
+
+        let mut training = Training::new(entries, self.nlist);
+        training.step(usize::MAX);
+        training.finish(self);
+    }
+
+    /// Retrains the centroids once enough vectors are stored, so clusters
+    /// drifted by additions and removals are balanced again.
+    pub fn optimize(&mut self) {
+        if self.is_trained() || self.pending.len() >= self.nlist * TRAINING_FACTOR {
+            self.train();
+        }
+    }
+
+    /// Scans the `nprobe` clusters closest to `query` and the pending vectors.
+    pub fn nearest(&self, query: &[f32], n: usize, metric: Metric) -> Vec<(f32, u64)> {
+        let query = super::trim(query);
+
+        let mut probes = self
+            .centroids
+            .iter()
+            .enumerate()
+            .map(|(list, centroid)| (super::distance(Metric::Euclidean, query, centroid), list))
+            .collect::<Vec<(f32, usize)>>();
+        probes.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let entries = probes
+            .iter()
+            .take(self.nprobe)
+            .flat_map(|(_, list)| self.lists[*list].iter())
+            .chain(self.pending.iter());
+
+        let mut candidates = super::parallel::map_iter(entries, |(item, vector)| {
+            (super::distance(metric, query, vector), *item)
+        });
+        super::profile::count_distances(probes.len() + candidates.len());
+        super::profile::count_buckets(probes.len().min(self.nprobe));
+
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        candidates.truncate(n);
+        candidates
+    }
+}
+
+fn closest(centroids: &[Vec<f32>], vector: &[f32]) -> usize {
+    centroids
+        .iter()
+        .enumerate()
+        .map(|(list, centroid)| (super::distance(Metric::Euclidean, vector, centroid), list))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map_or(0, |(_, list)| list)
+}
+
+/// Builds an `Ivf` like `Ivf::build` a bounded number of vectors per `step`.
+/// Every k-means pass over the vectors is spread over the steps.
+#[derive(Debug)]
+pub struct IvfBuilder {
+    ivf: Ivf,
+    training: Option<Training>,
+}
+
+impl IvfBuilder {
+    pub fn new(options: &IndexOptions, entries: Vec<Entry>) -> Self {
+        let mut ivf = Ivf::new(options);
+
+        ivf.pending = entries
+            .into_iter()
+            .map(|(item, mut vector)| {
+                vector.truncate(super::trim(&vector).len());
+                (item, vector)
+            })
+            .collect();
+
+        let training = (ivf.pending.len() >= ivf.nlist * TRAINING_FACTOR)
+            .then(|| Training::new(std::mem::take(&mut ivf.pending), ivf.nlist));
+
+        IvfBuilder { ivf, training }
+    }
+
+    /// The number of vectors the index is built over.
+    pub fn len(&self) -> usize {
+        let training = self.training.as_ref();
+        self.ivf.pending.len() + training.map_or(0, |training| training.entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How far the build has come, from 0 to `len`.
+    pub fn done(&self) -> usize {
+        match &self.training {
+            Some(training) => training.done(),
+            None => self.len(),
+        }
+    }
+
+    /// Runs the training over up to `max_vectors` more vectors. Returns
+    /// whether the index is built.
+    pub fn step(&mut self, max_vectors: usize) -> bool {
+        let Some(training) = self.training.as_mut() else {
+            return true;
+        };
+
+        if !training.step(max_vectors) {
+            return false;
+        }
+
+        if let Some(training) = self.training.take() {
+            training.finish(&mut self.ivf);
+        }
+
+        true
+    }
+
+    /// Returns the index, running the remaining steps at once.
+    pub fn finish(mut self) -> Ivf {
+        self.step(usize::MAX);
+        self.ivf
+    }
+}
+
+// Lloyd's algorithm seeded with evenly spaced entries, so training is
+// deterministic for the same insertion order. The passes over the entries can
+// be run a chunk at a time, the result does not depend on the chunks.
+#[derive(Debug)]
+struct Training {
+    entries: Vec<Entry>,
+    centroids: Vec<Vec<f32>>,
+    // The closest centroid of each entry as of the last pass.
+    lists: Vec<usize>,
+    // The completed k-means passes.
+    pass: usize,
+    // The entries visited by the current pass.
+    position: usize,
+    changed: bool,
+    sums: Vec<Vec<f32>>,
+    counts: Vec<usize>,
+    // Whether the current pass assigns the entries to the final centroids.
+    assigning: bool,
+    finished: bool,
+}
+
+impl Training {
+    fn new(entries: Vec<Entry>, nlist: usize) -> Self {
+        let k = nlist.min(entries.len());
+        let dimension = entries.iter().map(|(_, vector)| vector.len()).max().unwrap_or(0);
+
+        let centroids = (0..k)
+            .map(|i| {
+                let mut centroid = entries[i * entries.len() / k].1.to_owned();
+                centroid.resize(dimension, 0.0);
+                centroid
+            })
+            .collect::<Vec<Vec<f32>>>();
+
+        Training {
+            lists: vec![usize::MAX; entries.len()],
+            entries,
+            centroids,
+            pass: 0,
+            position: 0,
+            changed: false,
+            sums: vec![vec![0.0f32; dimension]; k],
+            counts: vec![0usize; k],
+            assigning: false,
+            finished: false,
+        }
+    }
+
+    // The passes left after converging are skipped, so progress jumps ahead.
+    fn done(&self) -> usize {
+        match self.finished {
+            true => self.entries.len(),
+            false => {
+                let passes = self.pass.min(KMEANS_ITERATIONS);
+                (passes * self.entries.len() + self.position) / (KMEANS_ITERATIONS + 1)
+            }
+        }
+    }
+
+    // Visits up to `max_vectors` more entries, returns whether training is
+    // finished.
+    fn step(&mut self, max_vectors: usize) -> bool {
+        let mut budget = max_vectors.max(1);
+
+        while !self.finished && budget > 0 {
+            let end = self.position + budget.min(self.entries.len() - self.position);
+            let lists = super::parallel::map(&self.entries[self.position..end], |(_, vector)| {
+                closest(&self.centroids, vector)
+            });
+            budget -= end - self.position;
+
+            for (position, list) in (self.position..end).zip(lists) {
+                if self.lists[position] != list {
+                    self.lists[position] = list;
+                    self.changed = true;
+                }
+
+                if !self.assigning {
+                    self.counts[list] += 1;
+
+                    for (sum, value) in self.sums[list].iter_mut().zip(&self.entries[position].1) {
+                        *sum += value;
+                    }
+                }
+            }
+
+            self.position = end;
+
+            if self.position == self.entries.len() {
+                self.end_pass();
+            }
+        }
+
+        self.finished
+    }
+
+    fn end_pass(&mut self) {
+        self.position = 0;
+
+        // An unchanged pass leaves every entry with its closest centroid.
+        if self.assigning || !self.changed {
+            self.finished = true;
+            return;
+        }
+
+        // Empty clusters keep their previous centroid.
+        for list in 0..self.centroids.len() {
+            if self.counts[list] > 0 {
+                self.centroids[list] = self.sums[list]
+                    .iter()
+                    .map(|sum| sum / self.counts[list] as f32)
+                    .collect();
+            }
+
+            self.sums[list].fill(0.0);
+            self.counts[list] = 0;
+        }
+
+        self.changed = false;
+        self.pass += 1;
+        self.assigning = self.pass == KMEANS_ITERATIONS;
+    }
+
+    fn finish(self, ivf: &mut Ivf) {
+        ivf.lists = vec![vec![]; self.centroids.len()];
+        ivf.centroids = self.centroids;
+
+        for ((item, vector), list) in self.entries.into_iter().zip(self.lists) {
+            ivf.assignments.insert(item, list);
+            ivf.lists[list].push((item, vector));
+        }
+    }
+}
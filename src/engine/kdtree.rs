@@ -0,0 +1,535 @@
+use crate::engine::{profile::Counted, types::*};
+use kiddo::float::distance::{Manhattan, SquaredEuclidean};
+use kiddo::traits::{DistanceMetric, Index as NodeIndex};
+use serde::de::Error as _;
+use serde::ser::{SerializeSeq, SerializeStruct};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::RefCell;
+
+/// The bucket sizes accepted by `IndexOptions::bucket_size`.
+pub const BUCKET_SIZES: [usize; 3] = [16, 32, 64];
+
+/// A kiddo kd-tree with the bucket size and node index width picked from the
+/// index options.
+///
+/// Trees start with `u16` node indexes, which keep the nodes small, and use
+/// `u32` ones when the expected capacity does not fit. A `u16` tree outgrowing
+/// its indexes is rebuilt with `u32` ones on the next `add`.
+#[derive(Debug, Clone)]
+pub enum AnyKdTree {
+    B16(KdTree<16, u16>),
+    B32(KdTree<32, u16>),
+    B64(KdTree<64, u16>),
+    WideB16(KdTree<16, u32>),
+    WideB32(KdTree<32, u32>),
+    WideB64(KdTree<64, u32>),
+}
+
+macro_rules! dispatch {
+    ($tree:expr, $inner:ident => $body:expr) => {
+        match $tree {
+            AnyKdTree::B16($inner) => $body,
+            AnyKdTree::B32($inner) => $body,
+            AnyKdTree::B64($inner) => $body,
+            AnyKdTree::WideB16($inner) => $body,
+            AnyKdTree::WideB32($inner) => $body,
+            AnyKdTree::WideB64($inner) => $body,
+        }
+    };
+}
+
+/// The most vectors a kd-tree with `bucket_size` can hold.
+///
+/// kiddo addresses `capacity_with_bucket_size` slots, but a split leaves both
+/// leaves half full, so only half of them are guaranteed to be usable. With the
+/// default bucket size this is 524,288 vectors for `u16` node indexes.
+pub fn kdtree_capacity(bucket_size: usize, wide: bool) -> usize {
+    let slots = match wide {
+        false => <u16 as NodeIndex>::capacity_with_bucket_size(bucket_size),
+        true => <u32 as NodeIndex>::capacity_with_bucket_size(bucket_size),
+    };
+
+    slots / 2
+}
+
+impl AnyKdTree {
+    /// Creates an empty tree, using `u32` node indexes when `capacity` vectors
+    /// do not fit `u16` ones. Storage is pre-sized for `reserve` vectors.
+    pub fn new(bucket_size: usize, capacity: usize, reserve: usize) -> Self {
+        let wide = capacity > kdtree_capacity(bucket_size, false);
+        let reserve = reserve.min(kdtree_capacity(bucket_size, wide));
+
+        match (bucket_size, wide) {
+            (16, false) => AnyKdTree::B16(KdTree::with_capacity(reserve)),
+            (64, false) => AnyKdTree::B64(KdTree::with_capacity(reserve)),
+            (_, false) => AnyKdTree::B32(KdTree::with_capacity(reserve)),
+            (16, true) => AnyKdTree::WideB16(KdTree::with_capacity(reserve)),
+            (64, true) => AnyKdTree::WideB64(KdTree::with_capacity(reserve)),
+            (_, true) => AnyKdTree::WideB32(KdTree::with_capacity(reserve)),
+        }
+    }
+
+    /// Builds a tree holding `entries`, inserted so that every leaf is split
+    /// at the median of the points below it.
+    pub fn build(bucket_size: usize, capacity: usize, entries: Vec<(u64, Embedding)>) -> Self {
+        KdTreeBuilder::new(bucket_size, capacity, entries).finish()
+    }
+
+    pub fn bucket_size(&self) -> usize {
+        match self {
+            AnyKdTree::B16(_) | AnyKdTree::WideB16(_) => 16,
+            AnyKdTree::B32(_) | AnyKdTree::WideB32(_) => 32,
+            AnyKdTree::B64(_) | AnyKdTree::WideB64(_) => 64,
+        }
+    }
+
+    /// Whether the tree uses `u32` node indexes.
+    pub fn is_wide(&self) -> bool {
+        matches!(
+            self,
+            AnyKdTree::WideB16(_) | AnyKdTree::WideB32(_) | AnyKdTree::WideB64(_)
+        )
+    }
+
+    /// The most vectors the tree can hold, widening its node indexes if
+    /// needed.
+    pub fn max_capacity(&self) -> usize {
+        kdtree_capacity(self.bucket_size(), true)
+    }
+
+    /// Rebuilds a tree with `u16` node indexes using `u32` ones.
+    pub fn widen(&mut self) {
+        if self.is_wide() {
+            return;
+        }
+
+        let entries = self
+            .iter()
+            .map(|(item, point)| (item, super::trim(&point).to_vec()))
+            .collect::<Vec<(u64, Embedding)>>();
+        let bucket_size = self.bucket_size();
+
+        *self = AnyKdTree::build(bucket_size, kdtree_capacity(bucket_size, true), entries);
+    }
+
+    /// Walks the nodes of the tree. This visits every node once in each
+    /// direction without reading the points.
+    pub fn shape(&self) -> KdTreeShape {
+        // Relies on kiddo 5.3.3's `within_unsorted_recurse`, which visits the
+        // closer child of a stem before the farther one and calls
+        // `accumulate` only when stepping into the farther child.
+        //
+        // A query below every split value always visits the left child first,
+        // one above them the right child, the other child being the farther
+        // one whose distance `ShapeProbe` counts.
+        let left_first = self.probe(f32::NEG_INFINITY);
+        let right_first = self.probe(f32::INFINITY);
+
+        // The stems come in order in the first walk and in reverse in the
+        // second, so together they count the edges above each stem.
+        let depth = left_first
+            .stems
+            .iter()
+            .zip(right_first.stems.iter().rev())
+            .map(|(right_edges, left_edges)| (right_edges + left_edges) as usize + 1)
+            .max()
+            .unwrap_or(0);
+
+        KdTreeShape {
+            leaves: left_first.leaf_sizes.len(),
+            stems: left_first.stems.len(),
+            depth,
+            leaf_sizes: left_first.leaf_sizes,
+        }
+    }
+
+    // Visits every node with a query of `value` in every dimension and
+    // returns what `ShapeProbe` recorded.
+    fn probe(&self, value: f32) -> Probe {
+        PROBE.with(|probe| probe.take());
+
+        let query = [value; EMBEDDING_DIMENSION];
+        dispatch!(self, tree => tree.within_unsorted::<ShapeProbe>(&query, f32::MAX));
+
+        let mut probe = PROBE.with(|probe| probe.take());
+        probe.leaf_sizes.push(probe.points);
+        probe
+    }
+
+    pub fn add(&mut self, point: &[f32; EMBEDDING_DIMENSION], item: u64) {
+        if !self.is_wide() && self.size() as usize >= kdtree_capacity(self.bucket_size(), false) {
+            self.widen();
+        }
+
+        dispatch!(self, tree => tree.add(point, item))
+    }
+
+    pub fn remove(&mut self, point: &[f32; EMBEDDING_DIMENSION], item: u64) {
+        dispatch!(self, tree => {
+            tree.remove(point, item);
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        dispatch!(self, tree => tree.size())
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (u64, [f32; EMBEDDING_DIMENSION])> + '_> {
+        dispatch!(self, tree => Box::new(tree.iter()))
+    }
+
+    pub fn nearest(
+        &self,
+        query: &[f32; EMBEDDING_DIMENSION],
+        n: usize,
+        metric: Metric,
+    ) -> Vec<(f32, u64)> {
+        let coordinates = super::profile::coordinates();
+
+        let neighbors = dispatch!(self, tree => match (metric, super::profile::is_measuring()) {
+            (Metric::Euclidean | Metric::Cosine, false) => {
+                tree.nearest_n::<SquaredEuclidean>(query, n)
+            }
+            (Metric::Euclidean | Metric::Cosine, true) => {
+                tree.nearest_n::<Counted<SquaredEuclidean>>(query, n)
+            }
+            (Metric::Manhattan, false) => tree.nearest_n::<Manhattan>(query, n),
+            (Metric::Manhattan, true) => tree.nearest_n::<Counted<Manhattan>>(query, n),
+        });
+
+        // The tree compares one coordinate for each split node it visits.
+        super::profile::count_nodes((super::profile::coordinates() - coordinates) as usize);
+
+        neighbors
+            .into_iter()
+            .map(|neighbor| match metric {
+                // For unit vectors |a - b|^2 = 2 - 2cos(a, b).
+                Metric::Cosine => (neighbor.distance / 2.0, neighbor.item),
+                _ => (neighbor.distance, neighbor.item),
+            })
+            .collect()
+    }
+}
+
+/// Builds an `AnyKdTree` like `AnyKdTree::build` a bounded number of vectors
+/// per `step`. The insertion order is computed by the first step.
+#[derive(Debug)]
+pub struct KdTreeBuilder {
+    tree: AnyKdTree,
+    entries: Vec<(u64, Embedding)>,
+    order: Option<std::vec::IntoIter<usize>>,
+    done: usize,
+}
+
+impl KdTreeBuilder {
+    pub fn new(bucket_size: usize, capacity: usize, entries: Vec<(u64, Embedding)>) -> Self {
+        KdTreeBuilder {
+            tree: AnyKdTree::new(bucket_size, capacity.max(entries.len()), entries.len()),
+            entries,
+            order: None,
+            done: 0,
+        }
+    }
+
+    /// The number of vectors the tree is built from.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of vectors inserted so far.
+    pub fn done(&self) -> usize {
+        self.done
+    }
+
+    /// Inserts up to `max_vectors` more vectors. Returns whether all of them
+    /// are in the tree.
+    pub fn step(&mut self, max_vectors: usize) -> bool {
+        let order = self.order.get_or_insert_with(|| {
+            balanced_order(&self.entries, self.tree.bucket_size()).into_iter()
+        });
+
+        for position in order.by_ref().take(max_vectors.max(1)) {
+            let (item, vector) = &self.entries[position];
+            self.tree.add(&super::pad(vector), *item);
+            self.done += 1;
+        }
+
+        self.done == self.entries.len()
+    }
+
+    /// Returns the tree, running the remaining steps at once.
+    pub fn finish(mut self) -> AnyKdTree {
+        self.step(usize::MAX);
+        self.tree
+    }
+}
+
+// kiddo stores the points of a leaf inline and deserializes every leaf on the
+// stack, which overflows the 2MB stack of native threads and wasm's 1MB one.
+// Trees are serialized as their entries instead and rebuilt when loaded.
+#[derive(Deserialize)]
+struct StoredKdTree {
+    bucket_size: usize,
+    wide: bool,
+    entries: Vec<(u64, Embedding)>,
+}
+
+impl Serialize for AnyKdTree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("StoredKdTree", 3)?;
+        state.serialize_field("bucket_size", &self.bucket_size())?;
+        state.serialize_field("wide", &self.is_wide())?;
+        state.serialize_field("entries", &Entries(self))?;
+        state.end()
+    }
+}
+
+// The entries of a tree with trailing zeros trimmed, serialized like
+// `Vec<(u64, Embedding)>` without collecting them.
+struct Entries<'a>(&'a AnyKdTree);
+
+impl Serialize for Entries<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.size() as usize))?;
+
+        for (item, point) in self.0.iter() {
+            seq.serialize_element(&(item, super::trim(&point)))?;
+        }
+
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for AnyKdTree {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let stored = StoredKdTree::deserialize(deserializer)?;
+
+        if !BUCKET_SIZES.contains(&stored.bucket_size) {
+            return Err(D::Error::custom(format!(
+                "Invalid kd-tree bucket size {}",
+                stored.bucket_size
+            )));
+        }
+
+        let capacity = match stored.wide {
+            true => kdtree_capacity(stored.bucket_size, true),
+            false => 0,
+        };
+
+        if stored.entries.len() > kdtree_capacity(stored.bucket_size, stored.wide) {
+            return Err(D::Error::custom("The kd-tree holds more entries than it can"));
+        }
+
+        let builder = KdTreeBuilder::new(stored.bucket_size, capacity, stored.entries);
+
+        DEFERRED.with(|deferred| match deferred.borrow_mut().as_mut() {
+            Some(builders) => {
+                let tree = AnyKdTree::new(stored.bucket_size, capacity.max(builder.len()), 0);
+                builders.push(builder);
+                Ok(tree)
+            }
+            None => Ok(builder.finish()),
+        })
+    }
+}
+
+thread_local! {
+    // The builders of the trees deserialized by `defer_builds`.
+    static DEFERRED: RefCell<Option<Vec<KdTreeBuilder>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with kd-trees deserialized empty, returning the builders of their
+/// content in deserialization order so the caller can build them in steps.
+pub(crate) fn defer_builds<T>(f: impl FnOnce() -> T) -> (T, Vec<KdTreeBuilder>) {
+    DEFERRED.with(|deferred| deferred.replace(Some(vec![])));
+    let result = f();
+    let builders = DEFERRED.with(|deferred| deferred.take()).unwrap_or_default();
+
+    (result, builders)
+}
+
+/// The node layout of a kd-tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KdTreeShape {
+    pub leaves: usize,
+    pub stems: usize,
+    /// The number of stems on the longest path from the root to a leaf.
+    pub depth: usize,
+    /// The number of vectors in each leaf.
+    pub leaf_sizes: Vec<usize>,
+}
+
+// kiddo keeps the nodes of a tree private, so the shape is read from the calls
+// a search makes to its distance metric. Searches visit the nearer child of a
+// stem, then add the distance to the split to that of the stem and visit the
+// farther child if it is within range. Every point of a leaf is compared, and
+// in order of the visits the leaves alternate with the stems.
+#[derive(Default)]
+struct Probe {
+    // The distance of each stem, the number of farther children above it.
+    stems: Vec<f32>,
+    leaf_sizes: Vec<usize>,
+    // The points compared since the last stem.
+    points: usize,
+}
+
+thread_local! {
+    static PROBE: RefCell<Probe> = RefCell::new(Probe::default());
+}
+
+// A metric keeping every point out of range and every node in range, so a
+// `within_unsorted` search visits the whole tree and returns nothing. This
+// depends on kiddo 5.3.3 calling `dist` once per point of a visited leaf,
+// `dist1` once per stem and `accumulate` just before it visits a farther child.
+struct ShapeProbe;
+
+impl DistanceMetric<f32, EMBEDDING_DIMENSION> for ShapeProbe {
+    fn dist(_: &[f32; EMBEDDING_DIMENSION], _: &[f32; EMBEDDING_DIMENSION]) -> f32 {
+        PROBE.with(|probe| probe.borrow_mut().points += 1);
+        f32::INFINITY
+    }
+
+    fn dist1(_: f32, _: f32) -> f32 {
+        1.0
+    }
+
+    fn accumulate(rd: f32, delta: f32) -> f32 {
+        PROBE.with(|probe| {
+            let mut probe = probe.borrow_mut();
+            let points = std::mem::take(&mut probe.points);
+            probe.leaf_sizes.push(points);
+            probe.stems.push(rd);
+        });
+
+        rd + delta
+    }
+}
+
+// Orders `positions` so that inserting them one by one splits every leaf at
+// the median of the points that end up below it.
+//
+// kiddo splits a full leaf at the point in the middle of its `bucket_size`
+// points, on a dimension cycling with the depth. This mirrors
+// `KdTree::split` of kiddo 5.3.3, which selects the pivot at `B / 2` and
+// moves it down past points sharing its value. Each node therefore picks the
+// points filling its leaf next to the ones inherited from its parent's leaf,
+// so that half of them lie below the median and the median itself is the
+// middle one. The remaining points follow, grouped by the side they end up on.
+fn balanced_order(entries: &[(u64, Embedding)], bucket_size: usize) -> Vec<usize> {
+    let mut positions = (0..entries.len())
+        .map(|position| (position, false))
+        .collect::<Vec<Position>>();
+
+    place(entries, &mut positions, bucket_size, 0)
+}
+
+// An entry position and whether it was already placed in the leaf of an
+// ancestor.
+type Position = (usize, bool);
+
+// Subtrees at least this large are ordered in parallel with the `threads`
+// feature.
+const PARALLEL_SIZE: usize = 4096;
+
+// Returns the unplaced positions in insertion order. The split value below is
+// the one kiddo 5.3.3 picks when the leaf overflows, points equal to it going
+// to the upper child as in its `add`.
+fn place(
+    entries: &[(u64, Embedding)],
+    positions: &mut [Position],
+    bucket_size: usize,
+    depth: usize,
+) -> Vec<usize> {
+    let unplaced = |positions: &[Position]| {
+        positions
+            .iter()
+            .filter(|(_, placed)| !placed)
+            .map(|(position, _)| *position)
+            .collect::<Vec<usize>>()
+    };
+
+    if positions.len() <= bucket_size {
+        return unplaced(positions);
+    }
+
+    let dimension = depth % EMBEDDING_DIMENSION;
+    let coordinate = |position: usize| entries[position].1.get(dimension).copied().unwrap_or(0.0);
+
+    let middle = positions.len() / 2;
+    positions.select_nth_unstable_by(middle, |a, b| coordinate(a.0).total_cmp(&coordinate(b.0)));
+
+    let half = bucket_size / 2;
+    let inherited_lower = positions[..middle]
+        .iter()
+        .filter(|(_, placed)| *placed)
+        .count();
+    let inherited_upper = positions[middle..]
+        .iter()
+        .filter(|(_, placed)| *placed)
+        .count();
+
+    let free = bucket_size.saturating_sub(inherited_lower + inherited_upper);
+    let free_lower = half.saturating_sub(inherited_lower).min(free);
+
+    // The upper half starts with the median, so it is picked first.
+    let mut order = Vec::with_capacity(positions.len());
+    let (lower, upper) = positions.split_at_mut(middle);
+
+    for (position, placed) in upper
+        .iter_mut()
+        .filter(|(_, placed)| !placed)
+        .take(free - free_lower)
+        .chain(
+            lower
+                .iter_mut()
+                .filter(|(_, placed)| !placed)
+                .take(free_lower),
+        )
+    {
+        *placed = true;
+        order.push(*position);
+    }
+
+    // The split value kiddo will choose from the points of the full leaf.
+    let mut leaf = positions
+        .iter()
+        .filter(|(_, placed)| *placed)
+        .map(|(position, _)| coordinate(*position))
+        .collect::<Vec<f32>>();
+    leaf.select_nth_unstable_by(half, f32::total_cmp);
+    let split = leaf[half];
+
+    let (mut lower, upper): (Vec<Position>, Vec<Position>) = positions
+        .iter()
+        .partition(|(position, _)| coordinate(*position) < split);
+
+    if lower.is_empty() || upper.is_empty() {
+        order.extend(unplaced(positions));
+        return order;
+    }
+
+    let middle = lower.len();
+    lower.extend(upper);
+    positions.copy_from_slice(&lower);
+
+    let (lower, upper) = positions.split_at_mut(middle);
+
+    let (lower, upper) = match lower.len().min(upper.len()) >= PARALLEL_SIZE {
+        true => super::parallel::join(
+            || place(entries, lower, bucket_size, depth + 1),
+            || place(entries, upper, bucket_size, depth + 1),
+        ),
+        false => (
+            place(entries, lower, bucket_size, depth + 1),
+            place(entries, upper, bucket_size, depth + 1),
+        ),
+    };
+
+    order.extend(lower);
+    order.extend(upper);
+    order
+}
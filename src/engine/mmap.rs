@@ -0,0 +1,292 @@
+use crate::engine::{candidate::Candidate, types::*};
+use memmap2::Mmap;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Snapshot layout, all numbers little-endian:
+//
+//   magic    b"LVDBMMAP"
+//   version  u32
+//   dim      u32
+//   count    u64
+//   vectors  count * dim * f32, row major
+//   expires  count * f64, milliseconds since the Unix epoch, infinite for
+//            entries that never expire (from version 2)
+//   offsets  (count + 1) * u64, byte offsets of each id inside `ids`
+//   ids      utf-8 bytes of all ids, concatenated
+//
+// The header is 24 bytes, so the vectors start 4-byte aligned.
+const MAGIC: &[u8; 8] = b"LVDBMMAP";
+const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 24;
+
+/// A read-only index backed by a memory-mapped snapshot file.
+///
+/// Opening validates the header and the id offsets, vectors are read straight
+/// from the mapping on each search, so large snapshots open quickly and the
+/// pages are shared between processes mapping the same file. Entries keep
+/// their expiry, searches skip the ones that expired.
+pub struct MmapIndex {
+    mmap: Mmap,
+    dimension: usize,
+    count: usize,
+    // Version 1 snapshots have no expiry section.
+    expiring: bool,
+}
+
+impl MmapIndex {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MmapIndex, EngineError> {
+        let file = File::open(path.as_ref())
+            .map_err(|err| EngineError::new(format!("Failed to open snapshot: {}", err)))?;
+
+        // SAFETY: the snapshot is treated as immutable, callers must not
+        // truncate or rewrite the file while it is mapped.
+        let mmap = unsafe { Mmap::map(&file) }
+            .map_err(|err| EngineError::new(format!("Failed to map snapshot: {}", err)))?;
+
+        if mmap.len() < HEADER_SIZE || &mmap[..8] != MAGIC {
+            return Err(EngineError::new("Invalid snapshot file".to_string()));
+        }
+
+        let version = read_u32(&mmap, 8);
+
+        if version != 1 && version != VERSION {
+            return Err(EngineError::new(format!(
+                "Unsupported snapshot version {}",
+                version
+            )));
+        }
+
+        let dimension = read_u32(&mmap, 12) as usize;
+        let count = read_u64(&mmap, 16) as usize;
+
+        let index = MmapIndex {
+            mmap,
+            dimension,
+            count,
+            expiring: version >= 2,
+        };
+        let expires_bytes = match index.expiring {
+            true => 8,
+            false => 0,
+        };
+
+        // The sizes come from the file, so a corrupt header must not overflow.
+        let ids_start = count
+            .checked_mul(dimension)
+            .and_then(|values| values.checked_mul(4))
+            .and_then(|bytes| bytes.checked_add(HEADER_SIZE))
+            .and_then(|expires_start| expires_start.checked_add(count.checked_mul(expires_bytes)?))
+            .and_then(|offsets_start| {
+                count
+                    .checked_add(1)?
+                    .checked_mul(8)?
+                    .checked_add(offsets_start)
+            })
+            .ok_or_else(|| EngineError::new("Invalid snapshot file".to_string()))?;
+
+        if index.mmap.len() < ids_start {
+            return Err(EngineError::new("Truncated snapshot file".to_string()));
+        }
+
+        // Ids are sliced by these offsets on every search, so they must run
+        // from 0 to the end of the file without going back or splitting a
+        // character.
+        let ids = std::str::from_utf8(&index.mmap[ids_start..])
+            .map_err(|_| EngineError::new("Invalid snapshot ids".to_string()))?;
+        let mut previous = 0;
+
+        for position in 0..=count {
+            let offset = index.id_offset(position);
+
+            if offset < previous
+                || (position == 0 && offset != 0)
+                || (position == count && offset != ids.len())
+                || !ids.is_char_boundary(offset)
+            {
+                return Err(EngineError::new("Invalid snapshot ids".to_string()));
+            }
+
+            previous = offset;
+        }
+
+        Ok(index)
+    }
+
+    pub fn size(&self) -> usize {
+        self.count
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    pub fn id(&self, position: usize) -> &str {
+        let ids_start = self.offsets_start() + (self.count + 1) * 8;
+        let start = ids_start + self.id_offset(position);
+        let end = ids_start + self.id_offset(position + 1);
+
+        std::str::from_utf8(&self.mmap[start..end]).unwrap_or_default()
+    }
+
+    pub fn vector(&self, position: usize) -> Vec<f32> {
+        self.row(position)
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            .collect()
+    }
+
+    /// When the entry at `position` expires, in milliseconds since the Unix
+    /// epoch.
+    pub fn expires_at(&self, position: usize) -> Option<f64> {
+        if !self.expiring {
+            return None;
+        }
+
+        let expires_at = f64::from_le_bytes(
+            self.mmap[self.expires_start() + position * 8..][..8]
+                .try_into()
+                .unwrap(),
+        );
+
+        expires_at.is_finite().then_some(expires_at)
+    }
+
+    /// Exact k nearest neighbour search by squared euclidean distance. Entries
+    /// that expired are skipped.
+    pub fn search(&self, query: &[f32], k: usize) -> SearchResult {
+        let mut query = query.to_owned();
+        query.resize(self.dimension, 0.0);
+
+        let now = super::now();
+        let mut heap: BinaryHeap<Candidate> = BinaryHeap::with_capacity(k + 1);
+
+        for position in 0..self.count {
+            if self.expires_at(position).is_some_and(|expires_at| expires_at <= now) {
+                continue;
+            }
+
+            let distance = self
+                .row(position)
+                .chunks_exact(4)
+                .zip(&query)
+                .map(|(bytes, value)| {
+                    let diff = f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) - value;
+                    diff * diff
+                })
+                .sum::<f32>();
+
+            if heap.len() < k {
+                heap.push(Candidate { distance, position });
+            } else if heap.peek().is_some_and(|worst| distance < worst.distance) {
+                heap.pop();
+                heap.push(Candidate { distance, position });
+            }
+        }
+
+        let neighbors = heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|candidate| Neighbor {
+                id: self.id(candidate.position).to_owned(),
+                distance: candidate.distance,
+                chunk: None,
+                content: None,
+            })
+            .collect();
+
+        SearchResult {
+            neighbors,
+            explain: None,
+        }
+    }
+
+    fn row(&self, position: usize) -> &[u8] {
+        let start = HEADER_SIZE + position * self.dimension * 4;
+        &self.mmap[start..start + self.dimension * 4]
+    }
+
+    fn expires_start(&self) -> usize {
+        HEADER_SIZE + self.count * self.dimension * 4
+    }
+
+    fn offsets_start(&self) -> usize {
+        match self.expiring {
+            true => self.expires_start() + self.count * 8,
+            false => self.expires_start(),
+        }
+    }
+
+    fn id_offset(&self, position: usize) -> usize {
+        read_u64(&self.mmap, self.offsets_start() + position * 8) as usize
+    }
+}
+
+/// Writes `index` as a snapshot that can be opened with `MmapIndex::open`.
+pub fn write_mmap<P: AsRef<Path>>(index: &Index, path: P) -> Result<(), EngineError> {
+    let to_error = |err: std::io::Error| EngineError::new(format!("Failed to write snapshot: {}", err));
+
+    if index.options.metric != Metric::Euclidean {
+        return Err(EngineError::new(
+            "Only euclidean indexes can be written as mmap snapshots".to_string(),
+        ));
+    }
+
+    if !index.chunks.is_empty() {
+        return Err(EngineError::new(
+            "Multi-vector documents can not be written as mmap snapshots".to_string(),
+        ));
+    }
+
+    // Entries that already expired are left out, the others keep their expiry
+    // for searches to skip them once it passes.
+    let now = super::now();
+    let mut records = super::records(index);
+    records.retain(|record| record.expires_at.is_none_or(|expires_at| expires_at > now));
+
+    let mut writer = BufWriter::new(File::create(path.as_ref()).map_err(to_error)?);
+
+    writer.write_all(MAGIC).map_err(to_error)?;
+    writer.write_all(&VERSION.to_le_bytes()).map_err(to_error)?;
+    writer
+        .write_all(&(index.dimension as u32).to_le_bytes())
+        .map_err(to_error)?;
+    writer
+        .write_all(&(records.len() as u64).to_le_bytes())
+        .map_err(to_error)?;
+
+    for record in &records {
+        for value in &record.vector {
+            writer.write_all(&value.to_le_bytes()).map_err(to_error)?;
+        }
+    }
+
+    for record in &records {
+        let expires_at = record.expires_at.unwrap_or(f64::INFINITY);
+        writer.write_all(&expires_at.to_le_bytes()).map_err(to_error)?;
+    }
+
+    let mut offset = 0u64;
+    writer.write_all(&offset.to_le_bytes()).map_err(to_error)?;
+
+    for record in &records {
+        offset += record.id.len() as u64;
+        writer.write_all(&offset.to_le_bytes()).map_err(to_error)?;
+    }
+
+    for record in &records {
+        writer.write_all(record.id.as_bytes()).map_err(to_error)?;
+    }
+
+    writer.flush().map_err(to_error)
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
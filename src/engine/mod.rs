@@ -1,7 +1,61 @@
+//! The native vector index.
+//!
+//! An [`Index`] is plain data, every operation is a free function taking the
+//! index as its first argument. Fallible operations return [`EngineError`].
+//! [`Collections`] groups several named indexes with their own options.
+
+mod candidate;
+mod collection;
+#[cfg(not(target_arch = "wasm32"))]
+mod disk;
 mod hash;
+#[allow(clippy::module_inception)]
 mod engine;
+mod embedding;
+mod evaluate;
+mod eviction;
+mod export;
+mod filter;
+mod format;
+mod frozen;
+mod ingest;
+mod ivf;
+mod kdtree;
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+mod mmap;
+mod parallel;
+mod profile;
+mod scan;
+#[cfg(all(feature = "sqlite", not(target_arch = "wasm32")))]
+mod sqlite;
+mod stats;
+mod storage;
+mod transaction;
+mod tree;
 mod types;
 
+pub use collection::*;
+#[cfg(not(target_arch = "wasm32"))]
+pub use disk::*;
 pub use hash::*;
 pub use engine::*;
+pub use embedding::*;
+pub use evaluate::*;
+pub use eviction::*;
+pub use export::*;
+pub use filter::*;
+pub use format::FORMAT_VERSION;
+pub use frozen::*;
+pub use ingest::*;
+pub use ivf::*;
+pub use kdtree::*;
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+pub use mmap::*;
+pub use scan::*;
+#[cfg(all(feature = "sqlite", not(target_arch = "wasm32")))]
+pub use sqlite::*;
+pub use stats::*;
+pub use storage::*;
+pub use transaction::*;
+pub use tree::*;
 pub use types::*;
\ No newline at end of file
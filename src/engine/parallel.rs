@@ -0,0 +1,65 @@
+//! Runs independent work on the rayon thread pool with the `threads` feature
+//! on native targets, and sequentially without it or on wasm, where rayon
+//! can't start its pool. Results come back in input order either way, so all
+//! builds return the same results.
+
+#[cfg(all(feature = "threads", not(target_arch = "wasm32")))]
+use rayon::prelude::*;
+
+// Items buffered from an iterator before mapping them in parallel. Tree
+// iterators yield padded vectors, this keeps the buffer at a few megabytes.
+#[cfg(all(feature = "threads", not(target_arch = "wasm32")))]
+const CHUNK_SIZE: usize = 256;
+
+/// Maps every item of `items`.
+pub(crate) fn map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync + Send,
+{
+    #[cfg(all(feature = "threads", not(target_arch = "wasm32")))]
+    return items.par_iter().map(f).collect();
+
+    #[cfg(not(all(feature = "threads", not(target_arch = "wasm32"))))]
+    return items.iter().map(f).collect();
+}
+
+/// Maps every item yielded by `iter`, a chunk at a time.
+pub(crate) fn map_iter<T, R, F>(iter: impl Iterator<Item = T>, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync + Send,
+{
+    #[cfg(all(feature = "threads", not(target_arch = "wasm32")))]
+    {
+        let mut iter = iter.peekable();
+        let mut result = vec![];
+
+        while iter.peek().is_some() {
+            let chunk = iter.by_ref().take(CHUNK_SIZE).collect::<Vec<T>>();
+            result.par_extend(chunk.into_par_iter().map(&f));
+        }
+
+        result
+    }
+
+    #[cfg(not(all(feature = "threads", not(target_arch = "wasm32"))))]
+    iter.map(f).collect()
+}
+
+/// Runs `a` and `b`, possibly at the same time.
+pub(crate) fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    #[cfg(all(feature = "threads", not(target_arch = "wasm32")))]
+    return rayon::join(a, b);
+
+    #[cfg(not(all(feature = "threads", not(target_arch = "wasm32"))))]
+    return (a(), b());
+}
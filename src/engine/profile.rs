@@ -0,0 +1,136 @@
+//! Measures the work done by searches, see `evaluate` and
+//! `SearchOptions::explain`.
+//!
+//! Searches run by `measure` count their distance computations and visited
+//! nodes in counters of the current thread. Distances computed on the rayon
+//! pool with the `threads` feature are counted by the calling thread instead.
+//! kd-tree searches only count while measured, as counting slows them down.
+
+use crate::engine::types::*;
+use kiddo::traits::DistanceMetric;
+use std::cell::Cell;
+use std::marker::PhantomData;
+
+thread_local! {
+    static MEASURING: Cell<bool> = const { Cell::new(false) };
+    static WORK: Cell<Work> = const { Cell::new(Work { distances: 0, nodes: 0, buckets: 0 }) };
+    static COORDINATES: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The work counted by `measure`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Work {
+    pub distances: u64,
+    /// Split nodes of kd-trees.
+    pub nodes: u64,
+    /// IVF clusters.
+    pub buckets: u64,
+}
+
+/// A kiddo distance metric counting the work of `D`: whole distances and
+/// single coordinate differences.
+pub(crate) struct Counted<D>(PhantomData<D>);
+
+impl<D: DistanceMetric<f32, EMBEDDING_DIMENSION>> DistanceMetric<f32, EMBEDDING_DIMENSION>
+    for Counted<D>
+{
+    fn dist(a: &[f32; EMBEDDING_DIMENSION], b: &[f32; EMBEDDING_DIMENSION]) -> f32 {
+        count_distances(1);
+        D::dist(a, b)
+    }
+
+    fn dist1(a: f32, b: f32) -> f32 {
+        COORDINATES.with(|coordinates| coordinates.set(coordinates.get() + 1));
+        D::dist1(a, b)
+    }
+}
+
+pub(crate) fn count_distances(count: usize) {
+    self::count(|work| work.distances += count as u64);
+}
+
+pub(crate) fn count_nodes(count: usize) {
+    self::count(|work| work.nodes += count as u64);
+}
+
+pub(crate) fn count_buckets(count: usize) {
+    self::count(|work| work.buckets += count as u64);
+}
+
+fn count(f: impl FnOnce(&mut Work)) {
+    WORK.with(|cell| {
+        let mut work = cell.get();
+        f(&mut work);
+        cell.set(work);
+    });
+}
+
+/// The coordinate differences computed by `Counted` metrics on this thread so
+/// far.
+pub(crate) fn coordinates() -> u64 {
+    COORDINATES.with(|coordinates| coordinates.get())
+}
+
+/// Whether a `measure` call is running on this thread, so kd-tree searches
+/// should use `Counted` metrics.
+pub(crate) fn is_measuring() -> bool {
+    MEASURING.with(|measuring| measuring.get())
+}
+
+/// Runs `f` and returns the work it did on this thread.
+pub(crate) fn measure<T>(f: impl FnOnce() -> T) -> (T, Work) {
+    let measuring = MEASURING.with(|measuring| measuring.replace(true));
+    let outer = WORK.with(|work| work.replace(Work::default()));
+
+    let result = f();
+
+    let counted = WORK.with(|work| {
+        work.replace(Work {
+            distances: outer.distances + work.get().distances,
+            nodes: outer.nodes + work.get().nodes,
+            buckets: outer.buckets + work.get().buckets,
+        })
+    });
+    MEASURING.with(|cell| cell.set(measuring));
+
+    (result, counted)
+}
+
+/// A monotonic clock in milliseconds for measuring durations,
+/// `performance.now()` in browsers and Node.js.
+///
+/// wasm32 builds without the `wasm` feature have no clock and always return
+/// 0.
+pub(crate) fn clock() -> f64 {
+    #[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+    return performance_now().unwrap_or_else(js_sys::Date::now);
+
+    #[cfg(not(target_arch = "wasm32"))]
+    return START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_secs_f64()
+        * 1000.0;
+
+    #[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+    return 0.0;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+
+// `performance.now()`, if the global object has a `performance`.
+#[cfg(all(target_arch = "wasm32", feature = "wasm"))]
+fn performance_now() -> Option<f64> {
+    use wasm_bindgen::JsCast;
+
+    let performance = js_sys::Reflect::get(&js_sys::global(), &"performance".into()).ok()?;
+
+    js_sys::Reflect::get(&performance, &"now".into())
+        .ok()?
+        .dyn_into::<js_sys::Function>()
+        .ok()?
+        .call0(&performance)
+        .ok()?
+        .as_f64()
+}
@@ -0,0 +1,86 @@
+use crate::engine::{types::*, ExportRecord};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+/// What `scan` returns besides the ids.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct ScanOptions {
+    /// Returns the vectors too. Finding them walks the tree until every vector
+    /// of the page is found, up to a full pass for each page.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub with_vectors: bool,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub with_metadata: bool,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub with_content: bool,
+}
+
+/// One page of a `scan`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct ScanPage {
+    pub entries: Vec<ExportRecord>,
+    /// Pass to the next `scan` call to continue after this page, unset once
+    /// every entry has been returned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+/// Returns up to `limit` entries in id order, starting after the id `cursor`.
+///
+/// The cursor is the last id of the previous page, so adding or removing
+/// entries between calls never repeats or skips an entry present for the
+/// whole scan. Entries added behind the cursor are not returned.
+pub fn scan(
+    index: &Index,
+    cursor: Option<&str>,
+    limit: usize,
+    options: ScanOptions,
+) -> Result<ScanPage, EngineError> {
+    if limit == 0 {
+        return Err(EngineError::new(
+            "The scan limit must be greater than 0".to_string(),
+        ));
+    }
+
+    let mut remaining = index
+        .hash
+        .iter()
+        .filter(|(_, id)| cursor.is_none_or(|cursor| id.as_str() > cursor))
+        .map(|(hash, id)| (id.as_str(), *hash))
+        .collect::<Vec<(&str, u64)>>();
+
+    let more = remaining.len() > limit;
+
+    if more {
+        remaining.select_nth_unstable(limit);
+        remaining.truncate(limit);
+    }
+
+    remaining.sort_unstable();
+
+    let hashes = remaining
+        .iter()
+        .map(|(_, hash)| *hash)
+        .collect::<Vec<u64>>();
+    let entries = super::records_of(
+        index,
+        &hashes,
+        options.with_vectors,
+        options.with_metadata,
+        options.with_content,
+    );
+
+    Ok(ScanPage {
+        cursor: match more {
+            true => remaining.last().map(|(id, _)| id.to_string()),
+            false => None,
+        },
+        entries,
+    })
+}
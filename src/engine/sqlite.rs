@@ -0,0 +1,534 @@
+use crate::engine::{types::*, Collections, MetadataFilter, Operation, DEFAULT_COLLECTION};
+use rusqlite::{params, params_from_iter, Connection, Transaction};
+use std::collections::HashMap;
+use std::path::Path;
+
+// Vectors are stored as little-endian f32 blobs. A multi-vector document keeps
+// an empty `vector` and one `chunks` row per vector. Entries without metadata
+// have no `metadata` rows.
+const SCHEMA: &str = "
+CREATE TABLE collections (
+    name TEXT PRIMARY KEY,
+    options TEXT NOT NULL,
+    frozen INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE entries (
+    collection TEXT NOT NULL REFERENCES collections (name) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    vector BLOB NOT NULL,
+    expires_at REAL,
+    content TEXT,
+    PRIMARY KEY (collection, id)
+);
+CREATE TABLE metadata (
+    collection TEXT NOT NULL,
+    id TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (collection, id, key),
+    FOREIGN KEY (collection, id) REFERENCES entries (collection, id) ON DELETE CASCADE
+);
+CREATE INDEX metadata_value ON metadata (collection, key, value);
+CREATE TABLE chunks (
+    collection TEXT NOT NULL,
+    id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    vector BLOB NOT NULL,
+    PRIMARY KEY (collection, id, position),
+    FOREIGN KEY (collection, id) REFERENCES entries (collection, id) ON DELETE CASCADE
+);
+";
+
+// Stored as `PRAGMA user_version`.
+const SCHEMA_VERSION: i64 = 1;
+
+/// Collections persisted in SQLite tables: `collections`, `entries`,
+/// `metadata` and `chunks`, so the data can be inspected and queried with
+/// standard tools.
+///
+/// The indexes are rebuilt from the tables on open. Every change is applied in
+/// memory and then written in one SQLite transaction. If writing fails the
+/// store refuses further changes, reopen it to continue from what was written.
+/// Search statistics used by `Eviction::Lru` and `Eviction::Lfu` are not
+/// persisted.
+pub struct SqliteStore {
+    connection: Connection,
+    collections: Collections,
+    failed: bool,
+}
+
+impl SqliteStore {
+    /// Opens the database file at `path`, creating it when missing.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, EngineError> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Uses an open connection, e.g. the database of the application or
+    /// `Connection::open_in_memory`. A database without the tables gets them
+    /// with an empty default collection, like `Collections::new`.
+    pub fn with_connection(connection: Connection) -> Result<Self, EngineError> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+
+        let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        match version {
+            0 => {
+                connection.execute_batch(&format!(
+                    "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+                    SCHEMA, SCHEMA_VERSION
+                ))?;
+
+                let mut store = SqliteStore {
+                    connection,
+                    collections: Collections {
+                        indexes: HashMap::new(),
+                    },
+                    failed: false,
+                };
+                store.create_collection(DEFAULT_COLLECTION, IndexOptions::default())?;
+
+                Ok(store)
+            }
+            SCHEMA_VERSION => {
+                let collections = read_collections(&connection)?;
+
+                Ok(SqliteStore {
+                    connection,
+                    collections,
+                    failed: false,
+                })
+            }
+            _ => Err(EngineError::new(format!(
+                "Unsupported schema version {}, expected {}",
+                version, SCHEMA_VERSION
+            ))),
+        }
+    }
+
+    pub fn collections(&self) -> &Collections {
+        &self.collections
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Index> {
+        self.collections.get(name)
+    }
+
+    pub fn into_connection(self) -> Connection {
+        self.connection
+    }
+
+    pub fn create_collection(
+        &mut self,
+        name: &str,
+        options: IndexOptions,
+    ) -> Result<(), EngineError> {
+        self.check_healthy()?;
+
+        let json =
+            serde_json::to_string(&options).map_err(|err| EngineError::new(err.to_string()))?;
+        self.collections.create(name, options)?;
+
+        self.write(|tx| {
+            tx.execute(
+                "INSERT INTO collections (name, options) VALUES (?1, ?2)",
+                params![name, json],
+            )?;
+
+            Ok(())
+        })
+    }
+
+    /// Drops the named collection with all its rows.
+    pub fn drop_collection(&mut self, name: &str) -> Result<(), EngineError> {
+        self.check_healthy()?;
+        self.collections.drop(name)?;
+
+        self.write(|tx| {
+            tx.execute("DELETE FROM collections WHERE name = ?1", params![name])?;
+
+            Ok(())
+        })
+    }
+
+    /// Applies `operations` to the named collection like `transact`, creating
+    /// it with default options when missing, and returns the evicted ids.
+    /// A `ttl` is stored as the `expires_at` it gives.
+    pub fn transact(
+        &mut self,
+        collection: &str,
+        mut operations: Vec<Operation>,
+    ) -> Result<Vec<String>, EngineError> {
+        self.check_healthy()?;
+
+        super::resolve_ttl(&mut operations, super::now());
+
+        // Missing collections are created with default options.
+        let options = serde_json::to_string(&IndexOptions::default())
+            .map_err(|err| EngineError::new(err.to_string()))?;
+        let created = self.collections.get(collection).is_none();
+        let index = self.collections.get_or_create(collection);
+
+        let evicted = match super::transact(index, operations.to_owned()) {
+            Ok(evicted) => evicted,
+            Err(err) => {
+                if created {
+                    self.collections.indexes.remove(collection);
+                }
+
+                return Err(EngineError::new(err.to_string()));
+            }
+        };
+
+        self.write(|tx| {
+            if created {
+                tx.execute(
+                    "INSERT INTO collections (name, options) VALUES (?1, ?2)",
+                    params![collection, options],
+                )?;
+            }
+
+            for operation in &operations {
+                match operation {
+                    Operation::Add(resource) => insert_entry(tx, collection, resource)?,
+                    Operation::Upsert(resource) => {
+                        delete_entries(tx, collection, &[resource.id.to_owned()])?;
+                        insert_entry(tx, collection, resource)?;
+                    }
+                    Operation::Remove(ids) => delete_entries(tx, collection, ids)?,
+                }
+            }
+
+            delete_entries(tx, collection, &evicted)
+        })?;
+
+        Ok(evicted)
+    }
+
+    /// Returns the ids of the named collection whose metadata matches
+    /// `filter`, sorted, evaluated by SQLite.
+    pub fn ids_where(
+        &self,
+        collection: &str,
+        filter: &MetadataFilter,
+    ) -> Result<Vec<String>, EngineError> {
+        if self.collections.get(collection).is_none() {
+            return Err(EngineError::new(format!(
+                "Collection {} not found",
+                collection
+            )));
+        }
+
+        let mut sql = "SELECT e.id FROM entries e WHERE e.collection = ? AND ".to_string();
+        let mut values = vec![collection.to_string()];
+        condition(filter, &mut sql, &mut values);
+        sql.push_str(" ORDER BY e.id");
+
+        let mut statement = self.connection.prepare(&sql)?;
+        let ids = statement
+            .query_map(params_from_iter(values), |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        Ok(ids)
+    }
+
+    /// Removes the entries of the named collection matching `filter`, found
+    /// with `ids_where`, and returns their ids sorted.
+    pub fn remove_where(
+        &mut self,
+        collection: &str,
+        filter: &MetadataFilter,
+    ) -> Result<Vec<String>, EngineError> {
+        self.check_healthy()?;
+        self.collections.find_mut(collection)?.check_mutable()?;
+
+        let ids = self.ids_where(collection, filter)?;
+
+        if ids.is_empty() {
+            return Ok(ids);
+        }
+
+        super::remove(self.collections.find_mut(collection)?, &ids)?;
+        self.write(|tx| delete_entries(tx, collection, &ids))?;
+
+        Ok(ids)
+    }
+
+    /// Removes the expired entries of the named collection, see
+    /// `purge_expired`.
+    pub fn purge_expired(
+        &mut self,
+        collection: &str,
+        now: f64,
+    ) -> Result<Vec<String>, EngineError> {
+        self.check_healthy()?;
+
+        let ids = super::purge_expired(self.collections.find_mut(collection)?, now)?;
+        self.write(|tx| delete_entries(tx, collection, &ids))?;
+
+        Ok(ids)
+    }
+
+    pub fn clear(&mut self, collection: &str) -> Result<(), EngineError> {
+        self.check_healthy()?;
+        super::clear(self.collections.find_mut(collection)?)?;
+
+        self.write(|tx| {
+            tx.execute(
+                "DELETE FROM entries WHERE collection = ?1",
+                params![collection],
+            )?;
+
+            Ok(())
+        })
+    }
+
+    pub fn freeze(&mut self, collection: &str) -> Result<(), EngineError> {
+        self.check_healthy()?;
+        super::freeze(self.collections.find_mut(collection)?);
+
+        self.write(|tx| {
+            tx.execute(
+                "UPDATE collections SET frozen = 1 WHERE name = ?1",
+                params![collection],
+            )?;
+
+            Ok(())
+        })
+    }
+
+    fn check_healthy(&self) -> Result<(), EngineError> {
+        match self.failed {
+            true => Err(EngineError::new(
+                "The store failed to persist a change, reopen it".to_string(),
+            )),
+            false => Ok(()),
+        }
+    }
+
+    // Runs `changes` in one SQLite transaction, refusing further changes if
+    // it fails as the collections are already changed.
+    fn write<F>(&mut self, changes: F) -> Result<(), EngineError>
+    where
+        F: FnOnce(&Transaction) -> Result<(), EngineError>,
+    {
+        self.failed = true;
+
+        let tx = self.connection.transaction()?;
+        changes(&tx)?;
+        tx.commit()?;
+
+        self.failed = false;
+
+        Ok(())
+    }
+}
+
+fn read_collections(connection: &Connection) -> Result<Collections, EngineError> {
+    let mut indexes = HashMap::new();
+
+    let mut statement = connection.prepare("SELECT name, options, frozen FROM collections")?;
+    let rows = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<Result<Vec<(String, String, bool)>, _>>()?;
+
+    for (name, options, frozen) in rows {
+        let options = serde_json::from_str::<IndexOptions>(&options)
+            .map_err(|err| EngineError::new(format!("Invalid options of {}: {}", name, err)))?;
+
+        let mut index = super::index(&read_entries(connection, &name)?, options)?;
+
+        if frozen {
+            super::freeze(&mut index);
+        }
+
+        indexes.insert(name, index);
+    }
+
+    Ok(Collections { indexes })
+}
+
+// Returns the entries of `collection` sorted by id.
+fn read_entries(
+    connection: &Connection,
+    collection: &str,
+) -> Result<Vec<EmbeddedResource>, EngineError> {
+    let mut statement = connection.prepare(
+        "SELECT id, vector, expires_at, content FROM entries WHERE collection = ?1 ORDER BY id",
+    )?;
+    let mut resources = statement
+        .query_map(params![collection], |row| {
+            Ok(EmbeddedResource {
+                id: row.get(0)?,
+                embeddings: from_blob(&row.get::<_, Vec<u8>>(1)?),
+                expires_at: row.get(2)?,
+                content: row.get(3)?,
+                ..Default::default()
+            })
+        })?
+        .collect::<Result<Vec<EmbeddedResource>, _>>()?;
+
+    let positions = resources
+        .iter()
+        .enumerate()
+        .map(|(position, resource)| (resource.id.to_owned(), position))
+        .collect::<HashMap<String, usize>>();
+
+    let mut statement =
+        connection.prepare("SELECT id, key, value FROM metadata WHERE collection = ?1")?;
+    let mut rows = statement.query(params![collection])?;
+
+    while let Some(row) = rows.next()? {
+        let resource = &mut resources[positions[&row.get::<_, String>(0)?]];
+
+        resource
+            .metadata
+            .get_or_insert_with(Metadata::new)
+            .insert(row.get(1)?, row.get(2)?);
+    }
+
+    let mut statement = connection
+        .prepare("SELECT id, vector FROM chunks WHERE collection = ?1 ORDER BY id, position")?;
+    let mut rows = statement.query(params![collection])?;
+
+    while let Some(row) = rows.next()? {
+        let resource = &mut resources[positions[&row.get::<_, String>(0)?]];
+
+        resource
+            .chunks
+            .get_or_insert_with(Vec::new)
+            .push(from_blob(&row.get::<_, Vec<u8>>(1)?));
+    }
+
+    Ok(resources)
+}
+
+fn insert_entry(
+    tx: &Transaction,
+    collection: &str,
+    resource: &EmbeddedResource,
+) -> Result<(), EngineError> {
+    let vector = match resource.chunks {
+        Some(_) => vec![],
+        None => to_blob(&resource.embeddings),
+    };
+
+    tx.execute(
+        "INSERT INTO entries (collection, id, vector, expires_at, content) VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![collection, resource.id, vector, resource.expires_at, resource.content],
+    )?;
+
+    for (key, value) in resource.metadata.iter().flatten() {
+        tx.execute(
+            "INSERT INTO metadata (collection, id, key, value) VALUES (?1, ?2, ?3, ?4)",
+            params![collection, resource.id, key, value],
+        )?;
+    }
+
+    for (position, chunk) in resource.chunks.iter().flatten().enumerate() {
+        tx.execute(
+            "INSERT INTO chunks (collection, id, position, vector) VALUES (?1, ?2, ?3, ?4)",
+            params![collection, resource.id, position, to_blob(chunk)],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Deletes the entries with `ids`, their metadata and chunks cascade.
+fn delete_entries(tx: &Transaction, collection: &str, ids: &[String]) -> Result<(), EngineError> {
+    let mut statement =
+        tx.prepare_cached("DELETE FROM entries WHERE collection = ?1 AND id = ?2")?;
+
+    for id in ids {
+        statement.execute(params![collection, id])?;
+    }
+
+    Ok(())
+}
+
+// Appends the SQL condition matching `filter` on the entry `e` to `sql` and
+// its parameters to `values`, with the semantics of `MetadataFilter::matches`.
+fn condition(filter: &MetadataFilter, sql: &mut String, values: &mut Vec<String>) {
+    const HAS_KEY: &str = "EXISTS (SELECT 1 FROM metadata m WHERE m.collection = e.collection \
+                           AND m.id = e.id AND m.key = ?";
+
+    match filter {
+        MetadataFilter::Eq { key, value } => {
+            sql.push_str(HAS_KEY);
+            sql.push_str(" AND m.value = ?)");
+            values.extend([key.to_owned(), value.to_owned()]);
+        }
+        MetadataFilter::Ne { key, value } => {
+            sql.push_str("NOT ");
+            condition(
+                &MetadataFilter::Eq {
+                    key: key.to_owned(),
+                    value: value.to_owned(),
+                },
+                sql,
+                values,
+            );
+        }
+        MetadataFilter::In { values: wanted, .. } if wanted.is_empty() => sql.push('0'),
+        MetadataFilter::In {
+            key,
+            values: wanted,
+        } => {
+            sql.push_str(HAS_KEY);
+            sql.push_str(" AND m.value IN (");
+            sql.push_str(&vec!["?"; wanted.len()].join(", "));
+            sql.push_str("))");
+            values.push(key.to_owned());
+            values.extend(wanted.iter().cloned());
+        }
+        MetadataFilter::Exists(key) => {
+            sql.push_str(HAS_KEY);
+            sql.push(')');
+            values.push(key.to_owned());
+        }
+        MetadataFilter::And(filters) => join(filters, " AND ", "1", sql, values),
+        MetadataFilter::Or(filters) => join(filters, " OR ", "0", sql, values),
+        MetadataFilter::Not(filter) => {
+            sql.push_str("NOT (");
+            condition(filter, sql, values);
+            sql.push(')');
+        }
+    }
+}
+
+// Joins the conditions of `filters` with `operator`, `empty` is the condition
+// of no filters.
+fn join(
+    filters: &[MetadataFilter],
+    operator: &str,
+    empty: &str,
+    sql: &mut String,
+    values: &mut Vec<String>,
+) {
+    if filters.is_empty() {
+        sql.push_str(empty);
+    }
+
+    for (position, filter) in filters.iter().enumerate() {
+        if position > 0 {
+            sql.push_str(operator);
+        }
+
+        sql.push('(');
+        condition(filter, sql, values);
+        sql.push(')');
+    }
+}
+
+fn to_blob(vector: &[f32]) -> Vec<u8> {
+    vector
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
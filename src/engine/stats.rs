@@ -0,0 +1,186 @@
+use crate::engine::{types::*, Tree};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::mem::size_of;
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+/// Sizes and shape of an index. Byte counts are estimates of the heap memory
+/// held by each part.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct IndexStats {
+    /// The number of entries. A multi-vector document counts once.
+    pub documents: usize,
+    /// The number of vectors in the tree.
+    pub vectors: usize,
+    pub dimension: usize,
+    pub metric: Metric,
+    pub kind: IndexKind,
+    pub frozen: bool,
+    /// Entries that expired but are still held until `purge_expired` removes
+    /// them. Removal is otherwise eager, its only remains being empty leaf
+    /// slots.
+    pub tombstones: usize,
+    /// The vector values, including the zero padding kept by the kd-trees.
+    pub vector_bytes: usize,
+    /// Everything else held by the tree: nodes, unused leaf slots, clusters
+    /// and the chunk map.
+    pub tree_bytes: usize,
+    /// The id strings and the id map.
+    pub id_bytes: usize,
+    /// The metadata, the content and the expiration times.
+    pub metadata_bytes: usize,
+    pub total_bytes: usize,
+    /// The number of stems on the longest path from the root to a leaf.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub depth: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub leaves: Option<usize>,
+    /// The fraction of leaf slots holding a vector.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub bucket_fill: Option<f32>,
+    /// The leaf slots holding no vector, including those emptied by removal.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub empty_slots: Option<usize>,
+    /// The number of IVF clusters, zero before training.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub lists: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub mean_list_size: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub max_list_size: Option<usize>,
+}
+
+/// Collects the statistics of `index`. For kd-trees this walks every node, but
+/// does not read the vectors.
+pub fn stats(index: &Index) -> IndexStats {
+    let vectors = index.tree.size() as usize;
+
+    let mut stats = IndexStats {
+        documents: index.hash.len(),
+        vectors,
+        dimension: index.dimension,
+        metric: index.options.metric,
+        kind: index.options.kind,
+        frozen: index.is_frozen(),
+        tombstones: expired(index, super::now()),
+        vector_bytes: 0,
+        tree_bytes: map_bytes(&index.chunks),
+        id_bytes: map_bytes(&index.hash) + index.hash.values().map(String::capacity).sum::<usize>(),
+        metadata_bytes: map_bytes(&index.metadata)
+            + map_bytes(&index.expires)
+            + map_bytes(&index.content)
+            + index
+                .content
+                .values()
+                .map(|content| content.len())
+                .sum::<usize>(),
+        total_bytes: 0,
+        depth: None,
+        leaves: None,
+        bucket_fill: None,
+        empty_slots: None,
+        lists: None,
+        mean_list_size: None,
+        max_list_size: None,
+    };
+
+    for metadata in index.metadata.values() {
+        stats.metadata_bytes += map_bytes(metadata);
+        stats.metadata_bytes += metadata
+            .iter()
+            .map(|(key, value)| key.capacity() + value.capacity())
+            .sum::<usize>();
+    }
+
+    let point_bytes = EMBEDDING_DIMENSION * size_of::<f32>();
+
+    match index.tree.as_ref() {
+        Tree::KdTree(tree) => {
+            let shape = tree.shape();
+            let index_bytes = if tree.is_wide() { 4 } else { 2 };
+            let slots = shape.leaves * tree.bucket_size();
+
+            // A leaf holds `bucket_size` points and items, its size is
+            // rounded up to the alignment of the items.
+            let leaf_bytes = (tree.bucket_size() * (point_bytes + size_of::<u64>()) + index_bytes)
+                .div_ceil(8)
+                * 8;
+
+            stats.vector_bytes = vectors * point_bytes;
+            stats.tree_bytes += shape.leaves * leaf_bytes - stats.vector_bytes
+                + shape.stems * (index_bytes * 2 + size_of::<f32>());
+            stats.depth = Some(shape.depth);
+            stats.leaves = Some(shape.leaves);
+            stats.bucket_fill = Some(match slots {
+                0 => 0.0,
+                _ => vectors as f32 / slots as f32,
+            });
+            stats.empty_slots = Some(slots.saturating_sub(vectors));
+        }
+        Tree::Ivf(ivf) => {
+            let entries = ivf.lists.iter().flatten().chain(ivf.pending.iter());
+
+            stats.vector_bytes = entries
+                .clone()
+                .map(|(_, vector)| vector.capacity() * size_of::<f32>())
+                .sum();
+            stats.tree_bytes += entries.count() * size_of::<(u64, Embedding)>()
+                + ivf
+                    .centroids
+                    .iter()
+                    .map(|centroid| size_of::<Embedding>() + centroid.capacity() * size_of::<f32>())
+                    .sum::<usize>()
+                + ivf.lists.len() * size_of::<Vec<(u64, Embedding)>>()
+                + map_bytes(&ivf.assignments);
+
+            let sizes = ivf.lists.iter().map(Vec::len);
+            stats.lists = Some(ivf.lists.len());
+            stats.max_list_size = Some(sizes.clone().max().unwrap_or(0));
+            stats.mean_list_size = Some(match ivf.lists.len() {
+                0 => 0.0,
+                lists => sizes.sum::<usize>() as f32 / lists as f32,
+            });
+        }
+        Tree::Frozen(frozen) => {
+            let (leaves, depth) = frozen.shape();
+
+            stats.vector_bytes = frozen.points.capacity() * size_of::<f32>();
+            stats.tree_bytes += frozen.items.capacity() * size_of::<u64>()
+                + frozen.stems.capacity() * size_of::<(u32, f32)>();
+            stats.depth = Some(depth);
+            stats.leaves = Some(leaves);
+            stats.bucket_fill = Some(match leaves {
+                0 => 0.0,
+                _ => vectors as f32 / (leaves * BUCKET_SIZE) as f32,
+            });
+            stats.empty_slots = Some((leaves * BUCKET_SIZE).saturating_sub(vectors));
+        }
+    }
+
+    stats.total_bytes =
+        stats.vector_bytes + stats.tree_bytes + stats.id_bytes + stats.metadata_bytes;
+    stats
+}
+
+// The number of entries that expired at or before `now`.
+fn expired(index: &Index, now: f64) -> usize {
+    index
+        .expires
+        .values()
+        .filter(|expires_at| **expires_at <= now)
+        .count()
+}
+
+// The table of a map, one control byte per bucket next to each pair.
+fn map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (size_of::<(K, V)>() + 1)
+}
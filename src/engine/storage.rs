@@ -0,0 +1,194 @@
+use crate::engine::{types::*, Collections};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+/// Where database snapshots are persisted, e.g. files or a key-value store.
+/// Each snapshot is stored under a key naming its database.
+pub trait StorageAdapter {
+    /// Returns the snapshot stored under `key`, if any.
+    fn read(&mut self, key: &str) -> Result<Option<Vec<u8>>, EngineError>;
+    fn write(&mut self, key: &str, data: &[u8]) -> Result<(), EngineError>;
+    /// Deletes the snapshot stored under `key`. Deleting a missing key is not
+    /// an error.
+    fn delete(&mut self, key: &str) -> Result<(), EngineError>;
+}
+
+/// A `StorageAdapter` keeping snapshots in memory, for tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    pub data: HashMap<String, Vec<u8>>,
+}
+
+impl StorageAdapter for MemoryStorage {
+    fn read(&mut self, key: &str) -> Result<Option<Vec<u8>>, EngineError> {
+        Ok(self.data.get(key).cloned())
+    }
+
+    fn write(&mut self, key: &str, data: &[u8]) -> Result<(), EngineError> {
+        self.data.insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), EngineError> {
+        self.data.remove(key);
+        Ok(())
+    }
+}
+
+/// When changes are saved automatically. Without either limit only explicit
+/// flushes save.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct AutosaveOptions {
+    /// Save once this many changes are unsaved.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub max_changes: Option<usize>,
+    /// Save the unsaved changes once this many milliseconds passed since the
+    /// last save.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub interval: Option<f64>,
+}
+
+/// Counts the changes made since the last save and tells when the
+/// `AutosaveOptions` call for the next one.
+#[derive(Debug, Clone, Default)]
+pub struct Autosave {
+    options: AutosaveOptions,
+    changes: usize,
+    last_save: f64,
+}
+
+impl Autosave {
+    pub fn new(options: AutosaveOptions, now: f64) -> Self {
+        Autosave {
+            options,
+            changes: 0,
+            last_save: now,
+        }
+    }
+
+    pub fn options(&self) -> AutosaveOptions {
+        self.options
+    }
+
+    /// The number of unsaved changes.
+    pub fn changes(&self) -> usize {
+        self.changes
+    }
+
+    pub fn record(&mut self, changes: usize) {
+        self.changes += changes;
+    }
+
+    /// Whether there are unsaved changes that should be saved at `now`.
+    pub fn is_due(&self, now: f64) -> bool {
+        self.changes > 0
+            && (self
+                .options
+                .max_changes
+                .is_some_and(|max| self.changes >= max)
+                || self
+                    .options
+                    .interval
+                    .is_some_and(|interval| now - self.last_save >= interval))
+    }
+
+    /// The milliseconds from `now` until the unsaved changes are due by
+    /// `interval`, or `None` without unsaved changes or an interval. Changes
+    /// made after the last save are only saved on time if another check runs
+    /// then.
+    pub fn due_in(&self, now: f64) -> Option<f64> {
+        match self.changes > 0 {
+            true => self
+                .options
+                .interval
+                .map(|interval| (self.last_save + interval - now).max(0.0)),
+            false => None,
+        }
+    }
+
+    pub fn saved(&mut self, now: f64) {
+        self.changes = 0;
+        self.last_save = now;
+    }
+}
+
+/// Collections saved to a `StorageAdapter` under one key.
+pub struct PersistentCollections<S: StorageAdapter> {
+    pub collections: Collections,
+    storage: S,
+    key: String,
+    autosave: Autosave,
+}
+
+impl<S: StorageAdapter> PersistentCollections<S> {
+    /// Loads the collections stored under `key`, or starts with empty ones
+    /// when nothing is stored yet.
+    pub fn open(mut storage: S, key: &str, options: AutosaveOptions) -> Result<Self, EngineError> {
+        let collections = match storage.read(key)? {
+            Some(data) => super::load_collections(&data)?,
+            None => Collections::new(),
+        };
+
+        Ok(PersistentCollections {
+            collections,
+            storage,
+            key: key.to_string(),
+            autosave: Autosave::new(options, super::now()),
+        })
+    }
+
+    /// Records `changes` changes made to `collections` and saves them if the
+    /// autosave options call for it. Returns whether it saved. Call it with
+    /// no changes once `Autosave::due_in` passed, e.g. from a timer, to save
+    /// the last changes when no further change comes.
+    pub fn changed(&mut self, changes: usize) -> Result<bool, EngineError> {
+        self.autosave.record(changes);
+
+        if !self.autosave.is_due(super::now()) {
+            return Ok(false);
+        }
+
+        self.flush()?;
+
+        Ok(true)
+    }
+
+    /// Saves the collections now.
+    pub fn flush(&mut self) -> Result<(), EngineError> {
+        let data = super::dump_collections(&self.collections, None)?;
+        self.storage.write(&self.key, &data)?;
+        self.autosave.saved(super::now());
+
+        Ok(())
+    }
+
+    /// The number of changes made since the last save.
+    pub fn unsaved_changes(&self) -> usize {
+        self.autosave.changes()
+    }
+
+    /// The milliseconds until the unsaved changes are due by the autosave
+    /// interval, see `Autosave::due_in`.
+    pub fn save_due_in(&self) -> Option<f64> {
+        self.autosave.due_in(super::now())
+    }
+
+    /// Deletes the stored collections. They are saved again on the next
+    /// flush.
+    pub fn delete(&mut self) -> Result<(), EngineError> {
+        self.storage.delete(&self.key)
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+}
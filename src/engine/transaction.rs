@@ -0,0 +1,173 @@
+use crate::engine::{types::*, Detached};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
+
+/// One change applied by `transact`, e.g. `{ "remove": ["a", "b"] }`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Add(EmbeddedResource),
+    Upsert(EmbeddedResource),
+    Remove(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct Transaction {
+    pub operations: Vec<Operation>,
+}
+
+impl Operation {
+    /// The number of entries the operation adds, replaces or removes.
+    pub fn entries(&self) -> usize {
+        match self {
+            Operation::Add(_) | Operation::Upsert(_) => 1,
+            Operation::Remove(ids) => ids.len(),
+        }
+    }
+}
+
+/// Why a `transact` call was rolled back.
+#[derive(Debug)]
+pub struct TransactionError {
+    /// The position of the failing operation.
+    pub operation: usize,
+    pub error: EngineError,
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(
+            f,
+            "Operation {} failed, the transaction was rolled back: {}",
+            self.operation, self.error
+        )
+    }
+}
+
+impl Error for TransactionError {}
+
+// What an applied operation changed, undone in reverse order on rollback.
+enum Change {
+    Added(u64),
+    Removed(Detached),
+}
+
+/// Applies `operations` in order, all or nothing. On the first failure every
+/// applied operation is undone, leaving the index as it was, and the failing
+/// operation is returned.
+///
+/// The `max_vectors` and `max_bytes` limits are enforced once every operation
+/// succeeded, the evicted ids are returned.
+pub fn transact(
+    index: &mut Index,
+    operations: Vec<Operation>,
+) -> Result<Vec<String>, TransactionError> {
+    apply_all(index, operations)?;
+
+    super::evict(index, 0).map_err(|error| TransactionError {
+        operation: 0,
+        error,
+    })
+}
+
+// Replaces each `ttl` of `operations` by the `expires_at` it gives at `now`,
+// so persisted operations expire at the same time when replayed.
+pub(crate) fn resolve_ttl(operations: &mut [Operation], now: f64) {
+    for operation in operations {
+        if let Operation::Add(resource) | Operation::Upsert(resource) = operation {
+            if resource.expires_at.is_none() {
+                resource.expires_at = resource.ttl.take().map(|ttl| now + ttl);
+            }
+        }
+    }
+}
+
+// Applies `operations` all or nothing like `transact`, without enforcing the
+// size limits.
+pub(crate) fn apply_all(
+    index: &mut Index,
+    operations: Vec<Operation>,
+) -> Result<(), TransactionError> {
+    let usage = index.usage.clone();
+    let dimension = index.dimension;
+    let mut changes = vec![];
+
+    for (position, operation) in operations.into_iter().enumerate() {
+        if let Err(error) = apply(index, operation, &mut changes) {
+            undo(index, changes);
+            index.usage = usage;
+            index.dimension = dimension;
+
+            return Err(TransactionError {
+                operation: position,
+                error,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Undoes `changes` in reverse order. Entries added in a row are detached in a
+// single pass over the tree, before the removal preceding them is undone since
+// they may reuse its ids.
+fn undo(index: &mut Index, changes: Vec<Change>) {
+    let mut added = HashSet::new();
+
+    for change in changes.into_iter().rev() {
+        match change {
+            Change::Added(hash) => {
+                added.insert(hash);
+            }
+            Change::Removed(detached) => {
+                if !added.is_empty() {
+                    super::detach(index, &std::mem::take(&mut added));
+                }
+
+                super::attach(index, detached);
+            }
+        }
+    }
+
+    if !added.is_empty() {
+        super::detach(index, &added);
+    }
+}
+
+fn apply(
+    index: &mut Index,
+    operation: Operation,
+    changes: &mut Vec<Change>,
+) -> Result<(), EngineError> {
+    index.check_mutable()?;
+
+    match operation {
+        Operation::Add(resource) => changes.push(Change::Added(super::insert(index, resource)?)),
+        Operation::Upsert(resource) => {
+            super::validate(index, &resource)?;
+
+            let hash = super::hash(&resource.id);
+
+            if index.hash.contains_key(&hash) {
+                let detached = super::detach(index, &HashSet::from([hash]));
+                changes.push(Change::Removed(detached));
+            }
+
+            changes.push(Change::Added(super::insert(index, resource)?));
+        }
+        Operation::Remove(ids) => {
+            super::check_present(index, &ids)?;
+
+            let hashes = ids.iter().map(super::hash).collect();
+            changes.push(Change::Removed(super::detach(index, &hashes)));
+        }
+    }
+
+    Ok(())
+}
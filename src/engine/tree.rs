@@ -0,0 +1,193 @@
+use crate::engine::{types::*, AnyKdTree, Frozen, Ivf, IvfBuilder, KdTreeBuilder};
+use serde::{Deserialize, Serialize};
+
+/// The nearest neighbour structure of an index, chosen by `IndexKind` and
+/// replaced by `Frozen` once the index is frozen.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Tree {
+    KdTree(AnyKdTree),
+    Ivf(Ivf),
+    Frozen(Frozen),
+}
+
+impl Tree {
+    pub fn new(options: &IndexOptions) -> Self {
+        match options.kind {
+            IndexKind::KdTree => Tree::KdTree(AnyKdTree::new(
+                options.bucket_size.unwrap_or(BUCKET_SIZE),
+                options.capacity.unwrap_or(0),
+                0,
+            )),
+            IndexKind::Ivf => Tree::Ivf(Ivf::new(options)),
+        }
+    }
+
+    /// Builds a tree holding `entries` of prepared vectors, shorter vectors are
+    /// zero padded.
+    pub fn build(options: &IndexOptions, entries: Vec<(u64, Embedding)>) -> Self {
+        TreeBuilder::new(options, entries).finish()
+    }
+
+    /// Rebuilds the tree from its current content.
+    pub fn optimize(&mut self) {
+        match self {
+            Tree::KdTree(tree) => {
+                let entries = tree
+                    .iter()
+                    .map(|(item, point)| (item, super::trim(&point).to_vec()))
+                    .collect::<Vec<(u64, Embedding)>>();
+
+                let capacity = match tree.is_wide() {
+                    true => super::kdtree_capacity(tree.bucket_size(), true),
+                    false => 0,
+                };
+
+                *tree = AnyKdTree::build(tree.bucket_size(), capacity, entries);
+            }
+            Tree::Ivf(ivf) => ivf.optimize(),
+            // Frozen trees are balanced when built.
+            Tree::Frozen(_) => {}
+        }
+    }
+
+    /// Converts the tree into its read-optimized frozen form.
+    pub fn freeze(&mut self) {
+        if matches!(self, Tree::Frozen(_)) {
+            return;
+        }
+
+        let entries = self
+            .iter()
+            .map(|(item, point)| (item, super::trim(&point).to_vec()))
+            .collect::<Vec<(u64, Embedding)>>();
+
+        *self = Tree::Frozen(Frozen::new(entries));
+    }
+
+    // Callers check that the index is mutable first.
+    pub(crate) fn add(&mut self, point: &[f32; EMBEDDING_DIMENSION], item: u64) {
+        match self {
+            Tree::KdTree(tree) => tree.add(point, item),
+            Tree::Ivf(ivf) => ivf.add(point, item),
+            Tree::Frozen(_) => panic!("Can not add to a frozen tree"),
+        }
+    }
+
+    // Callers check that the index is mutable first.
+    pub(crate) fn remove(&mut self, point: &[f32; EMBEDDING_DIMENSION], item: u64) {
+        match self {
+            Tree::KdTree(tree) => {
+                tree.remove(point, item);
+            }
+            Tree::Ivf(ivf) => ivf.remove(item),
+            Tree::Frozen(_) => panic!("Can not remove from a frozen tree"),
+        }
+    }
+
+    /// Returns the most vectors the tree can hold.
+    pub fn max_capacity(&self) -> usize {
+        match self {
+            Tree::KdTree(tree) => tree.max_capacity(),
+            Tree::Ivf(_) => usize::MAX,
+            Tree::Frozen(frozen) => frozen.size(),
+        }
+    }
+
+    /// Returns the number of stored vectors.
+    pub fn size(&self) -> u64 {
+        match self {
+            Tree::KdTree(tree) => tree.size(),
+            Tree::Ivf(ivf) => ivf.size() as u64,
+            Tree::Frozen(frozen) => frozen.size() as u64,
+        }
+    }
+
+    /// Iterates over all `(item, point)` pairs in arbitrary order.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (u64, [f32; EMBEDDING_DIMENSION])> + '_> {
+        match self {
+            Tree::KdTree(tree) => tree.iter(),
+            Tree::Ivf(ivf) => Box::new(
+                ivf.iter()
+                    .map(|(item, vector)| (item, super::pad(vector))),
+            ),
+            Tree::Frozen(frozen) => Box::new(
+                frozen
+                    .iter()
+                    .map(|(item, vector)| (item, super::pad(vector))),
+            ),
+        }
+    }
+
+    /// Returns up to `n` `(distance, item)` pairs closest to `query`, closest
+    /// first.
+    pub fn nearest(
+        &self,
+        query: &[f32; EMBEDDING_DIMENSION],
+        n: usize,
+        metric: Metric,
+    ) -> Vec<(f32, u64)> {
+        match self {
+            Tree::KdTree(tree) => tree.nearest(query, n, metric),
+            Tree::Ivf(ivf) => ivf.nearest(query, n, metric),
+            Tree::Frozen(frozen) => frozen.nearest(query, n, metric),
+        }
+    }
+}
+
+/// Builds a `Tree` like `Tree::build` a bounded number of vectors per `step`,
+/// so the caller can yield between steps.
+#[derive(Debug)]
+pub enum TreeBuilder {
+    KdTree(KdTreeBuilder),
+    Ivf(IvfBuilder),
+}
+
+impl TreeBuilder {
+    pub fn new(options: &IndexOptions, entries: Vec<(u64, Embedding)>) -> Self {
+        match options.kind {
+            IndexKind::KdTree => TreeBuilder::KdTree(KdTreeBuilder::new(
+                options.bucket_size.unwrap_or(BUCKET_SIZE),
+                options.capacity.unwrap_or(0),
+                entries,
+            )),
+            IndexKind::Ivf => TreeBuilder::Ivf(IvfBuilder::new(options, entries)),
+        }
+    }
+
+    /// The number of vectors the tree is built from.
+    pub fn len(&self) -> usize {
+        match self {
+            TreeBuilder::KdTree(builder) => builder.len(),
+            TreeBuilder::Ivf(builder) => builder.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How far the build has come, from 0 to `len`.
+    pub fn done(&self) -> usize {
+        match self {
+            TreeBuilder::KdTree(builder) => builder.done(),
+            TreeBuilder::Ivf(builder) => builder.done(),
+        }
+    }
+
+    /// Processes up to `max_vectors` more vectors. Returns whether the tree is
+    /// built.
+    pub fn step(&mut self, max_vectors: usize) -> bool {
+        match self {
+            TreeBuilder::KdTree(builder) => builder.step(max_vectors),
+            TreeBuilder::Ivf(builder) => builder.step(max_vectors),
+        }
+    }
+
+    /// Returns the tree, running the remaining steps at once.
+    pub fn finish(self) -> Tree {
+        match self {
+            TreeBuilder::KdTree(builder) => Tree::KdTree(builder.finish()),
+            TreeBuilder::Ivf(builder) => Tree::Ivf(builder.finish()),
+        }
+    }
+}
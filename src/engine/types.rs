@@ -1,26 +1,402 @@
-use kiddo::float::kdtree::KdTree;
+use crate::engine::{kdtree_capacity, Tree, Usage, BUCKET_SIZES};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
+#[cfg(feature = "wasm")]
+use tsify::Tsify;
 use std::{
     collections::HashMap,
     fmt::{Display, Formatter, Result as FmtResult},
+    sync::{
+        atomic::{AtomicU64, Ordering as AtomicOrdering},
+        Arc,
+    },
 };
 
 // Wasm has a 4GB memory limit. Should make sure the bucket size and capacity
 // doesn't exceed it and cause stack overflow.
 // More detail: https://v8.dev/blog/4gb-wasm-memory
-const BUCKET_SIZE: usize = 32;
+pub(crate) const BUCKET_SIZE: usize = 32;
 
 pub const EMBEDDING_DIMENSION: usize = 2048;
 
 pub type Embedding = Vec<f32>;
 
-pub type Tree = KdTree<f32, u64, EMBEDDING_DIMENSION, BUCKET_SIZE, u16>;
+pub type Metadata = HashMap<String, String>;
 
+pub type KdTree<const B: usize, IDX> = kiddo::float::kdtree::KdTree<f32, u64, EMBEDDING_DIMENSION, B, IDX>;
+
+/// The distance used to rank neighbours.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+#[serde(rename_all = "lowercase")]
+pub enum Metric {
+    /// Squared euclidean (L2) distance.
+    #[default]
+    Euclidean,
+    /// Manhattan (L1) distance.
+    Manhattan,
+    /// Cosine distance, `1 - cos(a, b)`. Vectors are normalized on insert.
+    Cosine,
+}
+
+/// How the chunk distances of a multi-vector document are combined.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    /// The distance of the closest chunk.
+    #[default]
+    Max,
+    /// The mean distance over all chunks. Searches scan every vector.
+    Mean,
+}
+
+/// The structure used to find nearest neighbours.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+#[serde(rename_all = "lowercase")]
+pub enum IndexKind {
+    /// An exact kd-tree.
+    #[default]
+    KdTree,
+    /// An inverted file index. Vectors are clustered around k-means
+    /// centroids and a search only scans the `nprobe` closest clusters.
+    Ivf,
+}
+
+/// Which entries are evicted once an index is over its size limits.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+#[serde(rename_all = "lowercase")]
+pub enum Eviction {
+    /// The entries inserted first.
+    #[default]
+    Oldest,
+    /// The least recently retrieved entries. Inserting counts as a retrieval.
+    Lru,
+    /// The least frequently retrieved entries, the least recently retrieved
+    /// first among equals.
+    Lfu,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct IndexOptions {
+    /// Required length of every inserted embedding. When unset any length up
+    /// to `EMBEDDING_DIMENSION` is accepted and zero padded.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub dimension: Option<usize>,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub metric: Metric,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub aggregation: Aggregation,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub kind: IndexKind,
+    /// The number of IVF clusters, 32 by default.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub nlist: Option<usize>,
+    /// The number of IVF clusters scanned per search, 4 by default.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub nprobe: Option<usize>,
+    /// The number of vectors per kd-tree leaf, one of `BUCKET_SIZES`, 32 by
+    /// default.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub bucket_size: Option<usize>,
+    /// The number of vectors the kd-tree is expected to hold. Trees outgrowing
+    /// `kdtree_capacity(bucket_size, false)` vectors are rebuilt once with
+    /// wider node indexes, a larger capacity given here uses them from the
+    /// start.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub capacity: Option<usize>,
+    /// The most vectors the index keeps. Adding past it evicts entries chosen
+    /// by `eviction`.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub max_vectors: Option<usize>,
+    /// The most bytes the entries may take, estimated by `entry_bytes`. Adding
+    /// past it evicts entries chosen by `eviction`.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub max_bytes: Option<usize>,
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub eviction: Eviction,
+}
+
+/// Locates one vector of a multi-vector document.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    /// The hash of the document id.
+    pub document: u64,
+    /// The position of the vector in `EmbeddedResource::chunks`.
+    pub position: usize,
+}
+
+/// A vector index together with the ids and metadata of its entries.
+///
+/// Cloning is cheap: the tree and the maps are shared with the clone until one
+/// side writes to them, which copies only the parts written to. The usage
+/// counters are copied, so searches on a clone don't change the eviction
+/// order of the original.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Index {
-    pub tree: Tree,
-    pub hash: HashMap<u64, String>,
+    pub tree: Arc<Tree>,
+    pub hash: Arc<HashMap<u64, String>>,
+    pub metadata: Arc<HashMap<u64, Metadata>>,
+    // Tree items of multi-vector documents. Single vector entries use the id
+    // hash as their tree item and are not listed here.
+    pub chunks: Arc<HashMap<u64, Chunk>>,
+    // Id hash -> expiration time of the entries added with an expiration.
+    pub expires: Arc<HashMap<u64, f64>>,
+    // Tracked only when `max_vectors` or `max_bytes` is set.
+    pub usage: Usage,
+    // Id hash -> the content of the entries added with one.
+    pub content: Arc<HashMap<u64, Box<str>>>,
+    // The largest embedding length seen so far. Vectors are zero padded to
+    // `EMBEDDING_DIMENSION` inside the tree, this is used to trim them back.
+    pub dimension: usize,
+    pub options: IndexOptions,
+    // Changes whenever entries are added or removed, so a job working on the
+    // index across calls can tell it was modified in between.
+    #[serde(skip, default = "next_revision")]
+    pub(crate) revision: u64,
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Index {
+            tree: Arc::new(Tree::new(&IndexOptions::default())),
+            hash: Arc::default(),
+            metadata: Arc::default(),
+            chunks: Arc::default(),
+            expires: Arc::default(),
+            usage: Usage::default(),
+            content: Arc::default(),
+            dimension: 0,
+            options: IndexOptions::default(),
+            revision: next_revision(),
+        }
+    }
+
+    pub fn with_options(options: IndexOptions) -> Result<Self, EngineError> {
+        if let Some(dimension) = options.dimension {
+            if dimension == 0 || dimension > EMBEDDING_DIMENSION {
+                return Err(EngineError::new(format!(
+                    "Dimension must be between 1 and {}, got {}",
+                    EMBEDDING_DIMENSION, dimension
+                )));
+            }
+        }
+
+        if options.nlist == Some(0) || options.nprobe == Some(0) {
+            return Err(EngineError::new(
+                "nlist and nprobe must be greater than 0".to_string(),
+            ));
+        }
+
+        if options.max_vectors == Some(0) || options.max_bytes == Some(0) {
+            return Err(EngineError::new(
+                "max_vectors and max_bytes must be greater than 0".to_string(),
+            ));
+        }
+
+        let bucket_size = options.bucket_size.unwrap_or(BUCKET_SIZE);
+
+        if !BUCKET_SIZES.contains(&bucket_size) {
+            return Err(EngineError::new(format!(
+                "Bucket size must be one of {:?}, got {}",
+                BUCKET_SIZES, bucket_size
+            )));
+        }
+
+        if let Some(capacity) = options.capacity {
+            let limit = kdtree_capacity(bucket_size, true);
+
+            if capacity == 0 || capacity > limit {
+                return Err(EngineError::new(format!(
+                    "Capacity must be between 1 and {}, got {}",
+                    limit, capacity
+                )));
+            }
+        }
+
+        Ok(Index {
+            tree: Arc::new(Tree::new(&options)),
+            dimension: options.dimension.unwrap_or(0),
+            options,
+            ..Index::new()
+        })
+    }
+
+    /// Whether the index has been frozen by `freeze`.
+    pub fn is_frozen(&self) -> bool {
+        matches!(*self.tree, Tree::Frozen(_))
+    }
+
+    /// Whether the tree or any map is still shared with a clone, so the next
+    /// write to it copies it.
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.tree) > 1
+            || Arc::strong_count(&self.hash) > 1
+            || Arc::strong_count(&self.metadata) > 1
+            || Arc::strong_count(&self.chunks) > 1
+            || Arc::strong_count(&self.expires) > 1
+            || Arc::strong_count(&self.content) > 1
+    }
+
+    /// Whether `max_vectors` or `max_bytes` limits the index.
+    pub fn is_limited(&self) -> bool {
+        self.options.max_vectors.is_some() || self.options.max_bytes.is_some()
+    }
+
+    /// Fails if the index is frozen.
+    pub fn check_mutable(&self) -> Result<(), EngineError> {
+        match self.is_frozen() {
+            true => Err(EngineError::new(
+                "The index is frozen and can not be modified".to_string(),
+            )),
+            false => Ok(()),
+        }
+    }
+}
+
+// Removes `key` from a map of the index, copying the map first only if it holds
+// the key and is shared with a clone.
+pub(crate) fn take<V: Clone>(map: &mut Arc<HashMap<u64, V>>, key: &u64) -> Option<V> {
+    match map.contains_key(key) {
+        true => Arc::make_mut(map).remove(key),
+        false => None,
+    }
+}
+
+// Revisions are unique across indexes, so an index replaced by a new one is
+// seen as modified too.
+pub(crate) fn next_revision() -> u64 {
+    static REVISION: AtomicU64 = AtomicU64::new(0);
+    REVISION.fetch_add(1, AtomicOrdering::Relaxed)
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct SearchResult {
+    pub neighbors: Vec<Neighbor>,
+    /// How the search went, when asked for by `SearchOptions::explain`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explain: Option<SearchExplain>,
+}
+
+/// The work done by one search, see `SearchOptions::explain`.
+///
+/// Chunks of one document and expired entries make a search widen and search
+/// the tree again, the candidate counts are those of the last round.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct SearchExplain {
+    /// The time the search took in milliseconds.
+    pub elapsed: f64,
+    /// The number of times the tree was searched.
+    pub rounds: usize,
+    /// The split nodes of a kd-tree visited.
+    pub nodes: u64,
+    /// The IVF clusters scanned.
+    pub buckets: u64,
+    /// The distances computed, including IVF centroids.
+    pub distances: u64,
+    /// The vectors found by the tree, before filtering.
+    pub candidates: usize,
+    /// The candidates dropped as a closer chunk of their document was found.
+    pub duplicate_chunks: usize,
+    /// The candidates dropped as their entry expired.
+    pub expired: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct Neighbor {
+    pub id: String,
+    pub distance: f32,
+    /// The position of the matched chunk for multi-vector documents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk: Option<usize>,
+    /// The content of the entry, when asked for by `SearchOptions`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// What `search_with` returns besides the ids and distances.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct SearchOptions {
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub with_content: bool,
+    /// Returns a `SearchExplain` with the result. Explained searches are
+    /// slower, as they count their work.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub explain: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct EmbeddedResource {
+    pub id: String,
+    /// The vector of the entry, left empty when `chunks` is given.
+    #[serde(default)]
+    #[cfg_attr(feature = "wasm", tsify(optional))]
+    pub embeddings: Vec<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+    /// The vectors of a multi-vector document. Searches return the document
+    /// once, scored by `IndexOptions::aggregation` over its chunks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<Embedding>>,
+    /// When the entry expires, in milliseconds since the Unix epoch. Searches
+    /// skip expired entries until `purge_expired` removes them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<f64>,
+    /// The lifetime of the entry in milliseconds from when it is added,
+    /// ignored when `expires_at` is given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<f64>,
+    /// The text the vectors were computed from, such as the chunk text for
+    /// retrieval augmented generation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+/// The queries of a batch search.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct BatchQuery {
+    pub queries: Vec<Embedding>,
+}
+
+/// One `SearchResult` per query of a batch search, in query order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct BatchSearchResult {
+    pub results: Vec<SearchResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "wasm", derive(Tsify), tsify(into_wasm_abi, from_wasm_abi))]
+pub struct Resource {
+    pub embeddings: Vec<EmbeddedResource>,
 }
 
 #[derive(Debug)]
@@ -41,3 +417,22 @@ impl Display for EngineError {
 }
 
 impl Error for EngineError {}
+
+impl From<std::io::Error> for EngineError {
+    fn from(err: std::io::Error) -> Self {
+        EngineError::new(err.to_string())
+    }
+}
+
+impl From<bincode::Error> for EngineError {
+    fn from(err: bincode::Error) -> Self {
+        EngineError::new(err.to_string())
+    }
+}
+
+#[cfg(all(feature = "sqlite", not(target_arch = "wasm32")))]
+impl From<rusqlite::Error> for EngineError {
+    fn from(err: rusqlite::Error) -> Self {
+        EngineError::new(err.to_string())
+    }
+}
@@ -1,12 +1,36 @@
-mod engine;
+//! A lightweight, local vector database.
+//!
+//! The [`engine`] module is the native Rust API. With the default `wasm`
+//! feature the crate also exports the `LunaVDB` JavaScript bindings.
+//!
+//! ```
+//! use luna_vdb::engine::{self, EmbeddedResource};
+//!
+//! let mut index = engine::Index::new();
+//!
+//! engine::add(
+//!     &mut index,
+//!     EmbeddedResource {
+//!         id: "cat".to_string(),
+//!         embeddings: vec![0.8, 0.7, 0.6],
+//!         ..Default::default()
+//!     },
+//! )
+//! .unwrap();
+//!
+//! let result = engine::search(&index, &[0.8, 0.7, 0.6], 1);
+//! assert_eq!(result.neighbors[0].id, "cat");
+//! ```
+
+pub mod engine;
+#[cfg(feature = "wasm")]
 mod utils;
+#[cfg(feature = "wasm")]
 mod wasm;
 
+#[cfg(feature = "wasm")]
 pub use wasm::*;
 
-#[cfg(test)]
-pub use engine::hash;
-
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
 #[cfg(feature = "wee_alloc")]
@@ -0,0 +1,87 @@
+use crate::engine::{self, Embedding, EngineError};
+use crate::wasm::*;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+/// The embedding callback registered with `LunaVDB::set_embedder`. It is
+/// called with an array of texts and returns an array of vectors, or a
+/// Promise of one.
+pub(crate) struct Embedder {
+    callback: js_sys::Function,
+    options: EmbedderOptions,
+    cache: engine::EmbeddingCache,
+}
+
+/// Shared so a pending `embed_texts` Promise can update the cache after the
+/// call returned.
+pub(crate) type SharedEmbedder = Rc<RefCell<Embedder>>;
+
+impl Embedder {
+    pub(crate) fn new(callback: js_sys::Function, options: EmbedderOptions) -> SharedEmbedder {
+        Rc::new(RefCell::new(Embedder {
+            callback,
+            options,
+            cache: engine::EmbeddingCache::new(options.cache_size),
+        }))
+    }
+}
+
+/// Embeds `texts`, awaiting the callback if it returns a Promise.
+pub(crate) async fn embed(
+    embedder: SharedEmbedder,
+    texts: Vec<String>,
+) -> Result<Vec<Embedding>, EngineError> {
+    // The cache is not borrowed across awaits, other calls may use it
+    // meanwhile.
+    let (callback, batches) = {
+        let embedder = embedder.borrow();
+        let batches = engine::pending_batches(&texts, &embedder.cache, embedder.options.batch_size);
+
+        (embedder.callback.to_owned(), batches)
+    };
+
+    let mut computed = HashMap::new();
+
+    for batch in batches {
+        let mut value = call(&callback, &batch)?;
+
+        if value.is_instance_of::<js_sys::Promise>() {
+            value = JsFuture::from(js_sys::Promise::from(value))
+                .await
+                .map_err(js_error)?;
+        }
+
+        let embeddings = parse(value)?;
+        engine::check_embeddings(&batch, &embeddings)?;
+
+        computed.extend(batch.into_iter().zip(embeddings));
+    }
+
+    engine::resolve_embeddings(&texts, computed, &mut embedder.borrow_mut().cache)
+}
+
+fn call(callback: &js_sys::Function, batch: &[String]) -> Result<JsValue, EngineError> {
+    let texts =
+        serde_wasm_bindgen::to_value(batch).map_err(|err| EngineError::new(err.to_string()))?;
+
+    callback.call1(&JsValue::NULL, &texts).map_err(js_error)
+}
+
+fn parse(value: JsValue) -> Result<Vec<Embedding>, EngineError> {
+    serde_wasm_bindgen::from_value(value).map_err(|err| {
+        EngineError::new(format!(
+            "The embedder must return an array of vectors: {}",
+            err
+        ))
+    })
+}
+
+fn js_error(value: JsValue) -> EngineError {
+    EngineError::new(
+        value
+            .as_string()
+            .unwrap_or_else(|| format!("The embedder failed: {:?}", value)),
+    )
+}
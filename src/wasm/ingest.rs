@@ -0,0 +1,137 @@
+use crate::{engine, wasm::*};
+
+use wasm_bindgen::prelude::*;
+
+/// Adds a resource to a collection a bounded number of vectors per `step`, so
+/// large imports can yield to the event loop between steps. The optional
+/// `on_progress(done, total)` callback is called after every step.
+#[wasm_bindgen]
+pub struct IngestJob {
+    job: engine::IngestJob,
+    collection: Option<String>,
+    on_progress: Option<js_sys::Function>,
+    // The number of resources, recorded as changes once the job is done.
+    changes: usize,
+}
+
+#[wasm_bindgen]
+impl IngestJob {
+    /// A job adding `resource` to a collection, like `LunaVDB::add`.
+    pub fn add(
+        resource: Resource,
+        collection: Option<String>,
+        on_progress: Option<js_sys::Function>,
+    ) -> IngestJob {
+        IngestJob {
+            changes: resource.embeddings.len(),
+            job: engine::IngestJob::add(resource.embeddings),
+            collection,
+            on_progress,
+        }
+    }
+
+    /// A job replacing the content of a collection, like `LunaVDB::index`.
+    /// Searches see the old content until the job is done.
+    pub fn index(
+        resource: Resource,
+        collection: Option<String>,
+        on_progress: Option<js_sys::Function>,
+    ) -> IngestJob {
+        IngestJob {
+            changes: resource.embeddings.len().max(1),
+            job: engine::IngestJob::index(resource.embeddings),
+            collection,
+            on_progress,
+        }
+    }
+
+    /// Processes at least `max_vectors` vectors into `db`, or the rest of the
+    /// resource. Returns whether the job is done, the resources count as
+    /// changes to `db` once it is.
+    ///
+    /// Throws when a vector fails to add, see `engine::IngestJob::step`.
+    pub fn step(&mut self, db: &mut LunaVDB, max_vectors: usize) -> Result<bool, JsValue> {
+        let job = &mut self.job;
+        let progress = db
+            .update_or_create(self.collection.to_owned(), |index| job.step(index, max_vectors))
+            .map_err(JsError::from)?;
+
+        if self.job.is_done() {
+            db.changed(self.changes);
+        }
+
+        report(self.on_progress.as_ref(), progress)?;
+        Ok(self.job.is_done())
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.job.progress()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.job.is_done()
+    }
+
+    /// Returns the ids evicted since the last call, when the collection has
+    /// size limits.
+    pub fn take_evicted(&mut self) -> Vec<String> {
+        self.job.take_evicted()
+    }
+}
+
+/// Restores a snapshot created by `LunaVDB::serialize` a bounded number of
+/// bytes per `step`. The optional `on_progress(done, total)` callback is
+/// called after every step with the bytes processed so far, see
+/// `engine::LoadJob`.
+#[wasm_bindgen]
+pub struct LoadJob {
+    job: engine::LoadJob,
+    on_progress: Option<js_sys::Function>,
+}
+
+#[wasm_bindgen]
+impl LoadJob {
+    #[wasm_bindgen(constructor)]
+    pub fn new(index: SerializedIndex, on_progress: Option<js_sys::Function>) -> LoadJob {
+        LoadJob {
+            job: engine::LoadJob::new(index),
+            on_progress,
+        }
+    }
+
+    /// Decompresses or decodes up to about `max_bytes` bytes of the snapshot.
+    /// Returns whether the job is done, throws if the snapshot is invalid.
+    pub fn step(&mut self, max_bytes: usize) -> Result<bool, JsValue> {
+        let progress = self.job.step(max_bytes).map_err(JsError::from)?;
+
+        report(self.on_progress.as_ref(), progress)?;
+        Ok(self.job.is_done())
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.job.progress()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.job.is_done()
+    }
+
+    /// Returns the restored database, running the remaining steps at once if
+    /// the job is not done yet.
+    pub fn finish(self) -> Result<LunaVDB, JsError> {
+        Ok(LunaVDB::from_collections(self.job.finish()?))
+    }
+}
+
+// Calls `on_progress`, passing on what it throws.
+fn report(on_progress: Option<&js_sys::Function>, progress: Progress) -> Result<(), JsValue> {
+    if let Some(on_progress) = on_progress {
+        on_progress.call2(
+            &JsValue::NULL,
+            &JsValue::from(progress.done),
+            &JsValue::from(progress.total),
+        )?;
+    }
+
+    Ok(())
+}
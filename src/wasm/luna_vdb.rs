@@ -1,12 +1,30 @@
-use crate::engine::Embedding;
+use crate::engine::{Embedding, IndexOptions, DEFAULT_COLLECTION};
 use crate::utils::set_panic_hook;
 use crate::{engine, wasm::*};
 
+use super::embedder::{self, Embedder, SharedEmbedder};
+use super::storage::{self, Persistence, StorageAdapter};
+use std::cell::{Ref, RefCell, RefMut};
+use std::rc::{Rc, Weak};
 use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::future_to_promise;
 
+const NOT_ATTACHED: &str = "No storage adapter is attached, call attach first";
+const NO_EMBEDDER: &str = "No embedder is set, call set_embedder first";
+
+/// A vector database holding one or more named collections. Methods taking a
+/// `collection` use the default collection when it is omitted.
 #[wasm_bindgen]
 pub struct LunaVDB {
-    index: engine::Index,
+    // Shared with the Promises of `add_texts` and `search_text`, which use the
+    // collections once the embedder is done.
+    state: Rc<RefCell<State>>,
+    embedder: Option<SharedEmbedder>,
+}
+
+struct State {
+    collections: engine::Collections,
+    persistence: Option<Persistence>,
 }
 
 #[wasm_bindgen]
@@ -20,56 +38,669 @@ impl LunaVDB {
             _ => Resource { embeddings: vec![] },
         };
 
-        let (data, ids) = resource
+        let mut collections = engine::Collections::new();
+        let index = engine::index(&resource.embeddings, IndexOptions::default()).unwrap();
+        collections.indexes.insert(DEFAULT_COLLECTION.to_string(), index);
+
+        LunaVDB::from_collections(collections)
+    }
+
+    /// Creates an empty database whose default collection uses `options`.
+    pub fn with_options(options: IndexOptions) -> LunaVDB {
+        set_panic_hook();
+
+        let mut collections = engine::Collections::new();
+        let index = engine::Index::with_options(options).unwrap();
+        collections.indexes.insert(DEFAULT_COLLECTION.to_string(), index);
+
+        LunaVDB::from_collections(collections)
+    }
+
+    /// Creates an empty collection. Throws if it already exists or the options
+    /// are invalid.
+    pub fn create_collection(
+        &mut self,
+        name: String,
+        options: Option<IndexOptions>,
+    ) -> Result<(), JsError> {
+        self.state
+            .borrow_mut()
+            .collections
+            .create(&name, options.unwrap_or_default())?;
+        self.changed(1);
+
+        Ok(())
+    }
+
+    /// Drops a collection. Throws if it is missing.
+    pub fn drop_collection(&mut self, name: String) -> Result<(), JsError> {
+        self.state.borrow_mut().collections.drop(&name)?;
+        self.changed(1);
+
+        Ok(())
+    }
+
+    pub fn has_collection(&self, name: String) -> bool {
+        self.state.borrow().collections.get(&name).is_some()
+    }
+
+    /// Returns the collection names in sorted order.
+    pub fn collections(&self) -> Vec<String> {
+        self.state.borrow().collections.names()
+    }
+
+    /// Replaces the content of a collection, keeping its options, or creates
+    /// it. Throws if the collection is frozen or an entry is invalid, leaving
+    /// the collection unchanged.
+    pub fn index(&mut self, resource: Resource, collection: Option<String>) -> Result<(), JsError> {
+        let name = collection.unwrap_or_else(|| DEFAULT_COLLECTION.to_string());
+
+        // Looked up without `collection_mut`, which fails for a missing
+        // collection.
+        let options = match self.state.borrow().collections.get(&name) {
+            Some(index) => {
+                index.check_mutable()?;
+                index.options.to_owned()
+            }
+            None => IndexOptions::default(),
+        };
+
+        let index = engine::index(&resource.embeddings, options)?;
+        self.state.borrow_mut().collections.indexes.insert(name, index);
+        self.changed(resource.embeddings.len().max(1));
+
+        Ok(())
+    }
+
+    pub fn search(&self, query: Embedding, k: TopK, collection: Option<String>) -> SearchResult {
+        match self.collection(collection) {
+            Some(index) => engine::search(&index, &query, k),
+            None => SearchResult { neighbors: vec![], explain: None },
+        }
+    }
+
+    /// Like `search`, also returning what `options` asks for with each
+    /// neighbour.
+    pub fn search_with(
+        &self,
+        query: Embedding,
+        k: TopK,
+        options: SearchOptions,
+        collection: Option<String>,
+    ) -> SearchResult {
+        match self.collection(collection) {
+            Some(index) => engine::search_with(&index, &query, k, options),
+            None => SearchResult { neighbors: vec![], explain: None },
+        }
+    }
+
+    /// Searches every query at once. Wasm builds search them one by one, the
+    /// `threads` feature only parallelizes native builds.
+    pub fn search_batch(
+        &self,
+        batch: BatchQuery,
+        k: TopK,
+        collection: Option<String>,
+    ) -> BatchSearchResult {
+        let results = match self.collection(collection) {
+            Some(index) => engine::search_batch(&index, &batch.queries, k),
+            None => batch
+                .queries
+                .iter()
+                .map(|_| SearchResult { neighbors: vec![], explain: None })
+                .collect(),
+        };
+
+        BatchSearchResult { results }
+    }
+
+    /// Runs every query through the collection and through an exact scan,
+    /// reporting the recall at `k`, the latencies and the distance
+    /// computations, to tune the index options.
+    pub fn evaluate(&self, batch: BatchQuery, k: TopK, collection: Option<String>) -> Evaluation {
+        match self.collection(collection) {
+            Some(index) => engine::evaluate(&index, &batch.queries, k),
+            None => engine::evaluate(&engine::Index::new(), &batch.queries, k),
+        }
+    }
+
+    /// Adds the entries of `resource`, all or nothing. Returns the ids evicted
+    /// to keep the collection within its `max_vectors` and `max_bytes` limits,
+    /// throws with the position of the failing entry.
+    pub fn add(
+        &mut self,
+        resource: Resource,
+        collection: Option<String>,
+    ) -> Result<Vec<String>, JsError> {
+        let operations = resource
             .embeddings
             .into_iter()
-            .map(|res| (res.embeddings, res.id))
-            .unzip();
+            .map(engine::Operation::Add)
+            .collect();
 
-        let index = engine::index(&data, &ids);
-        LunaVDB { index }
+        self.transact(Transaction { operations }, collection)
     }
 
-    pub fn index(&mut self, resource: Resource) {
-        let (data, ids) = resource
+    /// Adds the entries of `resource`, replacing entries with the same id, all
+    /// or nothing. Returns the ids evicted to keep the collection within its
+    /// limits, throws with the position of the failing entry.
+    pub fn upsert(
+        &mut self,
+        resource: Resource,
+        collection: Option<String>,
+    ) -> Result<Vec<String>, JsError> {
+        let operations = resource
             .embeddings
             .into_iter()
-            .map(|res| (res.embeddings, res.id))
-            .unzip();
+            .map(engine::Operation::Upsert)
+            .collect();
+
+        self.transact(Transaction { operations }, collection)
+    }
+
+    /// Applies the add, upsert and remove operations of `transaction` in
+    /// order, all or nothing. A failing operation rolls the collection back,
+    /// or removes it if the transaction created it, and throws with its
+    /// position. Returns the evicted ids.
+    pub fn transact(
+        &mut self,
+        transaction: Transaction,
+        collection: Option<String>,
+    ) -> Result<Vec<String>, JsError> {
+        let changes = transaction.operations.iter().map(engine::Operation::entries).sum();
+        let evicted = self.update_or_create(collection, |index| {
+            engine::transact(index, transaction.operations)
+        })?;
+
+        self.changed(changes);
+        Ok(evicted)
+    }
+
+    /// Returns the entry with `id`, with its vector, metadata and content.
+    pub fn get(&self, id: String, collection: Option<String>) -> Option<ExportRecord> {
+        self.collection(collection)
+            .and_then(|index| engine::get(&index, &id))
+    }
+
+    /// Registers the function embedding texts for `add_texts`, `search_text`,
+    /// `embed_texts` and `embed_query`. It is called with an array of texts
+    /// and returns one vector per text, or a Promise of them. Until one is
+    /// set, their Promises reject.
+    pub fn set_embedder(&mut self, embedder: js_sys::Function, options: Option<EmbedderOptions>) {
+        self.embedder = Some(Embedder::new(embedder, options.unwrap_or_default()));
+    }
+
+    /// Embeds the texts of `resource` with a sync or async embedder and adds
+    /// them all or nothing, keeping each text as the entry content. The
+    /// returned Promise resolves to the evicted ids, like `add`, once the
+    /// texts are added.
+    pub fn add_texts(
+        &mut self,
+        resource: TextResource,
+        collection: Option<String>,
+    ) -> js_sys::Promise {
+        let embedder = self.embedder();
+        let state = Rc::clone(&self.state);
+        let name = collection.unwrap_or_else(|| DEFAULT_COLLECTION.to_string());
+
+        future_to_promise(async move {
+            let texts = resource
+                .texts
+                .iter()
+                .map(|entry| entry.text.to_owned())
+                .collect();
+            let embeddings = embedder::embed(embedder?, texts)
+                .await
+                .map_err(|err| JsError::new(&err.message))?;
+
+            let changes = resource.texts.len();
+            let mut state = state.borrow_mut();
+            let evicted = state
+                .collections
+                .update_or_create(&name, |index| {
+                    engine::add_text_embeddings(index, resource.texts, embeddings)
+                })
+                .map_err(|err| JsError::new(&err.message))?;
+            state.changed(changes);
+
+            Ok(serde_wasm_bindgen::to_value(&evicted)?)
+        })
+    }
+
+    /// Embeds `query` with a sync or async embedder and searches for it. The
+    /// returned Promise resolves to a `SearchResult` with each neighbour's
+    /// content.
+    pub fn search_text(&self, query: String, k: TopK, collection: Option<String>) -> js_sys::Promise {
+        let embedder = self.embedder();
+        let state = Rc::clone(&self.state);
+        let name = collection.unwrap_or_else(|| DEFAULT_COLLECTION.to_string());
+        let options = SearchOptions {
+            with_content: true,
+            ..Default::default()
+        };
+
+        future_to_promise(async move {
+            let embedding = embedder::embed(embedder?, vec![query])
+                .await
+                .map_err(|err| JsError::new(&err.message))?
+                .remove(0);
+
+            let result = match state.borrow().collections.get(&name) {
+                Some(index) => engine::search_with(index, &embedding, k, options),
+                None => SearchResult { neighbors: vec![], explain: None },
+            };
+
+            Ok(serde_wasm_bindgen::to_value(&result)?)
+        })
+    }
+
+    /// Embeds the texts of `resource` with a sync or async embedder. The
+    /// returned Promise resolves to a `Resource` for `add` or `upsert`.
+    pub fn embed_texts(&self, resource: TextResource) -> js_sys::Promise {
+        let embedder = self.embedder();
+
+        future_to_promise(async move {
+            let texts = resource
+                .texts
+                .iter()
+                .map(|entry| entry.text.to_owned())
+                .collect();
+            let embeddings = embedder::embed(embedder?, texts)
+                .await
+                .map_err(|err| JsError::new(&err.message))?;
+            let resource = Resource {
+                embeddings: engine::text_resources(resource.texts, embeddings),
+            };
+
+            Ok(serde_wasm_bindgen::to_value(&resource)?)
+        })
+    }
+
+    /// Embeds `query` with a sync or async embedder. The returned Promise
+    /// resolves to a vector for `search`.
+    pub fn embed_query(&self, query: String) -> js_sys::Promise {
+        let embedder = self.embedder();
+
+        future_to_promise(async move {
+            let embedding = embedder::embed(embedder?, vec![query])
+                .await
+                .map_err(|err| JsError::new(&err.message))?
+                .remove(0);
+
+            Ok(serde_wasm_bindgen::to_value(&embedding)?)
+        })
+    }
+
+    pub fn remove(&mut self, ids: Vec<String>, collection: Option<String>) -> Result<(), JsError> {
+        engine::remove(&mut *self.collection_mut(collection)?, &ids)?;
+        self.changed(ids.len());
+
+        Ok(())
+    }
+
+    /// Removes the entries of a collection whose metadata matches `filter` and
+    /// returns their ids.
+    pub fn remove_where(
+        &mut self,
+        filter: MetadataFilter,
+        collection: Option<String>,
+    ) -> Result<Vec<String>, JsError> {
+        let removed = engine::remove_where(&mut *self.collection_mut(collection)?, &filter)?;
+
+        if !removed.is_empty() {
+            self.changed(removed.len());
+        }
+
+        Ok(removed)
+    }
+
+    /// Removes the entries of a collection that expired at or before `now`,
+    /// the current time by default, and returns their ids.
+    pub fn purge_expired(
+        &mut self,
+        now: Option<f64>,
+        collection: Option<String>,
+    ) -> Result<Vec<String>, JsError> {
+        let now = now.unwrap_or_else(engine::now);
+
+        let removed = engine::purge_expired(&mut *self.collection_mut(collection)?, now)?;
+
+        if !removed.is_empty() {
+            self.changed(removed.len());
+        }
+
+        Ok(removed)
+    }
 
-        let index = engine::index(&data, &ids);
-        self.index = index
+    pub fn clear(&mut self, collection: Option<String>) -> Result<(), JsError> {
+        let mut index = self.collection_mut(collection)?;
+        let changes = engine::size(&index).max(1);
+        engine::clear(&mut index)?;
+        drop(index);
+        self.changed(changes);
+
+        Ok(())
+    }
+
+    /// Rebuilds the index of a collection, restoring balanced searches after
+    /// many `add` and `remove` calls.
+    pub fn optimize(&mut self, collection: Option<String>) -> Result<(), JsError> {
+        engine::optimize(&mut *self.collection_mut(collection)?);
+        self.changed(1);
+
+        Ok(())
+    }
+
+    /// Freezes a collection into an immutable, query-optimized index. Adding,
+    /// removing, clearing or importing into it afterwards fails.
+    pub fn freeze(&mut self, collection: Option<String>) -> Result<(), JsError> {
+        engine::freeze(&mut *self.collection_mut(collection)?);
+        self.changed(1);
+
+        Ok(())
+    }
+
+    pub fn is_frozen(&self, collection: Option<String>) -> bool {
+        self.collection(collection)
+            .is_some_and(|index| index.is_frozen())
+    }
+
+    pub fn size(&self, collection: Option<String>) -> usize {
+        self.collection(collection)
+            .map(|index| engine::size(&index))
+            .unwrap_or(0)
+    }
+
+    /// Returns the sizes, estimated memory usage and tree shape of a
+    /// collection.
+    pub fn stats(&self, collection: Option<String>) -> IndexStats {
+        match self.collection(collection) {
+            Some(index) => engine::stats(&index),
+            None => engine::stats(&engine::Index::new()),
+        }
+    }
+
+    /// Returns the most vectors a collection can hold, see
+    /// `IndexOptions::capacity`.
+    pub fn max_capacity(&self, collection: Option<String>) -> usize {
+        match self.collection(collection) {
+            Some(index) => engine::max_capacity(&index),
+            None => engine::max_capacity(&engine::Index::new()),
+        }
+    }
+
+    /// Returns an independent copy of the database. Collections are shared
+    /// until either side writes to them, so forking is cheap and a fork can
+    /// be used for changes that may be discarded. The embedder is shared, the
+    /// fork is not attached to the storage adapter.
+    pub fn fork(&self) -> LunaVDB {
+        LunaVDB {
+            embedder: self.embedder.clone(),
+            ..LunaVDB::from_collections(self.state.borrow().collections.clone())
+        }
+    }
+
+    /// Returns a read-only view of the current content. Writes made after the
+    /// call do not show in the snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.state.borrow().collections.clone())
+    }
+
+    /// Opens the database saved under `key` by `adapter`, or an empty one if
+    /// nothing is saved yet, and attaches it to the adapter.
+    pub async fn open(
+        adapter: StorageAdapter,
+        key: String,
+        options: Option<AutosaveOptions>,
+    ) -> Result<LunaVDB, JsValue> {
+        set_panic_hook();
+
+        let collections = match storage::load(&adapter, &key).await? {
+            Some(data) => {
+                engine::load_collections(&data).map_err(|err| JsError::new(&err.message))?
+            }
+            None => engine::Collections::new(),
+        };
+
+        let mut db = LunaVDB::from_collections(collections);
+        db.attach(adapter, key, options);
+
+        Ok(db)
+    }
+
+    /// Saves the database under `key` with `adapter` from now on. Changes are
+    /// saved in the background once `options.max_changes` changed entries are
+    /// unsaved or `options.interval` milliseconds after the last save, and on
+    /// `flush`.
+    pub fn attach(&mut self, adapter: StorageAdapter, key: String, options: Option<AutosaveOptions>) {
+        let state = Rc::downgrade(&self.state);
+        let defer = move |delay| State::defer(Weak::clone(&state), delay);
+
+        self.state.borrow_mut().persistence =
+            Some(Persistence::new(adapter, key, options.unwrap_or_default(), defer));
+    }
+
+    /// Saves the database now. The returned Promise resolves once it and the
+    /// pending background saves are written. Throws if no storage adapter is
+    /// attached.
+    pub fn flush(&mut self) -> Result<js_sys::Promise, JsError> {
+        let State {
+            collections,
+            persistence,
+        } = &mut *self.state.borrow_mut();
+
+        match persistence.as_mut() {
+            Some(persistence) => Ok(persistence.flush(collections)),
+            None => Err(JsError::new(NOT_ATTACHED)),
+        }
+    }
+
+    /// Deletes the saved database. The next save writes it again. Throws if
+    /// no storage adapter is attached.
+    pub fn delete_saved(&mut self) -> Result<js_sys::Promise, JsError> {
+        match self.state.borrow_mut().persistence.as_mut() {
+            Some(persistence) => Ok(persistence.delete()),
+            None => Err(JsError::new(NOT_ATTACHED)),
+        }
+    }
+
+    /// The number of changed entries not saved yet. Changes count until the
+    /// adapter finished writing them, a failed save counts them again and is
+    /// retried without waiting for another change.
+    pub fn unsaved_changes(&self) -> usize {
+        self.state
+            .borrow()
+            .persistence
+            .as_ref()
+            .map_or(0, |persistence| persistence.unsaved_changes())
     }
 
-    pub fn search(&self, query: Embedding, k: TopK) -> SearchResult {
-        engine::search(&self.index, &query, k)
+    /// Serializes every collection, or only the given one. Throws if the
+    /// given collection is missing.
+    pub fn serialize(&mut self, collection: Option<String>) -> Result<SerializedIndex, JsError> {
+        let collections = &self.state.borrow().collections;
+
+        Ok(engine::dump_collections(collections, collection.as_deref())?)
+    }
+
+    /// Restores a database from `serialize`. Throws if the data is corrupt or
+    /// written by a newer version.
+    pub fn deserialize(index: SerializedIndex) -> Result<LunaVDB, JsError> {
+        Ok(LunaVDB::from_collections(engine::load_collections(&index)?))
     }
 
-    pub fn add(&mut self, resource: Resource) {
-        for res in resource.embeddings {
-            engine::add(&mut self.index, res.id, &res.embeddings).unwrap()
+    /// Returns up to `limit` entries in id order, starting after `cursor`.
+    /// Pass the returned cursor to fetch the next page, the scan is done once
+    /// it is unset. Adding or removing entries between pages is safe. Throws
+    /// if `limit` is 0.
+    pub fn scan(
+        &self,
+        cursor: Option<String>,
+        limit: usize,
+        options: Option<ScanOptions>,
+        collection: Option<String>,
+    ) -> Result<ScanPage, JsError> {
+        let options = options.unwrap_or_default();
+
+        let page = match self.collection(collection) {
+            Some(index) => engine::scan(&index, cursor.as_deref(), limit, options)?,
+            None => engine::scan(&engine::Index::new(), None, limit, options)?,
+        };
+
+        Ok(page)
+    }
+
+    /// Exports every entry as JSON Lines, one `{id, vector, metadata}` object
+    /// per line, sorted by id.
+    pub fn export_jsonl(&self, collection: Option<String>) -> Result<String, JsError> {
+        match self.collection(collection) {
+            Some(index) => Ok(engine::export_jsonl(&index)?),
+            None => Ok(String::new()),
         }
     }
 
-    pub fn remove(&mut self, ids: Vec<String>) {
-        engine::remove(&mut self.index, &ids).unwrap();
+    /// Adds the entries of a JSON Lines export to the index.
+    pub fn import_jsonl(
+        &mut self,
+        data: String,
+        collection: Option<String>,
+    ) -> Result<(), JsError> {
+        engine::import_jsonl(&mut *self.collection_mut(collection)?, &data)?;
+        self.changed(data.lines().filter(|line| !line.trim().is_empty()).count());
+
+        Ok(())
     }
 
-    pub fn clear(&mut self) {
-        engine::clear(&mut self.index);
+    /// Exports the vectors as a float32 `.npy` matrix. Row `i` belongs to the
+    /// `i`-th id returned by `export_ids`.
+    pub fn export_npy(&self, collection: Option<String>) -> Vec<u8> {
+        match self.collection(collection) {
+            Some(index) => engine::export_npy(&index).0,
+            None => engine::export_npy(&engine::Index::new()).0,
+        }
     }
 
-    pub fn size(&self) -> usize {
-        engine::size(&self.index)
+    /// Returns the ids in the row order of `export_npy`.
+    pub fn export_ids(&self, collection: Option<String>) -> Vec<String> {
+        self.collection(collection)
+            .map(|index| engine::export_ids(&index))
+            .unwrap_or_default()
     }
 
-    pub fn serialize(&mut self) -> SerializedIndex {
-        engine::dump(&mut self.index).unwrap()
+    /// Adds the rows of a float32 `.npy` matrix, using `ids` as the sidecar ids
+    /// in row order.
+    pub fn import_npy(
+        &mut self,
+        matrix: Vec<u8>,
+        ids: Vec<String>,
+        collection: Option<String>,
+    ) -> Result<(), JsError> {
+        engine::import_npy(&mut *self.collection_mut(collection)?, &matrix, &ids)?;
+        self.changed(ids.len());
+
+        Ok(())
+    }
+}
+
+impl LunaVDB {
+    pub(crate) fn from_collections(collections: engine::Collections) -> LunaVDB {
+        let state = State {
+            collections,
+            persistence: None,
+        };
+
+        LunaVDB {
+            state: Rc::new(RefCell::new(state)),
+            embedder: None,
+        }
     }
 
-    pub fn deserialize(index: SerializedIndex) -> LunaVDB {
-        let index = engine::load(&index);
+    /// Records `changes` changed entries for the autosave of the attached
+    /// storage adapter.
+    pub(crate) fn changed(&mut self, changes: usize) {
+        self.state.borrow_mut().changed(changes);
+    }
+
+    // Fails when no embedder is set, the Promise awaiting it rejects.
+    fn embedder(&self) -> Result<SharedEmbedder, JsValue> {
+        self.embedder
+            .to_owned()
+            .ok_or_else(|| JsError::new(NO_EMBEDDER).into())
+    }
 
-        LunaVDB { index }
+    fn collection(&self, name: Option<String>) -> Option<Ref<'_, engine::Index>> {
+        let name = name.as_deref().unwrap_or(DEFAULT_COLLECTION);
+
+        Ref::filter_map(self.state.borrow(), |state| state.collections.get(name)).ok()
+    }
+
+    // Returns the collection for writing, failing when it is missing so a
+    // misspelled name is not created by a remove or an import.
+    fn collection_mut(
+        &mut self,
+        name: Option<String>,
+    ) -> Result<RefMut<'_, engine::Index>, engine::EngineError> {
+        let name = name.as_deref().unwrap_or(DEFAULT_COLLECTION);
+        let mut state = self.state.borrow_mut();
+        state.collections.find_mut(name)?;
+
+        Ok(RefMut::map(state, |state| state.collections.get_or_create(name)))
+    }
+
+    // Runs `update` on the collection for adding entries, creating it with
+    // default options when missing and removing it again if `update` fails.
+    pub(crate) fn update_or_create<T, E>(
+        &mut self,
+        name: Option<String>,
+        update: impl FnOnce(&mut engine::Index) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let name = name.as_deref().unwrap_or(DEFAULT_COLLECTION);
+
+        self.state
+            .borrow_mut()
+            .collections
+            .update_or_create(name, update)
+    }
+}
+
+impl State {
+    fn changed(&mut self, changes: usize) {
+        if let Some(persistence) = self.persistence.as_mut() {
+            persistence.changed(&self.collections, changes);
+        }
+    }
+
+    // Calls `Persistence::deferred` after `delay` milliseconds, unless the
+    // database is gone by then.
+    fn defer(state: Weak<RefCell<State>>, delay: f64) {
+        storage::defer(delay, move || {
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+
+            let State {
+                collections,
+                persistence,
+            } = &mut *state.borrow_mut();
+
+            if let Some(persistence) = persistence.as_mut() {
+                persistence.deferred(collections);
+            }
+        });
+    }
+}
+
+#[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+impl LunaVDB {
+    /// Writes a collection as a read-only snapshot for `MmapIndex::open`.
+    pub fn write_mmap<P: AsRef<std::path::Path>>(
+        &self,
+        path: P,
+        collection: Option<String>,
+    ) -> Result<(), engine::EngineError> {
+        match self.collection(collection) {
+            Some(index) => engine::write_mmap(&index, path),
+            None => engine::write_mmap(&engine::Index::new(), path),
+        }
     }
 }
@@ -1,5 +1,12 @@
 mod types;
+mod embedder;
+mod ingest;
 mod luna_vdb;
+mod snapshot;
+mod storage;
 
 pub use types::*;
-pub use luna_vdb::*;
\ No newline at end of file
+pub use ingest::*;
+pub use luna_vdb::*;
+pub use snapshot::*;
+pub use storage::StorageAdapter;
\ No newline at end of file
@@ -0,0 +1,66 @@
+use crate::engine::{Embedding, DEFAULT_COLLECTION};
+use crate::{engine, wasm::*};
+
+use wasm_bindgen::prelude::*;
+
+/// A read-only view of a `LunaVDB` as it was when `LunaVDB::snapshot` was
+/// called. It shares the collections with the database, later writes to the
+/// database copy the collections they touch instead of changing the snapshot.
+///
+/// Searches on a snapshot do not count as retrievals for the `lru` and `lfu`
+/// eviction policies of the database.
+#[wasm_bindgen]
+pub struct Snapshot {
+    collections: engine::Collections,
+}
+
+#[wasm_bindgen]
+impl Snapshot {
+    /// Returns the collection names in sorted order.
+    pub fn collections(&self) -> Vec<String> {
+        self.collections.names()
+    }
+
+    pub fn size(&self, collection: Option<String>) -> usize {
+        self.collection(collection).map(engine::size).unwrap_or(0)
+    }
+
+    pub fn search(&self, query: Embedding, k: TopK, collection: Option<String>) -> SearchResult {
+        self.search_with(query, k, SearchOptions::default(), collection)
+    }
+
+    pub fn search_with(
+        &self,
+        query: Embedding,
+        k: TopK,
+        options: SearchOptions,
+        collection: Option<String>,
+    ) -> SearchResult {
+        match self.collection(collection) {
+            Some(index) => engine::peek_with(index, &query, k, options),
+            None => SearchResult { neighbors: vec![], explain: None },
+        }
+    }
+
+    pub fn get(&self, id: String, collection: Option<String>) -> Option<ExportRecord> {
+        self.collection(collection)
+            .and_then(|index| engine::get(index, &id))
+    }
+
+    /// Serializes every collection, or only the given one, for
+    /// `LunaVDB::deserialize`. Throws if the given collection is missing.
+    pub fn serialize(&self, collection: Option<String>) -> Result<SerializedIndex, JsError> {
+        Ok(engine::dump_collections(&self.collections, collection.as_deref())?)
+    }
+}
+
+impl Snapshot {
+    pub(crate) fn new(collections: engine::Collections) -> Snapshot {
+        Snapshot { collections }
+    }
+
+    fn collection(&self, name: Option<String>) -> Option<&engine::Index> {
+        self.collections
+            .get(name.as_deref().unwrap_or(DEFAULT_COLLECTION))
+    }
+}
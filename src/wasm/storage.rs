@@ -0,0 +1,226 @@
+use crate::engine;
+use std::{cell::Cell, future::Future, rc::Rc};
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+
+#[wasm_bindgen(typescript_custom_section)]
+const STORAGE_ADAPTER: &'static str = r#"
+/** Persists database snapshots, see `LunaVDB.open`. Every method may return a Promise. */
+export interface StorageAdapter {
+    read(key: string): Uint8Array | undefined | null | Promise<Uint8Array | undefined | null>;
+    write(key: string, data: Uint8Array): void | Promise<void>;
+    delete(key: string): void | Promise<void>;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "StorageAdapter")]
+    #[derive(Clone)]
+    pub type StorageAdapter;
+
+    #[wasm_bindgen(method, catch)]
+    fn read(this: &StorageAdapter, key: &str) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn write(
+        this: &StorageAdapter,
+        key: &str,
+        data: &js_sys::Uint8Array,
+    ) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(method, catch)]
+    fn delete(this: &StorageAdapter, key: &str) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(handler: &js_sys::Function, delay: f64) -> JsValue;
+}
+
+// How long a failed save waits before it is retried when no autosave interval
+// is set.
+const RETRY_DELAY: f64 = 1000.0;
+
+/// The adapter a `LunaVDB` saves to, see `LunaVDB::attach`.
+pub(crate) struct Persistence {
+    adapter: StorageAdapter,
+    key: String,
+    autosave: engine::Autosave,
+    // Settles once the last scheduled task did, without rejecting. Saves and
+    // deletes wait for it so they reach the adapter in order.
+    last: Option<js_sys::Promise>,
+    // The changes of the saves that haven't settled yet.
+    saving: Rc<Cell<usize>>,
+    // The changes of failed saves, counted again and saved by the next check.
+    failed: Rc<Cell<usize>>,
+    // Whether a call to `deferred` is scheduled.
+    deferred: Rc<Cell<bool>>,
+    // Schedules a call to `deferred` after the given delay in milliseconds.
+    defer: Rc<dyn Fn(f64)>,
+}
+
+impl Persistence {
+    pub(crate) fn new(
+        adapter: StorageAdapter,
+        key: String,
+        options: engine::AutosaveOptions,
+        defer: impl Fn(f64) + 'static,
+    ) -> Self {
+        Persistence {
+            adapter,
+            key,
+            autosave: engine::Autosave::new(options, engine::now()),
+            last: None,
+            saving: Rc::new(Cell::new(0)),
+            failed: Rc::new(Cell::new(0)),
+            deferred: Rc::new(Cell::new(false)),
+            defer: Rc::new(defer),
+        }
+    }
+
+    /// The changes not saved yet, including those of saves still being
+    /// written.
+    pub(crate) fn unsaved_changes(&self) -> usize {
+        self.autosave.changes() + self.saving.get() + self.failed.get()
+    }
+
+    /// Records `changes` changes to `collections` and starts saving them in
+    /// the background if the autosave options call for it, or if a save
+    /// failed. Changes the autosave interval doesn't save yet are checked
+    /// again by a call to `deferred` once it passes, so they are saved when
+    /// no further change comes.
+    pub(crate) fn changed(&mut self, collections: &engine::Collections, changes: usize) {
+        let failed = self.failed.replace(0);
+        self.autosave.record(changes + failed);
+
+        if failed > 0 || self.autosave.is_due(engine::now()) {
+            // Serializing only fails on allocation errors, `flush` reports it.
+            // The Promise is awaited by the next task, a failure is retried.
+            if let Ok(data) = engine::dump_collections(collections, None) {
+                let _ = self.save(data);
+            }
+        }
+
+        if let Some(delay) = self.autosave.due_in(engine::now()) {
+            arm(&self.deferred, &self.defer, delay);
+        }
+    }
+
+    /// Saves the changes left when a delay scheduled by `changed` or by a
+    /// failed save passed.
+    pub(crate) fn deferred(&mut self, collections: &engine::Collections) {
+        if self.deferred.replace(false) {
+            self.changed(collections, 0);
+        }
+    }
+
+    /// Saves `collections` after the pending saves. The returned Promise
+    /// rejects if this save fails.
+    pub(crate) fn flush(&mut self, collections: &engine::Collections) -> js_sys::Promise {
+        match engine::dump_collections(collections, None) {
+            Ok(data) => self.save(data),
+            Err(err) => js_sys::Promise::reject(&JsError::new(&err.message).into()),
+        }
+    }
+
+    /// Deletes the saved snapshot after the pending saves.
+    pub(crate) fn delete(&mut self) -> js_sys::Promise {
+        let adapter = self.adapter.to_owned();
+        let key = self.key.to_owned();
+
+        self.schedule(None, async move { resolve(adapter.delete(&key)?).await })
+    }
+
+    // Writes `data` once the pending saves are done. The changes count as
+    // unsaved until the write succeeds, later changes count towards the next
+    // save.
+    fn save(&mut self, data: Vec<u8>) -> js_sys::Promise {
+        let adapter = self.adapter.to_owned();
+        let key = self.key.to_owned();
+
+        let changes = self.autosave.changes() + self.failed.replace(0);
+        self.autosave.saved(engine::now());
+        self.saving.set(self.saving.get() + changes);
+
+        self.schedule(Some(changes), async move {
+            let data = js_sys::Uint8Array::from(&data[..]);
+
+            resolve(adapter.write(&key, &data)?).await
+        })
+    }
+
+    // Runs `task` after the previous one. Returns its Promise, and keeps a
+    // never rejecting one for the next task. Once a save of `changes`
+    // settles they no longer count as being saved, a failed save counts them
+    // as failed and schedules a retry.
+    fn schedule(
+        &mut self,
+        changes: Option<usize>,
+        task: impl Future<Output = Result<JsValue, JsValue>> + 'static,
+    ) -> js_sys::Promise {
+        let previous = self.last.take();
+        let promise = future_to_promise(async move {
+            if let Some(previous) = previous {
+                JsFuture::from(previous).await?;
+            }
+
+            task.await
+        });
+
+        let task = promise.to_owned();
+        let saving = Rc::clone(&self.saving);
+        let failed = Rc::clone(&self.failed);
+        let deferred = Rc::clone(&self.deferred);
+        let defer = Rc::clone(&self.defer);
+        let delay = self.autosave.options().interval.unwrap_or(RETRY_DELAY);
+
+        self.last = Some(future_to_promise(async move {
+            let result = JsFuture::from(task).await;
+
+            if let Some(changes) = changes {
+                saving.set(saving.get() - changes);
+
+                if result.is_err() {
+                    failed.set(failed.get() + changes);
+                    arm(&deferred, &defer, delay);
+                }
+            }
+
+            Ok(JsValue::UNDEFINED)
+        }));
+
+        promise
+    }
+}
+
+// Schedules a call to `Persistence::deferred` unless one is already.
+fn arm(deferred: &Cell<bool>, defer: &Rc<dyn Fn(f64)>, delay: f64) {
+    if !deferred.replace(true) {
+        defer(delay);
+    }
+}
+
+/// Calls `callback` after `delay` milliseconds.
+pub(crate) fn defer(delay: f64, callback: impl FnOnce() + 'static) {
+    let callback = Closure::once_into_js(callback);
+
+    set_timeout(callback.unchecked_ref(), delay);
+}
+
+/// Reads the snapshot stored under `key`, if any.
+pub(crate) async fn load(adapter: &StorageAdapter, key: &str) -> Result<Option<Vec<u8>>, JsValue> {
+    let value = resolve(adapter.read(key)?).await?;
+
+    match value.is_undefined() || value.is_null() {
+        true => Ok(None),
+        false => Ok(Some(js_sys::Uint8Array::new(&value).to_vec())),
+    }
+}
+
+// Awaits `value` if it is a Promise.
+async fn resolve(value: JsValue) -> Result<JsValue, JsValue> {
+    match value.dyn_into::<js_sys::Promise>() {
+        Ok(promise) => JsFuture::from(promise).await,
+        Err(value) => Ok(value),
+    }
+}
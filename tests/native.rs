@@ -0,0 +1,2238 @@
+//! Test suite for the native (non-wasm) build.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+use luna_vdb::engine::{self, EmbeddedResource, IndexKind, IndexOptions, Metric};
+use std::sync::Arc;
+
+#[allow(dead_code)]
+fn temp_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("luna-vdb-{}-{}", std::process::id(), name))
+}
+
+fn animals() -> Vec<EmbeddedResource> {
+    vec![
+        EmbeddedResource {
+            id: "cat".to_string(),
+            embeddings: vec![0.8, 0.7, 0.6, 0.2, 0.1],
+            ..Default::default()
+        },
+        EmbeddedResource {
+            id: "dog".to_string(),
+            embeddings: vec![0.7, 0.8, 0.6, 0.3, 0.1],
+            ..Default::default()
+        },
+        EmbeddedResource {
+            id: "car".to_string(),
+            embeddings: vec![-0.1, -0.2, -0.3, -0.8, -0.9],
+            ..Default::default()
+        },
+    ]
+}
+
+#[test]
+fn test_engine_api() {
+    let mut index = engine::index(&animals(), IndexOptions::default()).unwrap();
+    assert_eq!(engine::size(&index), 3);
+
+    let result = engine::search(&index, &[0.8, 0.7, 0.6, 0.2, 0.1], 2);
+    assert_eq!(result.neighbors[0].id, "cat");
+    assert_eq!(result.neighbors[1].id, "dog");
+
+    // 重复 id 与不存在的 id 返回错误而不是 panic
+    assert!(engine::add(&mut index, animals().remove(0)).is_err());
+    assert!(engine::remove(&mut index, &["bird".to_string()]).is_err());
+    assert_eq!(engine::size(&index), 3);
+
+    engine::remove(&mut index, &["cat".to_string()]).unwrap();
+    assert_eq!(engine::size(&index), 2);
+
+    let restored = engine::load(&engine::dump(&index).unwrap()).unwrap();
+    assert_eq!(engine::size(&restored), 2);
+    assert!(engine::load(&[1, 2, 3]).is_err());
+}
+
+#[test]
+fn test_engine_import_errors() {
+    let options = IndexOptions {
+        dimension: Some(2),
+        ..Default::default()
+    };
+    let mut index = engine::Index::with_options(options).unwrap();
+
+    // 第二行维度错误时整个导入回滚
+    let jsonl = "{\"id\":\"a\",\"vector\":[1.0,2.0]}\n\n{\"id\":\"b\",\"vector\":[1.0]}\n";
+    let error = engine::import_jsonl(&mut index, jsonl).unwrap_err();
+    assert!(error.to_string().contains("line 3"));
+    assert_eq!(engine::size(&index), 0);
+
+    let mut index = engine::Index::new();
+    let animals = engine::index(&animals(), IndexOptions::default()).unwrap();
+    let (matrix, ids) = engine::export_npy(&animals);
+    engine::import_npy(&mut index, &matrix, &ids).unwrap();
+    assert_eq!(engine::size(&index), 3);
+
+    // 已存在的 id 同样不会留下部分导入
+    let mut ids = ids;
+    ids[0] = "new".to_string();
+    assert!(engine::import_npy(&mut index, &matrix, &ids).is_err());
+    assert_eq!(engine::size(&index), 3);
+
+    // 头部长度和形状溢出时返回错误而不是 panic
+    let mut header = b"\x93NUMPY\x02\x00".to_vec();
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    assert!(engine::import_npy(&mut index, &header, &[]).is_err());
+
+    let shape = "{'descr': '<f4', 'fortran_order': False, 'shape': (4611686018427387904, 4), }";
+    let mut matrix = b"\x93NUMPY\x01\x00".to_vec();
+    matrix.extend_from_slice(&(shape.len() as u16).to_le_bytes());
+    matrix.extend_from_slice(shape.as_bytes());
+    assert!(engine::import_npy(&mut index, &matrix, &[]).is_err());
+}
+
+#[test]
+fn test_engine_snapshot_format() {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let index = engine::index(&animals(), IndexOptions::default()).unwrap();
+    let data = engine::dump(&index).unwrap();
+
+    // 快照以魔数和格式版本开头
+    assert_eq!(&data[..4], b"LVDB");
+    assert_eq!(&data[4..8], &engine::FORMAT_VERSION.to_le_bytes());
+
+    let mut future = data.clone();
+    future[4..8].copy_from_slice(&(engine::FORMAT_VERSION + 1).to_le_bytes());
+    let error = engine::load(&future).unwrap_err();
+    assert!(error.to_string().contains("Unsupported snapshot format version"));
+    assert!(engine::load_collections(&future).is_err());
+
+    // 没有头部的旧版快照会被迁移到默认集合
+    #[derive(serde::Serialize)]
+    struct LegacyIndex {
+        tree: kiddo::float::kdtree::KdTree<f32, u64, 2048, 32, u16>,
+        hash: std::collections::HashMap<u64, String>,
+    }
+
+    let mut legacy = LegacyIndex {
+        tree: kiddo::float::kdtree::KdTree::with_capacity(100),
+        hash: Default::default(),
+    };
+    for resource in animals().into_iter().chain(pseudo_random(100, 5)) {
+        let mut point = [0.0; 2048];
+        point[..5].copy_from_slice(&resource.embeddings);
+        legacy.tree.add(&point, engine::hash(&resource.id));
+        legacy.hash.insert(engine::hash(&resource.id), resource.id);
+    }
+
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(&bincode::serialize(&legacy).unwrap()).unwrap();
+    let legacy = encoder.finish().unwrap();
+
+    let query = [0.8, 0.7, 0.6, 0.2, 0.1];
+    let migrated = engine::load(&legacy).unwrap();
+    assert_eq!(engine::size(&migrated), 103);
+    assert_eq!(migrated.dimension, 5);
+    assert_eq!(engine::search(&migrated, &query, 1).neighbors[0].id, "cat");
+
+    let collections = engine::load_collections(&legacy).unwrap();
+    assert_eq!(collections.names(), vec!["default"]);
+    assert_eq!(engine::size(&collections.indexes["default"]), 103);
+
+    let mut job = engine::LoadJob::new(legacy.clone());
+    while !job.is_done() {
+        job.step(4096).unwrap();
+    }
+    assert_eq!(engine::size(&job.finish().unwrap().indexes["default"]), 103);
+
+    assert!(engine::load(&legacy[..legacy.len() / 2]).is_err());
+    assert!(engine::LoadJob::new(b"garbage".to_vec()).finish().is_err());
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mmap_search() {
+    let index = engine::index(&animals(), IndexOptions::default()).unwrap();
+
+    let path = temp_path("search.bin");
+    engine::write_mmap(&index, &path).unwrap();
+
+    let mmap = engine::MmapIndex::open(&path).unwrap();
+    assert_eq!(mmap.size(), 3);
+    assert_eq!(mmap.dimension(), 5);
+
+    // 与内存中的 kd-tree 结果一致
+    let query = [0.8, 0.7, 0.6, 0.2, 0.1];
+    let result = mmap.search(&query, 3);
+    assert_eq!(result, engine::search(&index, &query, 3));
+    assert_eq!(result.neighbors[0].id, "cat");
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mmap_invalid_file() {
+    let path = temp_path("invalid.bin");
+    std::fs::write(&path, b"not a snapshot").unwrap();
+
+    assert!(engine::MmapIndex::open(&path).is_err());
+
+    // 头部中溢出的数量返回错误而不是 panic
+    let mut header = b"LVDBMMAP".to_vec();
+    header.extend_from_slice(&1u32.to_le_bytes());
+    header.extend_from_slice(&2048u32.to_le_bytes());
+    header.extend_from_slice(&u64::MAX.to_le_bytes());
+    std::fs::write(&path, &header).unwrap();
+
+    assert!(engine::MmapIndex::open(&path).is_err());
+
+    // 倒退、越界或切开字符的 id 偏移在打开时就返回错误
+    let snapshot = |offsets: [u64; 3], ids: &[u8]| {
+        let mut data = b"LVDBMMAP".to_vec();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&2u64.to_le_bytes());
+        data.extend_from_slice(&1f32.to_le_bytes());
+        data.extend_from_slice(&2f32.to_le_bytes());
+        offsets.iter().for_each(|offset| data.extend_from_slice(&offset.to_le_bytes()));
+        data.extend_from_slice(ids);
+        data
+    };
+
+    std::fs::write(&path, snapshot([0, 1, 2], b"ab")).unwrap();
+    let index = engine::MmapIndex::open(&path).unwrap();
+    assert_eq!((index.id(0), index.id(1)), ("a", "b"));
+    drop(index);
+
+    let invalid = [
+        snapshot([0, 2, 1], b"ab"),
+        snapshot([1, 1, 2], b"ab"),
+        snapshot([0, 1, 3], b"ab"),
+        snapshot([0, 1, 2], b"\xff\xff"),
+        snapshot([0, 1, 2], "é".as_bytes()),
+    ];
+
+    for data in invalid {
+        std::fs::write(&path, data).unwrap();
+        assert!(engine::MmapIndex::open(&path).is_err());
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mmap_skips_expired() {
+    let mut index = engine::index(&animals(), IndexOptions::default()).unwrap();
+    let expired = EmbeddedResource {
+        id: "expired".to_string(),
+        embeddings: vec![0.5, 0.5],
+        expires_at: Some(1.0),
+        ..Default::default()
+    };
+    engine::add(&mut index, expired).unwrap();
+
+    // 未过期的条目保留过期时间，搜索在过期后跳过它们
+    let now = engine::now();
+    let expiring = [("later", now + 3_600_000.0), ("soon", now + 50.0)];
+    for (id, expires_at) in expiring {
+        let resource = EmbeddedResource {
+            id: id.to_string(),
+            embeddings: vec![0.8, 0.7, 0.6, 0.2, 0.1],
+            expires_at: Some(expires_at),
+            ..Default::default()
+        };
+        engine::add(&mut index, resource).unwrap();
+    }
+
+    // 已过期的条目不写入快照，和搜索一样跳过
+    let path = temp_path("expired.bin");
+    engine::write_mmap(&index, &path).unwrap();
+    let snapshot = engine::MmapIndex::open(&path).unwrap();
+
+    assert_eq!(snapshot.size(), engine::size(&index) - 1);
+    assert!((0..snapshot.size()).all(|position| snapshot.id(position) != "expired"));
+    let expires = (0..snapshot.size())
+        .filter_map(|position| Some((snapshot.id(position), snapshot.expires_at(position)?)))
+        .collect::<Vec<(&str, f64)>>();
+    assert_eq!(expires, expiring.to_vec());
+
+    let query = [0.8, 0.7, 0.6, 0.2, 0.1];
+    assert_eq!(snapshot.search(&query, 5).neighbors.len(), 5);
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    let ids = snapshot
+        .search(&query, 5)
+        .neighbors
+        .into_iter()
+        .map(|neighbor| neighbor.id)
+        .collect::<Vec<String>>();
+    assert_eq!(ids, vec!["cat", "later", "dog", "car"]);
+
+    drop(snapshot);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_engine_metrics() {
+    let resources = vec![
+        EmbeddedResource {
+            id: "short".to_string(),
+            embeddings: vec![1.0, 0.0],
+            ..Default::default()
+        },
+        EmbeddedResource {
+            id: "long".to_string(),
+            embeddings: vec![10.0, 1.0],
+            ..Default::default()
+        },
+    ];
+
+    // 欧氏距离下 short 更近，余弦距离只看方向所以 long 更近
+    let euclidean = engine::index(&resources, IndexOptions::default()).unwrap();
+    assert_eq!(engine::search(&euclidean, &[2.0, 0.3], 1).neighbors[0].id, "short");
+
+    let cosine = IndexOptions {
+        metric: Metric::Cosine,
+        ..Default::default()
+    };
+    let cosine = engine::index(&resources, cosine).unwrap();
+    let result = engine::search(&cosine, &[2.0, 0.2], 2);
+    assert_eq!(result.neighbors[0].id, "long");
+    assert!(result.neighbors[0].distance < 1e-6);
+
+    let manhattan = IndexOptions {
+        metric: Metric::Manhattan,
+        ..Default::default()
+    };
+    let manhattan = engine::index(&resources, manhattan).unwrap();
+    let result = engine::search(&manhattan, &[0.0, 0.0], 1);
+    assert_eq!(result.neighbors[0].id, "short");
+    assert_eq!(result.neighbors[0].distance, 1.0);
+}
+
+#[test]
+fn test_engine_dimension() {
+    let options = IndexOptions {
+        dimension: Some(5),
+        ..Default::default()
+    };
+    let mut index = engine::index(&animals(), options).unwrap();
+
+    let wrong = EmbeddedResource {
+        id: "bird".to_string(),
+        embeddings: vec![0.1, 0.2],
+        ..Default::default()
+    };
+    assert!(engine::add(&mut index, wrong).is_err());
+
+    let options = IndexOptions {
+        dimension: Some(0),
+        ..Default::default()
+    };
+    assert!(engine::Index::with_options(options).is_err());
+}
+
+// 确定性的伪随机向量，避免测试依赖随机数
+fn pseudo_random(count: usize, dim: usize) -> Vec<EmbeddedResource> {
+    let mut state = 42u32;
+    let mut next = move || {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        (state >> 8) as f32 / (1u32 << 24) as f32 * 2.0 - 1.0
+    };
+
+    (0..count)
+        .map(|i| EmbeddedResource {
+            id: i.to_string(),
+            embeddings: (0..dim).map(|_| next()).collect(),
+            ..Default::default()
+        })
+        .collect()
+}
+
+#[test]
+fn test_engine_ivf() {
+    let resources = pseudo_random(300, 16);
+    let exact = engine::index(&resources, IndexOptions::default()).unwrap();
+
+    // nprobe 等于 nlist 时扫描全部聚类，结果应与 kd-tree 一致
+    let options = IndexOptions {
+        kind: IndexKind::Ivf,
+        nlist: Some(8),
+        nprobe: Some(8),
+        ..Default::default()
+    };
+    let mut ivf = engine::index(&resources, options).unwrap();
+    assert_eq!(engine::size(&ivf), 300);
+
+    match ivf.tree.as_ref() {
+        engine::Tree::Ivf(tree) => {
+            assert!(tree.is_trained());
+            assert_eq!(tree.centroids.len(), 8);
+        }
+        _ => panic!("expected an ivf tree"),
+    }
+
+    for query in resources.iter().take(5) {
+        assert_eq!(
+            engine::search(&ivf, &query.embeddings, 10),
+            engine::search(&exact, &query.embeddings, 10)
+        );
+    }
+
+    // 训练后增量添加与删除
+    let extra = EmbeddedResource {
+        id: "extra".to_string(),
+        embeddings: vec![0.5; 16],
+        ..Default::default()
+    };
+    engine::add(&mut ivf, extra).unwrap();
+    assert_eq!(engine::search(&ivf, &[0.5; 16], 1).neighbors[0].id, "extra");
+
+    engine::remove(&mut ivf, &["extra".to_string(), "0".to_string()]).unwrap();
+    assert_eq!(engine::size(&ivf), 299);
+    assert_ne!(engine::search(&ivf, &[0.5; 16], 1).neighbors[0].id, "extra");
+
+    // 聚类中心和倒排列表随快照保存
+    let restored = engine::load(&engine::dump(&ivf).unwrap()).unwrap();
+    let query = &resources[1].embeddings;
+    assert_eq!(
+        engine::search(&restored, query, 5),
+        engine::search(&ivf, query, 5)
+    );
+}
+
+#[test]
+fn test_engine_bulk_build() {
+    let resources = pseudo_random(1000, 8);
+    let bulk = engine::index(&resources, IndexOptions::default()).unwrap();
+    assert_eq!(bulk.tree.size(), 1000);
+
+    // 一次性构建与逐条添加的搜索结果一致
+    let mut incremental = engine::Index::new();
+    for resource in &resources {
+        engine::add(&mut incremental, resource.to_owned()).unwrap();
+    }
+
+    for query in resources.iter().step_by(100) {
+        let result = engine::search(&bulk, &query.embeddings, 10);
+        assert_eq!(result.neighbors[0].id, query.id);
+        assert_eq!(result, engine::search(&incremental, &query.embeddings, 10));
+    }
+
+    // 多次添加删除后重建，结果不变
+    let ids = resources
+        .iter()
+        .take(500)
+        .map(|resource| resource.id.to_owned())
+        .collect::<Vec<String>>();
+    engine::remove(&mut incremental, &ids).unwrap();
+
+    let query = &resources[700].embeddings;
+    let before = engine::search(&incremental, query, 10);
+    engine::optimize(&mut incremental);
+    assert_eq!(engine::size(&incremental), 500);
+    assert_eq!(incremental.tree.size(), 500);
+    assert_eq!(engine::search(&incremental, query, 10), before);
+
+    // 重复的 id 仍然报错
+    let mut duplicated = resources[..2].to_vec();
+    duplicated.push(resources[0].to_owned());
+    assert!(engine::index(&duplicated, IndexOptions::default()).is_err());
+}
+
+#[test]
+fn test_engine_freeze() {
+    let resources = pseudo_random(500, 16);
+
+    for metric in [Metric::Euclidean, Metric::Manhattan, Metric::Cosine] {
+        let options = IndexOptions {
+            metric,
+            ..Default::default()
+        };
+        let index = engine::index(&resources, options).unwrap();
+        let mut frozen = index.clone();
+        engine::freeze(&mut frozen);
+        assert!(frozen.is_frozen());
+        assert_eq!(engine::size(&frozen), 500);
+
+        for query in resources.iter().step_by(50) {
+            let expected = engine::search(&index, &query.embeddings, 10);
+            let result = engine::search(&frozen, &query.embeddings, 10);
+            assert_eq!(result.neighbors.len(), 10);
+
+            for (a, b) in result.neighbors.iter().zip(&expected.neighbors) {
+                assert_eq!(a.id, b.id);
+                assert!((a.distance - b.distance).abs() < 1e-5);
+            }
+        }
+    }
+
+    let mut frozen = engine::index(&resources, IndexOptions::default()).unwrap();
+    let exported = engine::export_jsonl(&frozen).unwrap();
+    engine::freeze(&mut frozen);
+    assert_eq!(engine::export_jsonl(&frozen).unwrap(), exported);
+
+    // 冻结状态随快照保存
+    let restored = engine::load(&engine::dump(&frozen).unwrap()).unwrap();
+    assert!(restored.is_frozen());
+    let query = &resources[3].embeddings;
+    assert_eq!(
+        engine::search(&restored, query, 5),
+        engine::search(&frozen, query, 5)
+    );
+
+    // 修改冻结的索引会报错且不改变内容
+    let extra = EmbeddedResource {
+        id: "extra".to_string(),
+        embeddings: vec![0.5; 16],
+        ..Default::default()
+    };
+    assert!(engine::add(&mut frozen, extra).is_err());
+    assert!(engine::remove(&mut frozen, &["0".to_string()]).is_err());
+    assert!(engine::clear(&mut frozen).is_err());
+    assert!(engine::import_jsonl(&mut frozen, &exported).is_err());
+    assert_eq!(engine::size(&frozen), 500);
+
+    engine::optimize(&mut frozen);
+    assert_eq!(engine::search(&frozen, query, 5), engine::search(&restored, query, 5));
+}
+
+#[test]
+fn test_engine_capacity() {
+    let resources = pseudo_random(300, 8);
+    let query = &resources[7].embeddings;
+    let expected = engine::search(
+        &engine::index(&resources, IndexOptions::default()).unwrap(),
+        query,
+        10,
+    );
+
+    for bucket_size in engine::BUCKET_SIZES {
+        let options = IndexOptions {
+            bucket_size: Some(bucket_size),
+            ..Default::default()
+        };
+        let mut index = engine::index(&resources, options.clone()).unwrap();
+        assert_eq!(engine::search(&index, query, 10), expected);
+        assert_eq!(
+            engine::max_capacity(&index),
+            engine::kdtree_capacity(bucket_size, true)
+        );
+
+        // 逐条添加与重建后结果一致
+        let mut incremental = engine::Index::with_options(options).unwrap();
+        for resource in &resources {
+            engine::add(&mut incremental, resource.to_owned()).unwrap();
+        }
+        assert_eq!(engine::search(&incremental, query, 10), expected);
+
+        engine::optimize(&mut index);
+        assert_eq!(engine::search(&index, query, 10), expected);
+
+        // u16 节点索引用满时重建为 u32，结果不变
+        match Arc::make_mut(&mut index.tree) {
+            engine::Tree::KdTree(tree) => {
+                assert!(!tree.is_wide());
+                tree.widen();
+                assert!(tree.is_wide());
+            }
+            _ => panic!("expected a kd-tree"),
+        }
+        assert_eq!(engine::search(&index, query, 10), expected);
+
+        let extra = EmbeddedResource {
+            id: "extra".to_string(),
+            embeddings: vec![0.0; 8],
+            ..Default::default()
+        };
+        engine::add(&mut index, extra).unwrap();
+        assert_eq!(engine::size(&index), 301);
+    }
+
+    assert_eq!(engine::kdtree_capacity(32, false), 524_288);
+    assert_eq!(
+        engine::max_capacity(&engine::Index::new()),
+        engine::kdtree_capacity(32, true)
+    );
+
+    // 预期容量超过 u16 节点索引时使用 u32
+    let options = IndexOptions {
+        capacity: Some(1_000_000),
+        ..Default::default()
+    };
+    let mut index = engine::Index::with_options(options).unwrap();
+    assert!(engine::max_capacity(&index) > 1_000_000);
+    match index.tree.as_ref() {
+        engine::Tree::KdTree(tree) => assert!(tree.is_wide()),
+        _ => panic!("expected a kd-tree"),
+    }
+
+    for resource in &resources {
+        engine::add(&mut index, resource.to_owned()).unwrap();
+    }
+    assert_eq!(engine::search(&index, query, 10), expected);
+
+    // 重建后仍然使用 u32
+    engine::optimize(&mut index);
+    assert!(engine::max_capacity(&index) > 1_000_000);
+
+    let restored = engine::load(&engine::dump(&index).unwrap()).unwrap();
+    assert_eq!(engine::search(&restored, query, 10), expected);
+
+    for options in [
+        IndexOptions {
+            bucket_size: Some(20),
+            ..Default::default()
+        },
+        IndexOptions {
+            capacity: Some(0),
+            ..Default::default()
+        },
+        IndexOptions {
+            capacity: Some(usize::MAX),
+            ..Default::default()
+        },
+    ] {
+        assert!(engine::Index::with_options(options).is_err());
+    }
+}
+
+#[test]
+fn test_engine_small_stack() {
+    let resources = pseudo_random(300, 8);
+    let index = engine::index(&resources, IndexOptions::default()).unwrap();
+    let data = engine::dump(&index).unwrap();
+
+    // 快照在 wasm 大小（1MB）的栈上也能构建、序列化和恢复
+    let restored = std::thread::Builder::new()
+        .stack_size(1 << 20)
+        .spawn(move || {
+            let index = engine::index(&resources, IndexOptions::default()).unwrap();
+            engine::load(&engine::dump(&index).unwrap()).unwrap();
+
+            engine::load(&data).unwrap()
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+    let query = &pseudo_random(300, 8)[7].embeddings;
+    assert_eq!(engine::search(&restored, query, 10), engine::search(&index, query, 10));
+    let (stats, expected) = (engine::stats(&restored), engine::stats(&index));
+    assert_eq!((stats.depth, stats.leaves), (expected.depth, expected.leaves));
+}
+
+#[test]
+fn test_engine_stats() {
+    let resources = pseudo_random(2000, 8);
+    let index = engine::index(&resources, IndexOptions::default()).unwrap();
+
+    let stats = engine::stats(&index);
+    assert_eq!(stats.documents, 2000);
+    assert_eq!(stats.vectors, 2000);
+    assert_eq!(stats.dimension, 8);
+    assert_eq!(stats.kind, IndexKind::KdTree);
+    assert_eq!(stats.tombstones, 0);
+    assert_eq!(stats.vector_bytes, 2000 * engine::EMBEDDING_DIMENSION * 4);
+    assert!(stats.id_bytes > 0 && stats.tree_bytes > 0);
+    assert_eq!(
+        stats.total_bytes,
+        stats.vector_bytes + stats.tree_bytes + stats.id_bytes + stats.metadata_bytes
+    );
+
+    // 一次性构建的树是平衡的
+    let leaves = stats.leaves.unwrap();
+    assert_eq!(leaves, 64);
+    assert_eq!(stats.depth, Some(leaves.next_power_of_two().ilog2() as usize));
+    assert!(stats.bucket_fill.unwrap() >= 0.5);
+
+    // 按第一维递增逐条插入会让树倾斜，optimize 后恢复平衡
+    let mut sorted = resources.to_vec();
+    sorted.sort_by(|a, b| a.embeddings[0].total_cmp(&b.embeddings[0]));
+    let mut skewed = engine::Index::new();
+    for resource in sorted {
+        engine::add(&mut skewed, resource).unwrap();
+    }
+    let depth = engine::stats(&skewed).depth.unwrap();
+    assert!(depth > stats.depth.unwrap());
+
+    engine::optimize(&mut skewed);
+    assert_eq!(engine::stats(&skewed).depth, stats.depth);
+
+    // 删除只清空叶子中的槽位，不合并节点
+    let removed = resources[..500].iter().map(|resource| resource.id.clone()).collect::<Vec<_>>();
+    engine::remove(&mut skewed, &removed).unwrap();
+    match skewed.tree.as_ref() {
+        engine::Tree::KdTree(tree) => {
+            let shape = tree.shape();
+            assert_eq!((shape.leaves, shape.stems), (64, 63));
+            assert_eq!(shape.leaf_sizes.iter().sum::<usize>(), 1500);
+            assert_eq!(Some(shape.depth), stats.depth);
+        }
+        _ => panic!("expected a kd-tree"),
+    }
+    let removed_stats = engine::stats(&skewed);
+    assert_eq!(removed_stats.tombstones, 0);
+    assert_eq!(removed_stats.empty_slots, Some(64 * 32 - 1500));
+
+    // 过期但尚未清理的条目计为墓碑
+    let expired = EmbeddedResource {
+        id: "expired".to_string(),
+        embeddings: resources[0].embeddings.clone(),
+        expires_at: Some(engine::now() - 1.0),
+        ..Default::default()
+    };
+    engine::add(&mut skewed, expired).unwrap();
+    assert_eq!(engine::stats(&skewed).tombstones, 1);
+    engine::purge_expired(&mut skewed, engine::now()).unwrap();
+    assert_eq!(engine::stats(&skewed).tombstones, 0);
+
+    // 多向量文档按文档计数
+    let mut chunked = engine::Index::new();
+    engine::add(
+        &mut chunked,
+        EmbeddedResource {
+            id: "doc".to_string(),
+            chunks: Some(vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![1.0, 1.0]]),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let stats = engine::stats(&chunked);
+    assert_eq!((stats.documents, stats.vectors, stats.tombstones), (1, 3, 0));
+
+    let ivf = engine::index(
+        &resources,
+        IndexOptions {
+            kind: IndexKind::Ivf,
+            nlist: Some(8),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let stats = engine::stats(&ivf);
+    assert_eq!(stats.lists, Some(8));
+    assert_eq!(stats.mean_list_size, Some(250.0));
+    assert!(stats.max_list_size.unwrap() >= 250);
+    assert_eq!(stats.vector_bytes, 2000 * 8 * 4);
+    assert_eq!(stats.depth, None);
+
+    let mut frozen = index.clone();
+    engine::freeze(&mut frozen);
+    let stats = engine::stats(&frozen);
+    assert!(stats.frozen);
+    assert_eq!(stats.vectors, 2000);
+    assert_eq!(stats.leaves, Some(63));
+    // 冻结后每个向量只按实际维度存一份
+    assert_eq!(stats.vector_bytes, 2000 * 8 * 4);
+}
+
+#[test]
+fn test_engine_ingest_job() {
+    let resources = pseudo_random(1000, 8);
+    let bulk = engine::index(&resources, IndexOptions::default()).unwrap();
+
+    // 替换内容的任务在完成前不影响现有数据
+    let mut index = engine::index(&animals(), IndexOptions::default()).unwrap();
+    let mut job = engine::IngestJob::index(resources.clone());
+    let mut steps = 0;
+
+    while !job.is_done() {
+        let progress = job.step(&mut index, 300).unwrap();
+        steps += 1;
+
+        if !job.is_done() {
+            assert_eq!(progress.done, steps * 300);
+            assert_eq!(engine::size(&index), 3);
+        }
+    }
+
+    // 每个向量先登记再插入树，各计一次
+    assert_eq!(steps, 7);
+    assert_eq!(job.progress(), engine::Progress { done: 2000, total: 2000 });
+    assert_eq!(engine::stats(&index), engine::stats(&bulk));
+
+    let query = &resources[10].embeddings;
+    assert_eq!(engine::search(&index, query, 5), engine::search(&bulk, query, 5));
+
+    // IVF 的 k-means 训练同样分步进行，结果与一次性构建相同
+    let options = IndexOptions {
+        kind: engine::IndexKind::Ivf,
+        nlist: Some(8),
+        ..Default::default()
+    };
+    let ivf_bulk = engine::index(&resources, options.clone()).unwrap();
+    let mut ivf = engine::Index::with_options(options).unwrap();
+    let mut job = engine::IngestJob::index(resources.clone());
+    let mut steps = 0;
+
+    while !job.is_done() {
+        job.step(&mut ivf, 100).unwrap();
+        steps += 1;
+    }
+
+    assert!(steps > 20);
+    assert_eq!(engine::stats(&ivf), engine::stats(&ivf_bulk));
+    assert_eq!(engine::search(&ivf, query, 5), engine::search(&ivf_bulk, query, 5));
+
+    // 替换期间索引被修改时任务报错并停止，索引保持修改后的内容
+    let mut target = engine::index(&animals(), IndexOptions::default()).unwrap();
+    let mut job = engine::IngestJob::index(resources.clone());
+    job.step(&mut target, 300).unwrap();
+    engine::add(&mut target, resources[0].to_owned()).unwrap();
+
+    assert!(job.step(&mut target, 300).is_err());
+    assert!(job.is_done());
+    assert_eq!(engine::size(&target), 4);
+
+    // 追加任务逐步写入，重复的 id 返回错误但任务可以继续
+    let mut job = engine::IngestJob::add(vec![
+        animals()[0].to_owned(),
+        resources[0].to_owned(),
+        EmbeddedResource {
+            id: "doc".to_string(),
+            chunks: Some(vec![vec![1.0; 8], vec![0.5; 8]]),
+            ..Default::default()
+        },
+    ]);
+    assert_eq!(job.progress().total, 4);
+
+    job.step(&mut index, 1).unwrap();
+    assert_eq!(engine::size(&index), 1001);
+    assert!(job.step(&mut index, 1).is_err());
+    assert_eq!(job.step(&mut index, 1).unwrap().done, 4);
+    assert!(job.is_done());
+    assert_eq!(engine::size(&index), 1002);
+
+    // 快照可以分块恢复
+    let mut collections = engine::Collections::new();
+    collections.indexes.insert("default".to_string(), index.clone());
+    let data = engine::dump_collections(&collections, None).unwrap();
+
+    // 总量为压缩数据与解压后数据的字节数之和
+    let mut job = engine::LoadJob::new(data.clone());
+    let total = job.progress().total;
+    let mut last = 0;
+    let mut steps = 0;
+    assert!(total > data.len());
+
+    while !job.is_done() {
+        let progress = job.step(4096).unwrap();
+        assert!(progress.done >= last && progress.total == total);
+        last = progress.done;
+        steps += 1;
+    }
+
+    assert_eq!(last, total);
+    assert!(steps > total / 4096);
+    let restored = job.finish().unwrap();
+    assert_eq!(engine::size(restored.get("default").unwrap()), 1002);
+
+    let restored = engine::LoadJob::new(data).finish().unwrap();
+    assert_eq!(engine::size(restored.get("default").unwrap()), 1002);
+    // 一步内的资源在同一事务中添加，失败的资源被丢弃，其余的在下一步重试
+    let fresh = pseudo_random(1002, 8).split_off(1000);
+    let mut job = engine::IngestJob::add(vec![
+        fresh[0].to_owned(),
+        resources[1].to_owned(),
+        fresh[1].to_owned(),
+    ]);
+    assert!(job.step(&mut index, 10).is_err());
+    assert_eq!(engine::size(&index), 1002);
+    assert_eq!(job.step(&mut index, 10).unwrap(), engine::Progress { done: 3, total: 3 });
+    assert_eq!(engine::size(&index), 1004);
+}
+
+#[test]
+fn test_engine_search_batch() {
+    let resources = pseudo_random(2000, 8);
+    let queries = resources
+        .iter()
+        .step_by(50)
+        .map(|resource| resource.embeddings.to_owned())
+        .collect::<Vec<Vec<f32>>>();
+
+    let kdtree = engine::index(&resources, IndexOptions::default()).unwrap();
+    let ivf = engine::index(
+        &resources,
+        IndexOptions {
+            kind: IndexKind::Ivf,
+            nlist: Some(8),
+            nprobe: Some(2),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    // 批量搜索与逐条搜索的结果一致
+    for index in [&kdtree, &ivf] {
+        let results = engine::search_batch(index, &queries, 5);
+        assert_eq!(results.len(), queries.len());
+
+        for (query, result) in queries.iter().zip(results) {
+            assert_eq!(result, engine::search(index, query, 5));
+        }
+    }
+
+    // 多线程构建的结果与单线程一致
+    let large = pseudo_random(10000, 16);
+    let stats = engine::stats(&engine::index(&large, IndexOptions::default()).unwrap());
+    assert_eq!((stats.leaves, stats.depth), (Some(512), Some(9)));
+    assert_eq!(engine::stats(&ivf).max_list_size, Some(268));
+}
+
+#[test]
+fn test_engine_expiration() {
+    let mut resources = animals();
+    resources[0].expires_at = Some(1000.0);
+    resources[1].ttl = Some(60_000.0);
+
+    let mut index = engine::index(&resources, IndexOptions::default()).unwrap();
+    let query = [0.8, 0.7, 0.6, 0.2, 0.1];
+
+    // 搜索跳过已过期的条目
+    let result = engine::search(&index, &query, 3);
+    let ids = result.neighbors.iter().map(|n| n.id.as_str()).collect::<Vec<&str>>();
+    assert_eq!(ids, vec!["dog", "car"]);
+    assert_eq!(engine::size(&index), 3);
+
+    let expires_at = index.expires[&engine::hash(&"dog".to_string())];
+    assert!(expires_at > engine::now() && expires_at <= engine::now() + 60_000.0);
+
+    // 过期时间随快照和导出保留
+    let restored = engine::load(&engine::dump(&index).unwrap()).unwrap();
+    assert_eq!(restored.expires, index.expires);
+
+    let mut imported = engine::Index::new();
+    engine::import_jsonl(&mut imported, &engine::export_jsonl(&index).unwrap()).unwrap();
+    assert_eq!(imported.expires, index.expires);
+
+    // 冻结的索引同样跳过过期条目，但不能清理
+    let mut frozen = index.clone();
+    engine::freeze(&mut frozen);
+    assert_eq!(engine::search(&frozen, &query, 3), result);
+    assert!(engine::purge_expired(&mut frozen, engine::now()).is_err());
+
+    assert_eq!(engine::purge_expired(&mut index, engine::now()).unwrap(), vec!["cat"]);
+    assert_eq!(engine::size(&index), 2);
+    assert_eq!(index.tree.size(), 2);
+    assert!(engine::purge_expired(&mut index, engine::now()).unwrap().is_empty());
+
+    assert_eq!(
+        engine::purge_expired(&mut index, expires_at).unwrap(),
+        vec!["dog"]
+    );
+    assert!(index.expires.is_empty());
+
+    // 多向量文档按平均距离聚合时也跳过过期文档
+    let mut chunked = engine::Index::with_options(IndexOptions {
+        aggregation: engine::Aggregation::Mean,
+        ..Default::default()
+    })
+    .unwrap();
+
+    for (id, expires_at) in [("old", Some(1.0)), ("new", None)] {
+        engine::add(
+            &mut chunked,
+            EmbeddedResource {
+                id: id.to_string(),
+                chunks: Some(vec![vec![1.0, 0.0], vec![0.0, 1.0]]),
+                expires_at,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    }
+
+    let result = engine::search(&chunked, &[1.0, 0.0], 2);
+    assert_eq!(result.neighbors.len(), 1);
+    assert_eq!(result.neighbors[0].id, "new");
+}
+
+#[test]
+fn test_engine_eviction() {
+    let entry = |id: &str, x: f32| EmbeddedResource {
+        id: id.to_string(),
+        embeddings: vec![x, 1.0],
+        ..Default::default()
+    };
+    let limited = |eviction: engine::Eviction| {
+        let mut index = engine::Index::with_options(IndexOptions {
+            max_vectors: Some(3),
+            eviction,
+            ..Default::default()
+        })
+        .unwrap();
+
+        for (id, x) in [("a", 0.0), ("b", 10.0), ("c", 20.0)] {
+            assert!(engine::add(&mut index, entry(id, x)).unwrap().is_empty());
+        }
+
+        index
+    };
+
+    // 默认淘汰最早插入的条目
+    let mut index = limited(engine::Eviction::Oldest);
+    engine::search(&index, &[0.0, 1.0], 1);
+    assert_eq!(engine::add(&mut index, entry("d", 30.0)).unwrap(), vec!["a"]);
+    assert_eq!(engine::add(&mut index, entry("e", 40.0)).unwrap(), vec!["b"]);
+    assert_eq!(engine::size(&index), 3);
+    assert_eq!(index.tree.size(), 3);
+
+    // LRU 淘汰最久未被检索的条目
+    let mut index = limited(engine::Eviction::Lru);
+    engine::search(&index, &[0.0, 1.0], 1);
+    assert_eq!(engine::add(&mut index, entry("d", 30.0)).unwrap(), vec!["b"]);
+
+    // 使用信息随快照保留
+    let mut restored = engine::load(&engine::dump(&index).unwrap()).unwrap();
+    assert_eq!(engine::add(&mut restored, entry("e", 40.0)).unwrap(), vec!["c"]);
+
+    // LFU 淘汰检索次数最少的条目
+    let mut index = limited(engine::Eviction::Lfu);
+    engine::search(&index, &[0.0, 1.0], 1);
+    engine::search(&index, &[0.0, 1.0], 1);
+    engine::search(&index, &[10.0, 1.0], 1);
+    assert_eq!(engine::add(&mut index, entry("d", 30.0)).unwrap(), vec!["c"]);
+    assert_eq!(engine::add(&mut index, entry("e", 40.0)).unwrap(), vec!["d"]);
+
+    // 删除后不再需要淘汰
+    engine::remove(&mut index, &["a".to_string()]).unwrap();
+    assert!(engine::add(&mut index, entry("f", 50.0)).unwrap().is_empty());
+
+    // 按估算字节数限制，一次添加可能淘汰多个条目
+    let mut index = engine::Index::with_options(IndexOptions {
+        kind: IndexKind::Ivf,
+        max_bytes: Some(60),
+        ..Default::default()
+    })
+    .unwrap();
+
+    for (id, x) in [("a", 0.0), ("b", 10.0), ("c", 20.0)] {
+        engine::add(&mut index, entry(id, x)).unwrap();
+    }
+    assert_eq!(index.usage.bytes(), 3 * (2 * 4 + 8 + 1));
+
+    let large = EmbeddedResource {
+        id: "large".to_string(),
+        embeddings: vec![1.0; 6],
+        ..Default::default()
+    };
+    assert_eq!(engine::add(&mut index, large).unwrap(), vec!["a", "b"]);
+    assert_eq!(engine::size(&index), 2);
+    assert_eq!(index.usage.vectors(), 2);
+
+    // 单个条目超过限制时返回错误且不修改索引
+    let huge = EmbeddedResource {
+        id: "huge".to_string(),
+        embeddings: vec![1.0; 32],
+        ..Default::default()
+    };
+    assert!(engine::add(&mut index, huge).is_err());
+    assert_eq!(engine::size(&index), 2);
+
+    // 一次性构建超过限制时返回错误
+    let options = IndexOptions {
+        max_vectors: Some(2),
+        ..Default::default()
+    };
+    assert!(engine::index(&animals(), options.to_owned()).is_err());
+    assert!(engine::index(&animals()[..2], options).is_ok());
+}
+
+#[test]
+fn test_engine_scan() {
+    let mut index = engine::index(&pseudo_random(25, 4), IndexOptions::default()).unwrap();
+    let options = engine::ScanOptions::default();
+
+    // 按 id 顺序分页遍历
+    let mut ids = vec![];
+    let mut cursor = None;
+
+    loop {
+        let page = engine::scan(&index, cursor.as_deref(), 10, options).unwrap();
+        assert!(page.entries.len() <= 10);
+        assert!(page.entries.iter().all(|entry| entry.vector.is_empty()));
+        ids.extend(page.entries.into_iter().map(|entry| entry.id));
+
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    let mut expected = index.hash.values().cloned().collect::<Vec<String>>();
+    expected.sort();
+    assert_eq!(ids, expected);
+
+    // 遍历期间增删条目不会重复或遗漏其余条目
+    let page = engine::scan(&index, None, 5, options).unwrap();
+    assert_eq!(page.cursor.as_deref(), Some("12"));
+
+    engine::remove(&mut index, &["13".to_string(), "0".to_string()]).unwrap();
+    engine::add(
+        &mut index,
+        EmbeddedResource {
+            id: "99".to_string(),
+            embeddings: vec![1.0; 4],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let page = engine::scan(&index, page.cursor.as_deref(), 3, options).unwrap();
+    let ids = page.entries.iter().map(|entry| entry.id.as_str()).collect::<Vec<&str>>();
+    assert_eq!(ids, vec!["14", "15", "16"]);
+
+    // 可选返回向量和元数据，与导出内容一致
+    let mut chunked = engine::index(&animals(), IndexOptions::default()).unwrap();
+    engine::add(
+        &mut chunked,
+        EmbeddedResource {
+            id: "doc".to_string(),
+            chunks: Some(vec![vec![1.0, 0.0, 0.0, 0.0, 0.0], vec![0.0, 1.0, 0.0, 0.0, 0.0]]),
+            metadata: Some([("kind".to_string(), "note".to_string())].into()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    let full = engine::ScanOptions {
+        with_vectors: true,
+        with_metadata: true,
+        with_content: true,
+    };
+    let page = engine::scan(&chunked, None, 10, full).unwrap();
+    assert_eq!(page.entries, engine::records(&chunked));
+    assert_eq!(page.cursor, None);
+
+    // 只读取所需条目的向量，分块按位置排列
+    let doc = engine::get(&chunked, "doc").unwrap();
+    let expected = vec![vec![1.0, 0.0, 0.0, 0.0, 0.0], vec![0.0, 1.0, 0.0, 0.0, 0.0]];
+    assert_eq!((doc.vector.is_empty(), doc.chunks), (true, Some(expected)));
+    let page = engine::scan(&chunked, Some("doc"), 1, full).unwrap();
+    assert_eq!(page.entries[0].id, "dog");
+    assert_eq!(page.entries[0].vector, animals()[1].embeddings);
+
+    let page = engine::scan(&chunked, Some("cat"), 10, options).unwrap();
+    assert_eq!(page.entries.len(), 2);
+    assert_eq!(page.entries[0].id, "doc");
+    assert_eq!((page.entries[0].metadata.as_ref(), page.entries[0].chunks.as_ref()), (None, None));
+
+    assert!(engine::scan(&chunked, None, 0, options).is_err());
+}
+
+#[test]
+fn test_engine_content() {
+    let mut resources = animals();
+    for resource in resources.iter_mut() {
+        resource.content = Some(format!("A text about the {}", resource.id));
+    }
+    resources[2].content = None;
+
+    let mut index = engine::index(&resources, IndexOptions::default()).unwrap();
+    let query = [0.8, 0.7, 0.6, 0.2, 0.1];
+
+    // 按需随搜索结果返回内容
+    let with_content = engine::SearchOptions {
+        with_content: true,
+        ..Default::default()
+    };
+    let result = engine::search_with(&index, &query, 3, with_content);
+    let content = result
+        .neighbors
+        .iter()
+        .map(|neighbor| neighbor.content.as_deref())
+        .collect::<Vec<Option<&str>>>();
+    assert_eq!(content, vec![Some("A text about the cat"), Some("A text about the dog"), None]);
+    assert!(engine::search(&index, &query, 3).neighbors.iter().all(|n| n.content.is_none()));
+
+    let cat = engine::get(&index, "cat").unwrap();
+    assert_eq!(cat.content.as_deref(), Some("A text about the cat"));
+    assert_eq!(cat.vector, resources[0].embeddings);
+    assert!(engine::get(&index, "missing").is_none());
+
+    // 内容随快照和导出保留
+    let restored = engine::load(&engine::dump(&index).unwrap()).unwrap();
+    assert_eq!(restored.content, index.content);
+
+    let mut imported = engine::Index::new();
+    engine::import_jsonl(&mut imported, &engine::export_jsonl(&index).unwrap()).unwrap();
+    assert_eq!(imported.content, index.content);
+
+    // upsert 替换向量和内容，无效的条目不影响旧内容
+    engine::upsert(
+        &mut index,
+        EmbeddedResource {
+            id: "cat".to_string(),
+            embeddings: vec![-0.1, -0.2, -0.3, -0.8, -0.8],
+            content: Some("A new text".to_string()),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let cat = engine::get(&index, "cat").unwrap();
+    assert_eq!(cat.content.as_deref(), Some("A new text"));
+    assert_eq!(cat.vector, vec![-0.1, -0.2, -0.3, -0.8, -0.8]);
+    assert_eq!(index.tree.size(), 3);
+
+    let invalid = EmbeddedResource {
+        id: "cat".to_string(),
+        chunks: Some(vec![]),
+        ..Default::default()
+    };
+    assert!(engine::upsert(&mut index, invalid).is_err());
+    assert_eq!(engine::get(&index, "cat").unwrap().content.as_deref(), Some("A new text"));
+
+    engine::upsert(
+        &mut index,
+        EmbeddedResource {
+            id: "cat".to_string(),
+            embeddings: vec![0.8, 0.7, 0.6, 0.2, 0.1],
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(engine::get(&index, "cat").unwrap().content, None);
+
+    // 删除和清空时同步删除内容
+    engine::remove(&mut index, &["dog".to_string()]).unwrap();
+    assert!(index.content.is_empty());
+
+    engine::add(&mut index, resources[1].to_owned()).unwrap();
+    assert_eq!(index.content.len(), 1);
+    engine::clear(&mut index).unwrap();
+    assert!(index.content.is_empty());
+}
+
+// 按字母计数的玩具嵌入函数
+fn letter_counts(texts: &[String]) -> Result<Vec<engine::Embedding>, engine::EngineError> {
+    Ok(texts
+        .iter()
+        .map(|text| {
+            ['a', 'e', 'i', 'o']
+                .iter()
+                .map(|letter| text.matches(*letter).count() as f32)
+                .collect()
+        })
+        .collect())
+}
+
+#[test]
+fn test_engine_embedding() {
+    let texts = ["banana", "apple", "kiwi", "potato", "banana"]
+        .iter()
+        .enumerate()
+        .map(|(i, text)| engine::TextEntry {
+            id: format!("fruit{}", i),
+            text: text.to_string(),
+            metadata: None,
+        })
+        .collect::<Vec<engine::TextEntry>>();
+
+    // 按批调用嵌入函数，重复的文本只嵌入一次
+    let mut index = engine::Index::new();
+    let mut cache = engine::EmbeddingCache::new(16);
+    let mut batches = vec![];
+    let evicted = engine::add_texts(&mut index, texts.clone(), 2, &mut cache, |batch| {
+        batches.push(batch.to_vec());
+        letter_counts(batch)
+    })
+    .unwrap();
+    assert!(evicted.is_empty());
+    assert_eq!(batches, vec![vec!["banana", "apple"], vec!["kiwi", "potato"]]);
+    assert_eq!(engine::size(&index), 5);
+    assert_eq!(cache.len(), 4);
+    assert_eq!(engine::get(&index, "fruit2").unwrap().content.as_deref(), Some("kiwi"));
+
+    // 缓存的查询不再调用嵌入函数
+    let options = engine::SearchOptions {
+        with_content: true,
+        ..Default::default()
+    };
+    let result = engine::search_text(&index, "kiwi", 1, options, &mut cache, |_| {
+        panic!("The query should be cached")
+    })
+    .unwrap();
+    assert_eq!(result.neighbors[0].id, "fruit2");
+    assert_eq!(result.neighbors[0].content.as_deref(), Some("kiwi"));
+
+    // 缓存满时丢弃最早的文本，容量为 0 时不缓存
+    let mut small = engine::EmbeddingCache::new(2);
+    let strings = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+    engine::embed_texts(&strings, 8, &mut small, letter_counts).unwrap();
+    assert!(small.get("a").is_none());
+    assert!(small.get("c").is_some());
+    let mut disabled = engine::EmbeddingCache::new(0);
+    engine::embed_texts(&strings, 8, &mut disabled, letter_counts).unwrap();
+    assert!(disabled.is_empty());
+
+    // 数量或维度不符时报错且不添加任何条目
+    let mut fresh = engine::EmbeddingCache::new(0);
+    let short = engine::add_texts(&mut index, texts.clone(), 8, &mut fresh, |batch| {
+        Ok(letter_counts(batch)?.into_iter().skip(1).collect())
+    });
+    assert!(short.is_err());
+    let ragged = engine::add_texts(&mut index, texts.clone(), 8, &mut fresh, |batch| {
+        let mut embeddings = letter_counts(batch)?;
+        embeddings[0].push(1.0);
+        Ok(embeddings)
+    });
+    assert!(ragged.is_err());
+
+    let mut sized = engine::Index::with_options(IndexOptions {
+        dimension: Some(3),
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(engine::add_texts(&mut sized, texts.clone(), 8, &mut fresh, letter_counts).is_err());
+    assert_eq!(engine::size(&sized), 0);
+    assert_eq!(engine::size(&index), 5);
+
+    // 批次中出现重复的 id 时整批回滚
+    let mut duplicated = texts[..2].to_vec();
+    duplicated[1].id = duplicated[0].id.clone();
+    let mut empty = engine::Index::new();
+    assert!(engine::add_texts(&mut empty, duplicated, 8, &mut fresh, letter_counts).is_err());
+    assert_eq!(engine::size(&empty), 0);
+}
+
+#[test]
+fn test_engine_remove_where() {
+    use engine::MetadataFilter;
+
+    let chat = |id: usize| {
+        let mut metadata = engine::Metadata::new();
+        metadata.insert("chat".to_string(), (id % 3).to_string());
+        if id.is_multiple_of(4) {
+            metadata.insert("pinned".to_string(), "true".to_string());
+        }
+        metadata
+    };
+
+    let mut resources = pseudo_random(30, 4);
+    for (i, resource) in resources.iter_mut().enumerate() {
+        resource.metadata = (i != 29).then(|| chat(i));
+    }
+    let mut index = engine::index(&resources, IndexOptions::default()).unwrap();
+
+    // 删除 chat 为 1 且未置顶的条目
+    let filter = MetadataFilter::And(vec![
+        MetadataFilter::Eq {
+            key: "chat".to_string(),
+            value: "1".to_string(),
+        },
+        MetadataFilter::Not(Box::new(MetadataFilter::Exists("pinned".to_string()))),
+    ]);
+    let mut expected = (0..29)
+        .filter(|i| i % 3 == 1 && i % 4 != 0)
+        .map(|i| resources[i].id.to_owned())
+        .collect::<Vec<String>>();
+    expected.sort();
+
+    let removed = engine::remove_where(&mut index, &filter).unwrap();
+    assert_eq!(removed, expected);
+    assert_eq!(engine::size(&index), 30 - expected.len());
+    assert_eq!(index.tree.size(), (30 - expected.len()) as u64);
+    assert!(removed.iter().all(|id| engine::get(&index, id).is_none()));
+    assert!(engine::remove_where(&mut index, &filter).unwrap().is_empty());
+
+    // 没有元数据的条目只匹配否定条件
+    let ne = MetadataFilter::Ne {
+        key: "chat".to_string(),
+        value: "0".to_string(),
+    };
+    let removed = engine::remove_where(&mut index, &ne).unwrap();
+    assert!(removed.contains(&resources[29].id));
+    let pinned = (0..29).filter(|i| i % 3 == 1 && i % 4 == 0).count();
+    assert_eq!(removed.len(), 1 + (0..29).filter(|i| i % 3 == 2).count() + pinned);
+
+    let json = r#"{"in":{"key":"chat","values":["0"]}}"#;
+    let filter: MetadataFilter = serde_json::from_str(json).unwrap();
+    assert_eq!(engine::remove_where(&mut index, &filter).unwrap().len(), 10);
+    assert_eq!(engine::size(&index), 0);
+
+    engine::freeze(&mut index);
+    assert!(engine::remove_where(&mut index, &MetadataFilter::And(vec![])).is_err());
+}
+
+#[test]
+fn test_engine_transaction() {
+    use engine::Operation;
+
+    let mut resources = animals();
+    resources[0].content = Some("A text about the cat".to_string());
+    resources[1].expires_at = Some(f64::MAX);
+    resources.push(EmbeddedResource {
+        id: "bird".to_string(),
+        chunks: Some(vec![vec![0.5, 0.5, 0.5, 0.5, 0.5], vec![0.9, 0.1, 0.1, 0.1, 0.1]]),
+        ..Default::default()
+    });
+
+    let options = IndexOptions {
+        max_vectors: Some(7),
+        eviction: engine::Eviction::Lru,
+        ..Default::default()
+    };
+    let mut index = engine::index(&resources, options).unwrap();
+    let query = [0.8, 0.7, 0.6, 0.2, 0.1];
+    engine::search(&index, &query, 1);
+
+    let before = engine::records(&index);
+    let neighbors = engine::search(&index, &query, 4).neighbors;
+    let fish = EmbeddedResource {
+        id: "fish".to_string(),
+        embeddings: vec![0.1, 0.1, 0.9, 0.1, 0.1],
+        ..Default::default()
+    };
+
+    // 第四个操作重复添加 fish，之前的修改全部回滚
+    let operations = vec![
+        Operation::Add(fish.clone()),
+        Operation::Upsert(EmbeddedResource {
+            id: "cat".to_string(),
+            embeddings: vec![-0.1, -0.2, -0.3, -0.8, -0.8],
+            ..Default::default()
+        }),
+        Operation::Remove(vec!["bird".to_string(), "dog".to_string()]),
+        Operation::Add(fish.clone()),
+    ];
+    let error = engine::transact(&mut index, operations).unwrap_err();
+    assert_eq!(error.operation, 3);
+    assert!(error.to_string().contains("Id fish already exists"));
+
+    assert_eq!(engine::records(&index), before);
+    assert_eq!(engine::search(&index, &query, 4).neighbors, neighbors);
+    assert_eq!(index.tree.size(), 5);
+    assert_eq!(index.usage.vectors(), 5);
+
+    // 同一事务中添加后又删除的条目在回滚后不存在
+    let operations = vec![
+        Operation::Add(fish.clone()),
+        Operation::Remove(vec!["fish".to_string(), "cat".to_string()]),
+        Operation::Add(fish.clone()),
+        Operation::Add(fish.clone()),
+    ];
+    assert_eq!(engine::transact(&mut index, operations).unwrap_err().operation, 3);
+    assert_eq!(engine::records(&index), before);
+    assert_eq!(index.tree.size(), 5);
+
+    // 删除不存在的 id 同样回滚
+    let operations = vec![
+        Operation::Remove(vec!["cat".to_string()]),
+        Operation::Remove(vec!["missing".to_string()]),
+    ];
+    assert_eq!(engine::transact(&mut index, operations).unwrap_err().operation, 1);
+    assert_eq!(engine::records(&index), before);
+
+    // 全部成功时一次性应用，超出限制的条目在最后淘汰（最久未检索的 cat）
+    let mut more = pseudo_random(4, 5);
+    let operations = vec![
+        Operation::Remove(vec!["car".to_string()]),
+        Operation::Add(fish),
+        Operation::Add(more.remove(0)),
+        Operation::Add(more.remove(0)),
+        Operation::Add(more.remove(0)),
+        Operation::Upsert(EmbeddedResource {
+            id: "dog".to_string(),
+            embeddings: vec![0.7, 0.8, 0.6, 0.3, 0.2],
+            ..Default::default()
+        }),
+    ];
+    let evicted = engine::transact(&mut index, operations).unwrap();
+    assert_eq!(evicted, vec!["cat"]);
+    assert_eq!(engine::size(&index), 6);
+    assert_eq!(index.tree.size(), 7);
+    assert!(engine::get(&index, "car").is_none());
+    assert_eq!(engine::get(&index, "dog").unwrap().vector, vec![0.7, 0.8, 0.6, 0.3, 0.2]);
+
+    engine::freeze(&mut index);
+    let operations = vec![Operation::Remove(vec!["dog".to_string()])];
+    assert_eq!(engine::transact(&mut index, operations).unwrap_err().operation, 0);
+}
+
+#[test]
+fn test_engine_snapshot() {
+    let mut collections = engine::Collections::new();
+    *collections.get_or_create("default") = engine::index(&animals(), IndexOptions::default()).unwrap();
+    engine::add(collections.get_or_create("other"), animals().remove(0)).unwrap();
+    let data = engine::dump_collections(&collections, None).unwrap();
+
+    // 克隆只共享树和映射，不复制
+    let snapshot = collections.clone();
+    assert!(collections.is_shared("default"));
+    assert!(Arc::ptr_eq(&snapshot.indexes["default"].tree, &collections.indexes["default"].tree));
+
+    // 写入时只复制被修改的部分，快照保持不变
+    engine::remove(collections.get_mut("default").unwrap(), &["cat".to_string()]).unwrap();
+    let (original, copy) = (&collections.indexes["default"], &snapshot.indexes["default"]);
+    assert!(!Arc::ptr_eq(&original.tree, &copy.tree));
+    assert!(!Arc::ptr_eq(&original.hash, &copy.hash));
+    assert!(Arc::ptr_eq(&original.metadata, &copy.metadata));
+    assert!(Arc::ptr_eq(&original.content, &copy.content));
+    assert!(collections.is_shared("other"));
+    assert_eq!(engine::size(collections.get("default").unwrap()), 2);
+    assert_eq!(engine::size(snapshot.get("default").unwrap()), 3);
+    assert_eq!(engine::dump_collections(&snapshot, None).unwrap(), data);
+
+    let query = [0.8, 0.7, 0.6, 0.2, 0.1];
+    assert_eq!(engine::search(snapshot.get("default").unwrap(), &query, 1).neighbors[0].id, "cat");
+    assert_eq!(engine::search(collections.get("default").unwrap(), &query, 1).neighbors[0].id, "dog");
+
+    // 丢弃分支不影响原集合
+    let mut fork = snapshot.clone();
+    engine::clear(fork.get_mut("other").unwrap()).unwrap();
+    drop(fork);
+    assert_eq!(engine::size(snapshot.get("other").unwrap()), 1);
+    assert!(collections.drop("other").is_ok());
+    assert_eq!(engine::size(snapshot.get("other").unwrap()), 1);
+
+    // 分支有自己的使用计数，在分支上检索不改变原集合的淘汰顺序
+    let options = IndexOptions {
+        max_vectors: Some(3),
+        eviction: engine::Eviction::Lru,
+        ..Default::default()
+    };
+    let mut limited = engine::Collections::new();
+    *limited.get_or_create("default") = engine::index(&animals(), options).unwrap();
+    let fork = limited.clone();
+    engine::search(fork.get("default").unwrap(), &query, 1);
+    let mut resource = animals().remove(0);
+    resource.id = "lion".to_string();
+    assert_eq!(engine::add(limited.get_mut("default").unwrap(), resource).unwrap(), vec!["cat"]);
+    assert_eq!(engine::size(fork.get("default").unwrap()), 3);
+
+    // 写入失败时移除为它新建的集合，已有的集合保留
+    let operations = vec![
+        engine::Operation::Add(animals().remove(0)),
+        engine::Operation::Add(animals().remove(0)),
+    ];
+    let result = limited.update_or_create("new", |index| engine::transact(index, operations));
+    assert!(result.is_err());
+    assert!(limited.get("new").is_none());
+    let result = limited.update_or_create("default", |index| engine::remove(index, &["x".to_string()]));
+    assert!(result.is_err());
+    assert_eq!(engine::size(limited.get("default").unwrap()), 3);
+}
+
+#[test]
+fn test_engine_storage() {
+    use engine::{AutosaveOptions, MemoryStorage, PersistentCollections, StorageAdapter};
+
+    // 没有快照时打开空数据库
+    let options = AutosaveOptions {
+        max_changes: Some(2),
+        ..Default::default()
+    };
+    let mut db = PersistentCollections::open(MemoryStorage::default(), "db", options).unwrap();
+    assert_eq!(db.collections.names(), vec!["default"]);
+
+    // 未保存的修改达到 max_changes 时自动保存
+    let mut resources = animals().into_iter();
+    engine::add(db.collections.get_or_create("default"), resources.next().unwrap()).unwrap();
+    assert!(!db.changed(1).unwrap());
+    assert_eq!(db.unsaved_changes(), 1);
+    assert!(db.storage().data.is_empty());
+
+    engine::add(db.collections.get_or_create("default"), resources.next().unwrap()).unwrap();
+    assert!(db.changed(1).unwrap());
+    assert_eq!(db.unsaved_changes(), 0);
+
+    engine::add(db.collections.get_or_create("default"), resources.next().unwrap()).unwrap();
+    assert!(!db.changed(1).unwrap());
+
+    // 重新打开时读取已保存的快照，未保存的修改丢失
+    let mut storage = db.into_storage();
+    let reopened = PersistentCollections::open(storage.clone(), "db", options).unwrap();
+    assert_eq!(engine::size(reopened.collections.get("default").unwrap()), 2);
+
+    // flush 立即保存，delete 删除快照
+    let mut db = reopened;
+    db.flush().unwrap();
+    assert_eq!(
+        db.storage().data["db"],
+        engine::dump_collections(&db.collections, None).unwrap()
+    );
+    db.delete().unwrap();
+    assert!(db.storage().data.is_empty());
+
+    // 间隔为 0 时每次修改都会保存
+    let interval = AutosaveOptions {
+        interval: Some(0.0),
+        ..Default::default()
+    };
+    let mut db = PersistentCollections::open(storage.clone(), "other", interval).unwrap();
+    assert!(!db.changed(0).unwrap());
+    assert!(db.changed(1).unwrap());
+    assert!(db.storage().data.contains_key("other"));
+    assert!(storage.read("other").unwrap().is_none());
+
+    // 间隔到期后不再有修改时，用 0 个修改的 changed 保存最后的修改
+    let interval = AutosaveOptions {
+        interval: Some(20.0),
+        ..Default::default()
+    };
+    let mut db = PersistentCollections::open(storage.clone(), "trailing", interval).unwrap();
+    assert_eq!(db.save_due_in(), None);
+    assert!(!db.changed(2).unwrap());
+    assert!(db.save_due_in().is_some_and(|delay| delay > 0.0 && delay <= 20.0));
+    std::thread::sleep(std::time::Duration::from_millis(30));
+    assert_eq!(db.save_due_in(), Some(0.0));
+    assert!(db.changed(0).unwrap());
+    assert_eq!(db.save_due_in(), None);
+    assert!(db.storage().data.contains_key("trailing"));
+    storage.write("other", b"invalid").unwrap();
+    assert!(PersistentCollections::open(storage, "other", interval).is_err());
+}
+
+fn stored_state(collections: &engine::Collections) -> Vec<(String, Vec<engine::ExportRecord>)> {
+    collections
+        .names()
+        .into_iter()
+        .map(|name| {
+            let records = engine::records(collections.get(&name).unwrap());
+            (name, records)
+        })
+        .collect()
+}
+
+fn disk_files(dir: &std::path::Path, prefix: &str) -> Vec<std::path::PathBuf> {
+    let mut files = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with(prefix))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+#[test]
+fn test_disk_store() {
+    use engine::{DiskStore, DiskStoreOptions, MetadataFilter, Operation};
+
+    let dir = temp_path("disk-store");
+    let options = DiskStoreOptions {
+        checkpoint_after: 3,
+    };
+
+    // 新目录只有空的默认集合
+    let mut store = DiskStore::open(&dir, options).unwrap();
+    assert_eq!(store.collections().names(), vec!["default"]);
+
+    let operations = animals().into_iter().map(Operation::Add).collect();
+    store.transact("default", operations).unwrap();
+    store
+        .create_collection("notes", IndexOptions::default())
+        .unwrap();
+    store
+        .transact(
+            "notes",
+            vec![Operation::Add(EmbeddedResource {
+                id: "note".to_string(),
+                embeddings: vec![1.0, 0.0],
+                metadata: Some([("chat".to_string(), "1".to_string())].into()),
+                ttl: Some(60_000.0),
+                ..Default::default()
+            })],
+        )
+        .unwrap();
+
+    // 第三条记录触发 checkpoint，之后的修改只写日志
+    assert_eq!(disk_files(&dir, "MANIFEST-").len(), 1);
+    assert_eq!(disk_files(&dir, "segment-").len(), 2);
+    store
+        .transact("default", vec![Operation::Remove(vec!["car".to_string()])])
+        .unwrap();
+    store.create_collection("temp", IndexOptions::default()).unwrap();
+    store.drop_collection("temp").unwrap();
+
+    // 失败的事务不改变集合，也不会写入日志
+    assert!(store
+        .transact("default", vec![Operation::Add(animals().remove(0))])
+        .is_err());
+    assert!(store.transact("missing", vec![Operation::Remove(vec!["x".to_string()])]).is_err());
+    assert!(store.get("missing").is_none());
+
+    // 重新打开时恢复 checkpoint 和之后的日志，ttl 已转换为 expires_at
+    let expected = stored_state(store.collections());
+    drop(store);
+
+    let mut store = DiskStore::open(&dir, options).unwrap();
+    assert_eq!(stored_state(store.collections()), expected);
+    assert_eq!(store.collections().names(), vec!["default", "notes"]);
+    assert!(engine::get(store.get("notes").unwrap(), "note")
+        .unwrap()
+        .expires_at
+        .is_some());
+
+    // checkpoint 只重写修改过的集合，并删除不再引用的文件
+    let filter = MetadataFilter::Eq {
+        key: "chat".to_string(),
+        value: "1".to_string(),
+    };
+    assert_eq!(store.remove_where("notes", &filter).unwrap(), vec!["note"]);
+    store.checkpoint().unwrap();
+    assert_eq!(disk_files(&dir, "MANIFEST-").len(), 1);
+    assert_eq!(disk_files(&dir, "log-").len(), 1);
+    assert_eq!(disk_files(&dir, "segment-").len(), 2);
+
+    store.clear("default").unwrap();
+    store.freeze("notes").unwrap();
+    let expected = stored_state(store.collections());
+    drop(store);
+
+    let mut store = DiskStore::open(&dir, options).unwrap();
+    assert_eq!(stored_state(store.collections()), expected);
+    assert!(store.get("notes").unwrap().is_frozen());
+    assert!(store.purge_expired("notes", engine::now()).is_err());
+    assert!(store.clear("missing").is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_disk_store_torn_log() {
+    use engine::{DiskStore, DiskStoreOptions, Operation};
+
+    let dir = temp_path("disk-store-torn");
+    let options = DiskStoreOptions {
+        checkpoint_after: 0,
+    };
+
+    let mut store = DiskStore::open(&dir, options).unwrap();
+    let mut resources = animals().into_iter();
+    store
+        .transact("default", vec![Operation::Add(resources.next().unwrap())])
+        .unwrap();
+    let before = stored_state(store.collections());
+    let log = disk_files(&dir, "log-").remove(0);
+    let complete = std::fs::metadata(&log).unwrap().len() as usize;
+
+    store
+        .transact(
+            "default",
+            resources.map(Operation::Add).collect::<Vec<Operation>>(),
+        )
+        .unwrap();
+    let after = stored_state(store.collections());
+    drop(store);
+    let data = std::fs::read(&log).unwrap();
+
+    // 在最后一条记录的任意位置中断写入，都恢复到上一条完整的记录
+    for cut in complete..data.len() {
+        std::fs::write(&log, &data[..cut]).unwrap();
+
+        let store = DiskStore::open(&dir, options).unwrap();
+        assert_eq!(stored_state(store.collections()), before, "cut at {}", cut);
+        assert_eq!(std::fs::metadata(&log).unwrap().len() as usize, complete);
+    }
+
+    // 校验和不符的记录同样被丢弃
+    let mut corrupted = data.clone();
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    std::fs::write(&log, &corrupted).unwrap();
+    assert_eq!(stored_state(DiskStore::open(&dir, options).unwrap().collections()), before);
+
+    // 日志末尾的残留数据被截断，之后的写入可以正常恢复
+    let mut garbage = data.clone();
+    garbage.extend_from_slice(&[0xff; 13]);
+    std::fs::write(&log, &garbage).unwrap();
+
+    let mut store = DiskStore::open(&dir, options).unwrap();
+    assert_eq!(stored_state(store.collections()), after);
+    store
+        .transact("default", vec![Operation::Remove(vec!["dog".to_string()])])
+        .unwrap();
+    let expected = stored_state(store.collections());
+    drop(store);
+    assert_eq!(stored_state(DiskStore::open(&dir, options).unwrap().collections()), expected);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_disk_store_invalid_record() {
+    use engine::{DiskStore, DiskStoreOptions, Operation};
+
+    let dir = temp_path("disk-store-invalid");
+    let options = DiskStoreOptions {
+        checkpoint_after: 0,
+    };
+    let entry = |id: &str, x: f32| EmbeddedResource {
+        id: id.to_string(),
+        embeddings: vec![x, 1.0],
+        ..Default::default()
+    };
+
+    // NaN 和无穷大的向量被拒绝，不会写入日志，之后的记录照常恢复
+    let mut store = DiskStore::open(&dir, options).unwrap();
+    store.transact("default", vec![Operation::Add(entry("a", 0.0))]).unwrap();
+    assert!(store.transact("default", vec![Operation::Add(entry("bad", f32::NAN))]).is_err());
+    assert!(store.transact("default", vec![Operation::Add(entry("inf", f32::INFINITY))]).is_err());
+    store.transact("default", vec![Operation::Add(entry("c", 2.0))]).unwrap();
+    drop(store);
+
+    let store = DiskStore::open(&dir, options).unwrap();
+    let index = store.get("default").unwrap();
+    assert_eq!(engine::size(index), 2);
+    assert!(engine::get(index, "a").is_some() && engine::get(index, "c").is_some());
+    drop(store);
+
+    // 校验和正确但无法解析的记录不是写入中断，打开时报错而不是截断日志
+    let log = disk_files(&dir, "log-").remove(0);
+    let data = std::fs::read(&log).unwrap();
+    let payload = b"{\"invalid\": true}";
+    let mut crc = flate2::Crc::new();
+    crc.update(payload);
+
+    let mut invalid = data.clone();
+    invalid.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    invalid.extend_from_slice(&crc.sum().to_le_bytes());
+    invalid.extend_from_slice(payload);
+    std::fs::write(&log, &invalid).unwrap();
+    assert!(DiskStore::open(&dir, options).is_err());
+    assert_eq!(std::fs::read(&log).unwrap(), invalid);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_disk_store_interrupted_checkpoint() {
+    use engine::{DiskStore, DiskStoreOptions, Operation};
+
+    let dir = temp_path("disk-store-checkpoint");
+    let options = DiskStoreOptions {
+        checkpoint_after: 0,
+    };
+
+    let mut store = DiskStore::open(&dir, options).unwrap();
+    let operations = animals().into_iter().map(Operation::Add).collect();
+    store.transact("default", operations).unwrap();
+    store.checkpoint().unwrap();
+    store
+        .transact("default", vec![Operation::Remove(vec!["cat".to_string()])])
+        .unwrap();
+    let expected = stored_state(store.collections());
+    drop(store);
+
+    // 在写入段文件和临时 manifest 时中断：旧 manifest 和日志仍然有效
+    std::fs::write(dir.join("segment-99999999999999999999-0"), b"partial").unwrap();
+    std::fs::write(dir.join("MANIFEST-99999999999999999999.tmp"), b"{\"seq").unwrap();
+
+    let mut store = DiskStore::open(&dir, options).unwrap();
+    assert_eq!(stored_state(store.collections()), expected);
+    assert_eq!(disk_files(&dir, "segment-").len(), 1);
+    assert_eq!(disk_files(&dir, "MANIFEST-").len(), 1);
+
+    // 在重命名 manifest 之后、创建新日志之前中断：新 manifest 已包含全部修改
+    store.checkpoint().unwrap();
+    let manifest = disk_files(&dir, "MANIFEST-").remove(0);
+    std::fs::remove_file(disk_files(&dir, "log-").remove(0)).unwrap();
+    drop(store);
+
+    let store = DiskStore::open(&dir, options).unwrap();
+    assert_eq!(stored_state(store.collections()), expected);
+    assert_eq!(disk_files(&dir, "MANIFEST-"), vec![manifest]);
+    assert_eq!(disk_files(&dir, "log-").len(), 1);
+
+    // 缺失段文件或 manifest 损坏时报错，而不是打开空数据库
+    drop(store);
+    std::fs::remove_file(disk_files(&dir, "segment-").remove(0)).unwrap();
+    assert!(DiskStore::open(&dir, options).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// 子进程持续写入，父进程在随机时刻将其杀死后重新打开目录
+const KILL_DIR: &str = "LUNA_VDB_KILL_DIR";
+
+#[test]
+fn test_disk_store_kill_during_write() {
+    use engine::{DiskStore, DiskStoreOptions, Operation};
+
+    let options = DiskStoreOptions {
+        checkpoint_after: 100,
+    };
+
+    // 第 i 个事务添加 i 并删除 i - 3，恢复后的 id 应为某个前缀的结果
+    let transaction = |i: usize| {
+        let mut operations = vec![Operation::Add(EmbeddedResource {
+            id: i.to_string(),
+            embeddings: vec![i as f32, 1.0],
+            metadata: Some([("i".to_string(), "x".repeat(i % 50))].into()),
+            ..Default::default()
+        })];
+
+        if i >= 3 {
+            operations.push(Operation::Remove(vec![(i - 3).to_string()]));
+        }
+
+        operations
+    };
+
+    if let Ok(dir) = std::env::var(KILL_DIR) {
+        let mut store = DiskStore::open(&dir, options).unwrap();
+        let started = std::time::Instant::now();
+
+        for i in 0.. {
+            if started.elapsed().as_secs() > 30 {
+                break;
+            }
+
+            store.transact("default", transaction(i)).unwrap();
+        }
+
+        return;
+    }
+
+    for (round, delay) in [0, 10, 25, 60, 150, 250].into_iter().enumerate() {
+        let dir = temp_path(&format!("disk-store-kill-{}", round));
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["test_disk_store_kill_during_write", "--exact", "--test-threads=1"])
+            .env(KILL_DIR, &dir)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(delay));
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let store = DiskStore::open(&dir, options).unwrap();
+        let index = store.get("default").unwrap();
+        let mut ids = engine::export_ids(index)
+            .into_iter()
+            .map(|id| id.parse::<usize>().unwrap())
+            .collect::<Vec<usize>>();
+        ids.sort();
+
+        let count = ids.last().map_or(0, |last| last + 1);
+        assert_eq!(ids, (count.saturating_sub(3)..count).collect::<Vec<usize>>());
+
+        for id in ids {
+            let record = engine::get(index, &id.to_string()).unwrap();
+            assert_eq!(record.metadata.unwrap()["i"], "x".repeat(id % 50));
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn test_sqlite_store() {
+    use engine::{MetadataFilter, Operation, SqliteStore};
+
+    let path = temp_path("store.sqlite");
+    let metadata = |pairs: &[(&str, &str)]| {
+        Some(
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        )
+    };
+
+    // 新数据库只有空的默认集合
+    let mut store = SqliteStore::open(&path).unwrap();
+    assert_eq!(store.collections().names(), vec!["default"]);
+
+    let mut resources = animals();
+    resources[0].metadata = metadata(&[("kind", "pet"), ("name", "tom")]);
+    resources[1].metadata = metadata(&[("kind", "pet")]);
+    resources[2].metadata = metadata(&[("kind", "vehicle")]);
+    resources[2].content = Some("a car".to_string());
+    store
+        .transact("default", resources.into_iter().map(Operation::Add).collect())
+        .unwrap();
+
+    // 多向量文档、ttl、upsert 和淘汰都同步到表中
+    let options = IndexOptions {
+        max_vectors: Some(2),
+        ..Default::default()
+    };
+    store.create_collection("docs", options).unwrap();
+    let document = |id: &str| EmbeddedResource {
+        id: id.to_string(),
+        chunks: Some(vec![vec![1.0, 0.0], vec![0.0, 1.0]]),
+        ttl: Some(60_000.0),
+        ..Default::default()
+    };
+    store
+        .transact("docs", vec![Operation::Add(document("a"))])
+        .unwrap();
+    assert_eq!(
+        store
+            .transact("docs", vec![Operation::Add(document("b"))])
+            .unwrap(),
+        vec!["a"]
+    );
+    store
+        .transact(
+            "default",
+            vec![
+                Operation::Upsert(EmbeddedResource {
+                    id: "dog".to_string(),
+                    embeddings: vec![0.1, 0.2, 0.3, 0.4, 0.5],
+                    ..Default::default()
+                }),
+                Operation::Remove(vec!["cat".to_string()]),
+            ],
+        )
+        .unwrap();
+
+    // 失败的事务不写入任何行，也不会创建集合
+    assert!(store
+        .transact("missing", vec![Operation::Remove(vec!["x".to_string()])])
+        .is_err());
+    assert!(store.get("missing").is_none());
+    store.transact("created", vec![]).unwrap();
+
+    // 重新打开时从表中重建索引
+    let expected = stored_state(store.collections());
+    drop(store);
+
+    let mut store = SqliteStore::open(&path).unwrap();
+    assert_eq!(stored_state(store.collections()), expected);
+    assert_eq!(store.collections().names(), vec!["created", "default", "docs"]);
+    let document = engine::get(store.get("docs").unwrap(), "b").unwrap();
+    assert_eq!(document.chunks.unwrap().len(), 2);
+    assert!(document.expires_at.is_some());
+
+    // 元数据过滤由 SQL 执行，结果与内存中的过滤一致
+    store
+        .transact(
+            "default",
+            vec![Operation::Upsert(EmbeddedResource {
+                id: "cat".to_string(),
+                embeddings: vec![0.8, 0.7, 0.6, 0.2, 0.1],
+                metadata: metadata(&[("kind", "pet"), ("name", "tom")]),
+                ..Default::default()
+            })],
+        )
+        .unwrap();
+
+    let eq = |key: &str, value: &str| MetadataFilter::Eq {
+        key: key.to_string(),
+        value: value.to_string(),
+    };
+    let filters = vec![
+        eq("kind", "pet"),
+        MetadataFilter::Ne {
+            key: "kind".to_string(),
+            value: "pet".to_string(),
+        },
+        MetadataFilter::In {
+            key: "kind".to_string(),
+            values: vec!["vehicle".to_string(), "boat".to_string()],
+        },
+        MetadataFilter::In {
+            key: "kind".to_string(),
+            values: vec![],
+        },
+        MetadataFilter::Exists("name".to_string()),
+        MetadataFilter::And(vec![]),
+        MetadataFilter::Or(vec![]),
+        MetadataFilter::And(vec![eq("kind", "pet"), MetadataFilter::Exists("name".to_string())]),
+        MetadataFilter::Or(vec![eq("name", "tom"), eq("kind", "vehicle")]),
+        MetadataFilter::Not(Box::new(MetadataFilter::Exists("kind".to_string()))),
+    ];
+
+    let index = store.get("default").unwrap();
+    for filter in &filters {
+        let mut expected = engine::records(index)
+            .into_iter()
+            .filter(|record| filter.matches(record.metadata.as_ref()))
+            .map(|record| record.id)
+            .collect::<Vec<String>>();
+        expected.sort();
+
+        assert_eq!(store.ids_where("default", filter).unwrap(), expected, "{:?}", filter);
+    }
+    assert!(store.ids_where("missing", &filters[0]).is_err());
+
+    assert_eq!(
+        store.remove_where("default", &eq("kind", "pet")).unwrap(),
+        vec!["cat"]
+    );
+    assert_eq!(engine::size(store.get("default").unwrap()), 2);
+
+    // 冻结、清空、删除集合和过期清理同样持久化
+    store.freeze("default").unwrap();
+    assert!(store.remove_where("default", &eq("kind", "vehicle")).is_err());
+    assert_eq!(
+        store.purge_expired("docs", f64::INFINITY).unwrap(),
+        vec!["b"]
+    );
+    store.drop_collection("created").unwrap();
+    assert!(store.clear("default").is_err());
+
+    let expected = stored_state(store.collections());
+    drop(store);
+
+    let store = SqliteStore::open(&path).unwrap();
+    assert_eq!(stored_state(store.collections()), expected);
+    assert!(store.get("default").unwrap().is_frozen());
+
+    // 数据可直接用 SQL 查看，删除的行级联清理
+    let connection = store.into_connection();
+    let count = |sql: &str| -> i64 { connection.query_row(sql, [], |row| row.get(0)).unwrap() };
+    assert_eq!(count("SELECT COUNT(*) FROM collections"), 2);
+    assert_eq!(count("SELECT COUNT(*) FROM entries"), 2);
+    assert_eq!(count("SELECT COUNT(*) FROM metadata"), 1);
+    assert_eq!(count("SELECT COUNT(*) FROM chunks"), 0);
+    assert_eq!(
+        connection
+            .query_row("SELECT content FROM entries WHERE id = 'car'", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .unwrap(),
+        "a car"
+    );
+    drop(connection);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_engine_evaluate() {
+    let resources = pseudo_random(500, 16);
+    let queries = resources
+        .iter()
+        .step_by(25)
+        .map(|resource| resource.embeddings.to_owned())
+        .collect::<Vec<engine::Embedding>>();
+
+    // kd-tree 搜索是精确的，计算的距离少于暴力扫描
+    let mut index = engine::index(&resources, IndexOptions::default()).unwrap();
+    let evaluation = engine::evaluate(&index, &queries, 10);
+    assert_eq!(evaluation.queries, 20);
+    assert_eq!(evaluation.k, 10);
+    assert_eq!(evaluation.recall, 1.0);
+    assert_eq!(evaluation.exact_mean_distances, 500.0);
+    assert!(evaluation.mean_distances > 0.0);
+    assert!(evaluation.mean_distances <= 500.0);
+    assert!(evaluation.mean_latency > 0.0);
+    assert!(evaluation.p95_latency >= evaluation.mean_latency * 0.5);
+    assert!(evaluation.exact_p95_latency > 0.0);
+
+    let result = engine::search_exact(&index, &queries[1], 10);
+    assert_eq!(result.neighbors.len(), 10);
+    assert_eq!(result.neighbors[0].id, resources[25].id);
+
+    // 冻结后仍然精确，距离计数按坐标数换算
+    engine::freeze(&mut index);
+    let frozen = engine::evaluate(&index, &queries, 10);
+    assert_eq!(frozen.recall, 1.0);
+    assert!(frozen.mean_distances > 0.0);
+    assert!(frozen.mean_distances <= 500.0 + 1.0);
+
+    // 只探查一个簇的 IVF 计算的距离更少，召回率可能下降
+    let options = IndexOptions {
+        kind: IndexKind::Ivf,
+        nlist: Some(8),
+        nprobe: Some(1),
+        ..Default::default()
+    };
+    let ivf = engine::index(&resources, options).unwrap();
+    let evaluation = engine::evaluate(&ivf, &queries, 10);
+    assert!(evaluation.recall > 0.0 && evaluation.recall <= 1.0);
+    assert!(evaluation.mean_distances < evaluation.exact_mean_distances);
+
+    // 没有查询时全部为 0
+    assert_eq!(
+        engine::evaluate(&ivf, &[], 10),
+        engine::Evaluation::default()
+    );
+    // 评估的查询不算作检索，不影响 LRU 淘汰顺序
+    let mut index = engine::Index::with_options(IndexOptions {
+        max_vectors: Some(500),
+        eviction: engine::Eviction::Lru,
+        ..Default::default()
+    })
+    .unwrap();
+    for resource in &resources {
+        engine::add(&mut index, resource.to_owned()).unwrap();
+    }
+    engine::evaluate(&index, &queries[..1], 10);
+
+    let evicted = engine::add(&mut index, pseudo_random(501, 16).pop().unwrap()).unwrap();
+    assert_eq!(evicted, vec![resources[0].id.clone()]);
+}
+
+#[test]
+fn test_engine_search_explain() {
+    let mut resources = pseudo_random(500, 16);
+    let query = resources[0].embeddings.to_owned();
+    let explain = engine::SearchOptions {
+        explain: true,
+        ..Default::default()
+    };
+
+    // 默认不返回解释，解释不改变结果
+    let index = engine::index(&resources, IndexOptions::default()).unwrap();
+    let result = engine::search(&index, &query, 10);
+    assert_eq!(result.explain, None);
+
+    let explained = engine::search_with(&index, &query, 10, explain);
+    assert_eq!(explained.neighbors, result.neighbors);
+
+    // kd-tree 报告访问的节点和计算的距离
+    let report = explained.explain.unwrap();
+    assert_eq!(report.rounds, 1);
+    assert!(report.nodes > 0);
+    assert_eq!(report.buckets, 0);
+    assert!(report.distances >= 10 && report.distances <= 500);
+    assert_eq!(report.candidates, 10);
+    assert_eq!((report.duplicate_chunks, report.expired), (0, 0));
+    assert!(report.elapsed >= 0.0);
+
+    // 过期条目和同一文档的其他分块被过滤并分别计数
+    resources[0].expires_at = Some(1.0);
+    resources.push(EmbeddedResource {
+        id: "doc".to_string(),
+        chunks: Some(vec![query.to_owned(), query.iter().map(|x| x * 0.99).collect()]),
+        ..Default::default()
+    });
+    let index = engine::index(&resources, IndexOptions::default()).unwrap();
+    let report = engine::search_with(&index, &query, 10, explain).explain.unwrap();
+    assert_eq!(report.rounds, 1);
+    assert_eq!(report.candidates, 40);
+    assert_eq!(report.duplicate_chunks, 1);
+    assert_eq!(report.expired, 1);
+
+    // 过期条目太多时搜索会扩大，计数是最后一轮的
+    for resource in resources.iter_mut().take(480) {
+        resource.expires_at = Some(1.0);
+    }
+    let index = engine::index(&resources, IndexOptions::default()).unwrap();
+    let result = engine::search_with(&index, &query, 10, explain);
+    let report = result.explain.unwrap();
+    assert_eq!(result.neighbors.len(), 10);
+    assert!(report.rounds > 1);
+    assert!(report.expired > 0);
+    assert!(report.candidates > 40);
+
+    // 冻结的树同样统计节点和距离
+    let mut frozen = engine::index(&pseudo_random(500, 16), IndexOptions::default()).unwrap();
+    engine::freeze(&mut frozen);
+    let report = engine::search_with(&frozen, &query, 10, explain).explain.unwrap();
+    assert!(report.nodes > 0);
+    assert!(report.distances > 0);
+
+    // IVF 报告扫描的簇
+    let options = IndexOptions {
+        kind: IndexKind::Ivf,
+        nlist: Some(8),
+        nprobe: Some(2),
+        ..Default::default()
+    };
+    let ivf = engine::index(&pseudo_random(500, 16), options).unwrap();
+    let report = engine::search_with(&ivf, &query, 10, explain).explain.unwrap();
+    assert_eq!(report.buckets, 2);
+    assert_eq!(report.nodes, 0);
+    assert!(report.distances >= 8);
+}
@@ -5,6 +5,7 @@
 extern crate wasm_bindgen_test;
 extern crate web_sys;
 
+use luna_vdb::engine::{Aggregation, Eviction, IndexKind, IndexOptions, Metric, Operation};
 use luna_vdb::*;
 use wasm_bindgen_test::*;
 
@@ -25,7 +26,7 @@ fn random_number() -> i32 {
     getrandom(&mut buffer).expect("Failed to generate random bytes");
 
     // 将字节数组转换为整数
-    return i32::from_le_bytes(buffer);
+    i32::from_le_bytes(buffer)
 }
 
 fn random_string(length: usize) -> String {
@@ -53,6 +54,7 @@ fn generate_test_data(count: usize, dim: usize) -> Vec<EmbeddedResource> {
         resources.push(EmbeddedResource {
             id: random_string(10),
             embeddings,
+            ..Default::default()
         });
     }
     resources
@@ -64,22 +66,24 @@ fn test_luna_vdb_basic() {
     console_log!("Starting test_luna_vdb_basic");
     
     let mut luna_vdb = LunaVDB::new(None);
-    assert_eq!(luna_vdb.size(), 0);
+    assert_eq!(luna_vdb.size(None), 0);
 
     // 测试初始索引
     let embeddings = vec![
         EmbeddedResource {
             id: "1".to_string(),
             embeddings: vec![0.1, 0.2, 0.3],
+            ..Default::default()
         },
         EmbeddedResource {
             id: "2".to_string(),
             embeddings: vec![0.4, 0.5, 0.6],
+            ..Default::default()
         },
     ];
     let resource = Resource { embeddings };
-    luna_vdb.index(resource);
-    assert_eq!(luna_vdb.size(), 2);
+    luna_vdb.index(resource, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 2);
 
 }
 
@@ -94,32 +98,37 @@ fn test_luna_vdb_search() {
         EmbeddedResource {
             id: "cat".to_string(),
             embeddings: vec![0.8, 0.7, 0.6, 0.2, 0.1], 
+            ..Default::default()
         },
         EmbeddedResource {
             id: "dog".to_string(),
             embeddings: vec![0.7, 0.8, 0.6, 0.3, 0.1], 
+            ..Default::default()
         },
         EmbeddedResource {
             id: "bird".to_string(),
             embeddings: vec![0.6, 0.5, 0.8, 0.4, 0.2], 
+            ..Default::default()
         },
         EmbeddedResource {
             id: "fish".to_string(),
             embeddings: vec![0.2, 0.3, 0.4, 0.8, 0.7], 
+            ..Default::default()
         },
         EmbeddedResource {
             id: "car".to_string(),
             embeddings: vec![-0.1, -0.2, -0.3, -0.8, -0.9], 
+            ..Default::default()
         },
     ];
 
     let resource = Resource { embeddings };
-    luna_vdb.index(resource);
+    luna_vdb.index(resource, None).unwrap();
 
     // 测试场景1: 搜索最接近"猫"的向量
     console_log!("Testing cat-like vector search");
     let cat_query = vec![0.8, 0.7, 0.6, 0.2, 0.1];
-    let result = luna_vdb.search(cat_query, 3);
+    let result = luna_vdb.search(cat_query, 3, None);
     assert_eq!(result.neighbors.len(), 3);
     assert_eq!(result.neighbors[0].id, "cat");
     assert_eq!(result.neighbors[1].id, "dog");
@@ -132,7 +141,7 @@ fn test_luna_vdb_search() {
     // 测试场景2: 搜索边界值向量
     console_log!("Testing boundary vector search");
     let boundary_query = vec![1.0, 1.0, 1.0, 1.0, 1.0];
-    let result = luna_vdb.search(boundary_query, 5);
+    let result = luna_vdb.search(boundary_query, 5, None);
     assert_eq!(result.neighbors.len(), 5);
     
     // 验证所有结果都有合理的距离值
@@ -143,26 +152,26 @@ fn test_luna_vdb_search() {
     // 测试场景3: 搜索零向量
     console_log!("Testing zero vector search");
     let zero_query = vec![0.0, 0.0, 0.0, 0.0, 0.0];
-    let result = luna_vdb.search(zero_query, 3);
+    let result = luna_vdb.search(zero_query, 3, None);
     assert_eq!(result.neighbors.len(), 3);
 
     // 测试场景4: 搜索负向量
     console_log!("Testing negative vector search");
     let negative_query = vec![-0.1, -0.2, -0.3, -0.8, -0.9];
-    let result = luna_vdb.search(negative_query, 1);
+    let result = luna_vdb.search(negative_query, 1, None);
     assert_eq!(result.neighbors[0].id, "car");
     assert!(result.neighbors[0].distance < 0.1); // 应该非常接近
 
     // 测试场景5: 验证距离计算
     console_log!("Testing distance calculations");
     let query = vec![0.8, 0.7, 0.6, 0.2, 0.1];  // 与 cat 向量相同
-    let result = luna_vdb.search(query, 1);
+    let result = luna_vdb.search(query, 1, None);
     assert_eq!(result.neighbors[0].id, "cat");
     assert!(result.neighbors[0].distance < 1e-6); // 应该几乎为0
 
     // 测试场景6: 极限搜索数量
     console_log!("Testing search with max k");
-    let result = luna_vdb.search(vec![0.0; 5], 10);
+    let result = luna_vdb.search(vec![0.0; 5], 10, None);
     assert_eq!(result.neighbors.len(), 5); // 不应超过实际存在的向量数量
 }
 
@@ -177,15 +186,16 @@ fn test_luna_vdb_add_remove() {
     let embeddings = vec![EmbeddedResource {
         id: "3".to_string(),
         embeddings: vec![0.7, 0.8, 0.9],
+        ..Default::default()
     }];
     let resource = Resource { embeddings };
-    luna_vdb.add(resource);
-    assert_eq!(luna_vdb.size(), 1);
+    luna_vdb.add(resource, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 1);
 
     // 测试移除 - 使用新的方式
     let ids = vec!["3".to_string()];
-    luna_vdb.remove(ids);
-    assert_eq!(luna_vdb.size(), 0);
+    luna_vdb.remove(ids, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 0);
 
 
 }
@@ -201,23 +211,31 @@ fn test_luna_vdb_serialization() {
     let embeddings = vec![EmbeddedResource {
         id: "1".to_string(),
         embeddings: vec![0.1, 0.2, 0.3],
+        ..Default::default()
     }];
     let resource = Resource { embeddings };
-    luna_vdb.index(resource);
+    luna_vdb.index(resource, None).unwrap();
 
     // 测试序列化
-    let serialized = luna_vdb.serialize();
+    let serialized = luna_vdb.serialize(None).unwrap();
     assert!(!serialized.is_empty());
 
+    // 更新版本写入的或损坏的数据抛出错误而不是中止实例
+    let mut newer = serialized.clone();
+    newer[4] += 1;
+    assert!(LunaVDB::deserialize(newer).is_err());
+    assert!(LunaVDB::deserialize(serialized[..serialized.len() / 2].to_vec()).is_err());
+    assert!(luna_vdb.serialize(Some("missing".to_string())).is_err());
+
     // 测试反序列化
-    let new_luna_vdb = LunaVDB::deserialize(serialized);
+    let new_luna_vdb = LunaVDB::deserialize(serialized).unwrap();
 
-    assert_eq!(new_luna_vdb.size(), 1);
+    assert_eq!(new_luna_vdb.size(None), 1);
 
     // 验证搜索结果一致性
     let query = vec![0.15, 0.25, 0.35];
-    let original_results = luna_vdb.search(query.clone(), 1);
-    let new_results = new_luna_vdb.search(query, 1);
+    let original_results = luna_vdb.search(query.clone(), 1, None);
+    let new_results = new_luna_vdb.search(query, 1, None);
     assert_eq!(original_results, new_results);
 
 }
@@ -233,14 +251,15 @@ fn test_luna_vdb_clear() {
     let embeddings = vec![EmbeddedResource {
         id: "1".to_string(),
         embeddings: vec![0.1, 0.2, 0.3],
+        ..Default::default()
     }];
     let resource = Resource { embeddings };
-    luna_vdb.index(resource);
-    assert_eq!(luna_vdb.size(), 1);
+    luna_vdb.index(resource, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 1);
 
     // 测试清空
-    luna_vdb.clear();
-    assert_eq!(luna_vdb.size(), 0);
+    luna_vdb.clear(None).unwrap();
+    assert_eq!(luna_vdb.size(None), 0);
 
    
 }
@@ -258,13 +277,13 @@ fn test_luna_vdb_large_dataset() {
 
     // 测试大规模索引
     console_log!("Indexing 1000 vectors...");
-    luna_vdb.index(resource);
-    assert_eq!(luna_vdb.size(), 1000);
+    luna_vdb.index(resource, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 1000);
 
     // 测试批量搜索
     console_log!("Testing batch search...");
     let query = vec![0.5; 1024]; // 创建一个1024维的查询向量
-    let neighbors = luna_vdb.search(query, 10);
+    let neighbors = luna_vdb.search(query, 10, None);
     assert_eq!(neighbors.neighbors.len(), 10);
 
     // 测试增量更新
@@ -273,8 +292,8 @@ fn test_luna_vdb_large_dataset() {
     let new_resource = Resource {
         embeddings: new_embeddings,
     };
-    luna_vdb.add(new_resource);
-    assert_eq!(luna_vdb.size(), 1100);
+    luna_vdb.add(new_resource, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 1100);
 
 
 }
@@ -290,14 +309,17 @@ fn test_luna_vdb_edge_cases() {
         EmbeddedResource {
             id: "max".to_string(),
             embeddings: vec![f32::MAX; 10],
+            ..Default::default()
         },
         EmbeddedResource {
             id: "min".to_string(),
             embeddings: vec![f32::MIN; 10],
+            ..Default::default()
         },
         EmbeddedResource {
             id: "zero".to_string(),
             embeddings: vec![0.0; 10],
+            ..Default::default()
         },
         EmbeddedResource {
             id: "mixed".to_string(),
@@ -313,14 +335,15 @@ fn test_luna_vdb_edge_cases() {
                 -f32::EPSILON,
                 1.0,
             ],
+            ..Default::default()
         },
     ];
 
     let resource = Resource { embeddings };
-    luna_vdb.index(resource);
+    luna_vdb.index(resource, None).unwrap();
 
     // 使用不同类型的查询向量测试
-    let queries = vec![
+    let queries = [
         vec![0.0; 10],  // 零向量
         vec![1.0; 10],  // 单位向量
         vec![-1.0; 10], // 负单位向量
@@ -328,7 +351,7 @@ fn test_luna_vdb_edge_cases() {
 
     for (i, query) in queries.iter().enumerate() {
         console_log!("Testing query type {}", i);
-        let results = luna_vdb.search(query.clone(), 4);
+        let results = luna_vdb.search(query.clone(), 4, None);
         assert_eq!(results.neighbors.len(), 4);
     }
 
@@ -348,22 +371,22 @@ fn test_luna_vdb_persistence() {
     let resource = Resource {
         embeddings: initial_embeddings,
     };
-    luna_vdb.index(resource);
+    luna_vdb.index(resource, None).unwrap();
 
     // 序列化
-    let serialized = luna_vdb.serialize();
+    let serialized = luna_vdb.serialize(None).unwrap();
 
     // 创建新实例并反序列化
-    let new_luna_vdb = LunaVDB::deserialize(serialized);
+    let new_luna_vdb = LunaVDB::deserialize(serialized).unwrap();
 
     // 验证数据完整性
-    assert_eq!(new_luna_vdb.size(), 500);
+    assert_eq!(new_luna_vdb.size(None), 500);
 
     // 在新实例上进行搜索测试
     for (i, query) in test_queries.iter().enumerate() {
         console_log!("Testing query {} on restored database", i);
-        let original_results = luna_vdb.search(query.embeddings.clone(), 5);
-        let new_results = new_luna_vdb.search(query.embeddings.clone(), 5);
+        let original_results = luna_vdb.search(query.embeddings.clone(), 5, None);
+        let new_results = new_luna_vdb.search(query.embeddings.clone(), 5, None);
         assert_eq!(original_results, new_results);
     }
 
@@ -385,7 +408,7 @@ fn test_luna_vdb_dynamic_operations() {
 
     luna_vdb.index(Resource {
         embeddings: initial_embeddings,
-    });
+    }, None).unwrap();
 
     // 随机删除一些向量
     let remove_count = 50;
@@ -396,16 +419,16 @@ fn test_luna_vdb_dynamic_operations() {
     }
 
     console_log!("Removing {} vectors", remove_count);
-    luna_vdb.remove(to_remove);
-    assert_eq!(luna_vdb.size(), 400 - remove_count);
+    luna_vdb.remove(to_remove, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 400 - remove_count);
 
     // 添加新的向量
     let new_embeddings = generate_test_data(100, 32);
     console_log!("Adding 100 new vectors");
     luna_vdb.add(Resource {
         embeddings: new_embeddings,
-    });
-    assert_eq!(luna_vdb.size(), 450);
+    }, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 450);
 
     // 执行复杂搜索
     let complex_query = vec![0.1, -0.2, 0.3, -0.4, 0.5, -0.6, 0.7, -0.8, 0.9, -1.0]
@@ -414,7 +437,971 @@ fn test_luna_vdb_dynamic_operations() {
         .take(32)
         .collect::<Vec<f32>>();
 
-    let results = luna_vdb.search(complex_query, 20);
+    let results = luna_vdb.search(complex_query, 20, None);
     assert_eq!(results.neighbors.len(), 20);
 
 }
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_export_import() {
+    console_log!("Starting test_luna_vdb_export_import");
+
+    let mut luna_vdb = LunaVDB::new(None);
+
+    let mut metadata = std::collections::HashMap::new();
+    metadata.insert("chat".to_string(), "42".to_string());
+
+    let embeddings = vec![
+        EmbeddedResource {
+            id: "b".to_string(),
+            embeddings: vec![0.4, 0.5, 0.6],
+            ..Default::default()
+        },
+        EmbeddedResource {
+            id: "a".to_string(),
+            embeddings: vec![0.1, 0.2, 0.3],
+            metadata: Some(metadata),
+            ..Default::default()
+        },
+    ];
+    luna_vdb.index(Resource { embeddings }, None).unwrap();
+
+    // 测试 JSONL 导出，按 id 排序并裁剪到原始维度
+    let jsonl = luna_vdb.export_jsonl(None).unwrap();
+    let lines = jsonl.lines().collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(
+        lines[0],
+        r#"{"id":"a","vector":[0.1,0.2,0.3],"metadata":{"chat":"42"}}"#
+    );
+    assert_eq!(lines[1], r#"{"id":"b","vector":[0.4,0.5,0.6]}"#);
+
+    let mut restored = LunaVDB::new(None);
+    restored.import_jsonl(jsonl.clone(), None).unwrap();
+    assert_eq!(restored.size(None), 2);
+    assert_eq!(restored.export_jsonl(None).unwrap(), jsonl);
+
+    // 测试 npy 导出
+    let matrix = luna_vdb.export_npy(None);
+    let ids = luna_vdb.export_ids(None);
+    assert_eq!(ids, vec!["a".to_string(), "b".to_string()]);
+    assert_eq!(&matrix[..6], b"\x93NUMPY");
+    // 头部按 64 字节对齐，之后是 2 * 3 个 float32
+    assert_eq!((matrix.len() - 2 * 3 * 4) % 64, 0);
+
+    let mut restored = LunaVDB::new(None);
+    restored.import_npy(matrix, ids, None).unwrap();
+    assert_eq!(restored.size(None), 2);
+
+    let query = vec![0.1, 0.2, 0.3];
+    assert_eq!(luna_vdb.search(query.clone(), 2, None), restored.search(query, 2, None));
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_collections() {
+    console_log!("Starting test_luna_vdb_collections");
+
+    let mut luna_vdb = LunaVDB::new(None);
+    assert_eq!(luna_vdb.collections(), vec!["default".to_string()]);
+
+    luna_vdb.create_collection(
+        "persona".to_string(),
+        Some(IndexOptions {
+            dimension: Some(3),
+            metric: Metric::Cosine,
+            ..Default::default()
+        }),
+    )
+    .unwrap();
+    assert!(luna_vdb.has_collection("persona".to_string()));
+
+    let persona = vec![EmbeddedResource {
+        id: "1".to_string(),
+        embeddings: vec![0.1, 0.2, 0.3],
+        ..Default::default()
+    }];
+    luna_vdb.add(Resource { embeddings: persona.clone() }, Some("persona".to_string())).unwrap();
+
+    // 未创建的集合在写入时自动创建
+    let group = vec![EmbeddedResource {
+        id: "1".to_string(),
+        embeddings: vec![0.7, 0.8, 0.9, 1.0],
+        ..Default::default()
+    }];
+    luna_vdb.add(Resource { embeddings: group }, Some("group".to_string())).unwrap();
+
+    assert_eq!(luna_vdb.size(None), 0);
+    assert_eq!(luna_vdb.size(Some("persona".to_string())), 1);
+    assert_eq!(luna_vdb.size(Some("group".to_string())), 1);
+    assert_eq!(
+        luna_vdb.collections(),
+        vec![
+            "default".to_string(),
+            "group".to_string(),
+            "persona".to_string()
+        ]
+    );
+
+    // 余弦距离只与方向有关
+    let result = luna_vdb.search(vec![0.2, 0.4, 0.6], 1, Some("persona".to_string()));
+    assert_eq!(result.neighbors[0].id, "1");
+    assert!(result.neighbors[0].distance < 1e-6);
+
+    // 序列化全部集合
+    let restored = LunaVDB::deserialize(luna_vdb.serialize(None).unwrap()).unwrap();
+    assert_eq!(restored.collections(), luna_vdb.collections());
+    assert_eq!(restored.size(Some("group".to_string())), 1);
+
+    // 只序列化单个集合
+    let serialized = luna_vdb.serialize(Some("persona".to_string())).unwrap();
+    let restored = LunaVDB::deserialize(serialized).unwrap();
+    assert_eq!(restored.collections(), vec!["persona".to_string()]);
+    assert_eq!(
+        restored.search(vec![0.2, 0.4, 0.6], 1, Some("persona".to_string())),
+        result
+    );
+
+    luna_vdb.drop_collection("group".to_string()).unwrap();
+    assert!(!luna_vdb.has_collection("group".to_string()));
+
+    // 重复创建、删除不存在的集合和无效的选项抛出错误
+    assert!(luna_vdb.create_collection("persona".to_string(), None).is_err());
+    assert!(luna_vdb.drop_collection("group".to_string()).is_err());
+    let options = IndexOptions {
+        dimension: Some(0),
+        ..Default::default()
+    };
+    assert!(luna_vdb.create_collection("invalid".to_string(), Some(options)).is_err());
+    assert!(!luna_vdb.has_collection("invalid".to_string()));
+
+    // 冻结的集合和重复的 id 不能用 index 替换
+    let duplicated = vec![persona[0].clone(), persona[0].clone()];
+    let collection = Some("persona".to_string());
+    assert!(luna_vdb.index(Resource { embeddings: duplicated }, collection.clone()).is_err());
+    luna_vdb.freeze(collection.clone()).unwrap();
+    let resource = Resource { embeddings: persona.clone() };
+    assert!(luna_vdb.index(resource, collection).is_err());
+
+    // 删除、清空和导入不会创建拼错的集合
+    let missing = Some("gruop".to_string());
+    assert!(luna_vdb.remove(vec!["1".to_string()], missing.clone()).is_err());
+    assert!(luna_vdb.clear(missing.clone()).is_err());
+    assert!(luna_vdb.import_jsonl(String::new(), missing.clone()).is_err());
+    assert!(!luna_vdb.has_collection("gruop".to_string()));
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_multi_vector() {
+    console_log!("Starting test_luna_vdb_multi_vector");
+
+    let mut luna_vdb = LunaVDB::new(None);
+
+    let embeddings = vec![
+        EmbeddedResource {
+            id: "long".to_string(),
+            chunks: Some(vec![
+                vec![0.0, 0.0, 1.0],
+                vec![1.0, 0.0, 0.0],
+                vec![0.0, 1.0, 0.0],
+            ]),
+            ..Default::default()
+        },
+        EmbeddedResource {
+            id: "short".to_string(),
+            embeddings: vec![0.9, 0.1, 0.0],
+            ..Default::default()
+        },
+    ];
+    luna_vdb.index(Resource { embeddings }, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 2);
+
+    // 每个文档只返回一次，并报告命中的分块
+    let result = luna_vdb.search(vec![1.0, 0.0, 0.0], 2, None);
+    assert_eq!(result.neighbors.len(), 2);
+    assert_eq!(result.neighbors[0].id, "long");
+    assert_eq!(result.neighbors[0].chunk, Some(1));
+    assert_eq!(result.neighbors[1].id, "short");
+    assert_eq!(result.neighbors[1].chunk, None);
+
+    // 取平均距离时，其他分块会拉低长文档的得分
+    luna_vdb.create_collection(
+        "mean".to_string(),
+        Some(IndexOptions {
+            aggregation: Aggregation::Mean,
+            ..Default::default()
+        }),
+    )
+    .unwrap();
+    luna_vdb.import_jsonl(luna_vdb.export_jsonl(None).unwrap(), Some("mean".to_string())).unwrap();
+    let result = luna_vdb.search(vec![1.0, 0.0, 0.0], 2, Some("mean".to_string()));
+    assert_eq!(result.neighbors[0].id, "short");
+    assert_eq!(result.neighbors[1].id, "long");
+    assert_eq!(result.neighbors[1].chunk, Some(1));
+
+    // npy 导出中分块按行展开，导入时按 id 重新合并
+    let mut restored = LunaVDB::new(None);
+    restored.import_npy(luna_vdb.export_npy(None), luna_vdb.export_ids(None), None).unwrap();
+    assert_eq!(restored.size(None), 2);
+    assert_eq!(restored.export_jsonl(None).unwrap(), luna_vdb.export_jsonl(None).unwrap());
+
+    luna_vdb.remove(vec!["long".to_string()], None).unwrap();
+    assert_eq!(luna_vdb.size(None), 1);
+    let result = luna_vdb.search(vec![1.0, 0.0, 0.0], 2, None);
+    assert_eq!(result.neighbors.len(), 1);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_ivf() {
+    console_log!("Starting test_luna_vdb_ivf");
+
+    let mut luna_vdb = LunaVDB::with_options(IndexOptions {
+        kind: IndexKind::Ivf,
+        nlist: Some(4),
+        nprobe: Some(4),
+        ..Default::default()
+    });
+
+    let embeddings = generate_test_data(100, 32);
+    let query = embeddings[0].clone();
+    luna_vdb.add(Resource { embeddings }, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 100);
+
+    let result = luna_vdb.search(query.embeddings.clone(), 5, None);
+    assert_eq!(result.neighbors.len(), 5);
+    assert_eq!(result.neighbors[0].id, query.id);
+
+    // 序列化后仍为 IVF 索引
+    let restored = LunaVDB::deserialize(luna_vdb.serialize(None).unwrap()).unwrap();
+    assert_eq!(restored.search(query.embeddings.clone(), 5, None), result);
+
+    luna_vdb.remove(vec![query.id.clone()], None).unwrap();
+    assert_eq!(luna_vdb.size(None), 99);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_optimize() {
+    console_log!("Starting test_luna_vdb_optimize");
+
+    let embeddings = generate_test_data(200, 16);
+    let query = embeddings[150].clone();
+    let mut luna_vdb = LunaVDB::new(Some(Resource {
+        embeddings: embeddings.clone(),
+    }));
+
+    let removed = embeddings[..100]
+        .iter()
+        .map(|resource| resource.id.clone())
+        .collect::<Vec<String>>();
+    luna_vdb.remove(removed, None).unwrap();
+
+    let result = luna_vdb.search(query.embeddings.clone(), 5, None);
+    assert_eq!(result.neighbors[0].id, query.id);
+
+    luna_vdb.optimize(None).unwrap();
+    assert_eq!(luna_vdb.size(None), 100);
+    assert_eq!(luna_vdb.search(query.embeddings.clone(), 5, None), result);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_freeze() {
+    console_log!("Starting test_luna_vdb_freeze");
+
+    let embeddings = generate_test_data(100, 16);
+    let query = embeddings[10].clone();
+    let mut luna_vdb = LunaVDB::new(Some(Resource { embeddings }));
+
+    let result = luna_vdb.search(query.embeddings.clone(), 5, None);
+    assert!(!luna_vdb.is_frozen(None));
+
+    luna_vdb.freeze(None).unwrap();
+    assert!(luna_vdb.is_frozen(None));
+    assert_eq!(luna_vdb.size(None), 100);
+    assert_eq!(luna_vdb.search(query.embeddings.clone(), 5, None), result);
+
+    // 冻结状态随序列化保存
+    let restored = LunaVDB::deserialize(luna_vdb.serialize(None).unwrap()).unwrap();
+    assert!(restored.is_frozen(None));
+    assert_eq!(restored.search(query.embeddings.clone(), 5, None), result);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_capacity() {
+    console_log!("Starting test_luna_vdb_capacity");
+
+    let embeddings = generate_test_data(100, 16);
+    let query = embeddings[20].clone();
+
+    let mut luna_vdb = LunaVDB::with_options(IndexOptions {
+        bucket_size: Some(16),
+        capacity: Some(2_000_000),
+        ..Default::default()
+    });
+    assert!(luna_vdb.max_capacity(None) >= 2_000_000);
+
+    luna_vdb.add(Resource { embeddings }, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 100);
+
+    let result = luna_vdb.search(query.embeddings.clone(), 5, None);
+    assert_eq!(result.neighbors[0].id, query.id);
+
+    let restored = LunaVDB::deserialize(luna_vdb.serialize(None).unwrap()).unwrap();
+    assert_eq!(restored.search(query.embeddings.clone(), 5, None), result);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_stats() {
+    console_log!("Starting test_luna_vdb_stats");
+
+    let embeddings = generate_test_data(100, 16);
+    let luna_vdb = LunaVDB::new(Some(Resource { embeddings }));
+
+    let stats = luna_vdb.stats(None);
+    assert_eq!(stats.documents, 100);
+    assert_eq!(stats.vectors, 100);
+    assert_eq!(stats.dimension, 16);
+    assert_eq!(stats.kind, IndexKind::KdTree);
+    assert_eq!(stats.tombstones, 0);
+    assert!(stats.vector_bytes > 0 && stats.tree_bytes > 0 && stats.id_bytes > 0);
+    assert_eq!(stats.leaves, Some(4));
+    assert_eq!(stats.depth, Some(2));
+
+    // 不存在的集合返回空统计
+    assert_eq!(luna_vdb.stats(Some("missing".to_string())).vectors, 0);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_ingest_job() {
+    console_log!("Starting test_luna_vdb_ingest_job");
+
+    let embeddings = generate_test_data(100, 16);
+    let query = embeddings[7].clone();
+    let expected = LunaVDB::new(Some(Resource {
+        embeddings: embeddings.clone(),
+    }));
+
+    // 分步构建索引，完成前旧内容仍可搜索
+    let mut luna_vdb = LunaVDB::new(None);
+    let mut job = IngestJob::index(Resource { embeddings }, None, None);
+
+    assert!(!job.step(&mut luna_vdb, 40).unwrap());
+    assert_eq!(job.progress(), Progress { done: 40, total: 200 });
+    assert_eq!(luna_vdb.size(None), 0);
+
+    while !job.step(&mut luna_vdb, 40).unwrap() {}
+
+    assert!(job.is_done());
+    assert_eq!(luna_vdb.size(None), 100);
+    assert_eq!(
+        luna_vdb.search(query.embeddings.clone(), 5, None),
+        expected.search(query.embeddings.clone(), 5, None)
+    );
+
+    // 追加到指定集合
+    let mut job = IngestJob::add(
+        Resource {
+            embeddings: generate_test_data(10, 16),
+        },
+        Some("docs".to_string()),
+        None,
+    );
+    while !job.step(&mut luna_vdb, 3).unwrap() {}
+    assert_eq!(luna_vdb.size(Some("docs".to_string())), 10);
+
+    // 分步反序列化
+    let serialized = luna_vdb.serialize(None).unwrap();
+    let mut job = LoadJob::new(serialized.clone(), None);
+    while !job.step(1024).unwrap() {}
+    assert_eq!(job.progress().done, job.progress().total);
+
+    let restored = job.finish().unwrap();
+    assert_eq!(restored.size(None), 100);
+    assert_eq!(restored.size(Some("docs".to_string())), 10);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_search_batch() {
+    console_log!("Starting test_luna_vdb_search_batch");
+
+    let embeddings = generate_test_data(200, 16);
+    let queries = embeddings
+        .iter()
+        .step_by(20)
+        .map(|resource| resource.embeddings.clone())
+        .collect::<Vec<Vec<f32>>>();
+    let luna_vdb = LunaVDB::new(Some(Resource { embeddings }));
+
+    let batch = luna_vdb.search_batch(
+        BatchQuery {
+            queries: queries.clone(),
+        },
+        3,
+        None,
+    );
+    assert_eq!(batch.results.len(), 10);
+
+    for (query, result) in queries.iter().zip(batch.results) {
+        assert_eq!(result, luna_vdb.search(query.clone(), 3, None));
+    }
+
+    // 不存在的集合每个查询返回空结果
+    let batch = luna_vdb.search_batch(BatchQuery { queries }, 3, Some("missing".to_string()));
+    assert!(batch.results.iter().all(|result| result.neighbors.is_empty()));
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_expiration() {
+    console_log!("Starting test_luna_vdb_expiration");
+
+    let mut embeddings = generate_test_data(20, 8);
+    let expired = embeddings[0].clone();
+    embeddings[0].expires_at = Some(1.0);
+    embeddings[1].ttl = Some(60_000.0);
+
+    let mut luna_vdb = LunaVDB::new(Some(Resource { embeddings: embeddings.clone() }));
+    assert_eq!(luna_vdb.size(None), 20);
+
+    // 搜索跳过已过期的条目
+    let result = luna_vdb.search(expired.embeddings.clone(), 20, None);
+    assert_eq!(result.neighbors.len(), 19);
+    assert!(result.neighbors.iter().all(|neighbor| neighbor.id != expired.id));
+
+    // 过期时间在序列化后保留
+    let mut restored = LunaVDB::deserialize(luna_vdb.serialize(None).unwrap()).unwrap();
+    assert_eq!(restored.search(expired.embeddings.clone(), 20, None), result);
+    assert_eq!(restored.purge_expired(None, None).unwrap(), vec![expired.id.clone()]);
+
+    assert_eq!(luna_vdb.purge_expired(None, None).unwrap(), vec![expired.id.clone()]);
+    assert_eq!(luna_vdb.size(None), 19);
+
+    // 指定时间清理使用 ttl 的条目
+    let removed = luna_vdb.purge_expired(Some(f64::MAX), None).unwrap();
+    assert_eq!(removed, vec![embeddings[1].id.clone()]);
+    assert_eq!(luna_vdb.size(None), 18);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_eviction() {
+    console_log!("Starting test_luna_vdb_eviction");
+
+    let mut luna_vdb = LunaVDB::with_options(IndexOptions {
+        max_vectors: Some(5),
+        eviction: Eviction::Lru,
+        ..Default::default()
+    });
+
+    let embeddings = generate_test_data(5, 8);
+    assert!(luna_vdb.add(Resource { embeddings: embeddings.clone() }, None).unwrap().is_empty());
+
+    // 检索过的条目不会被优先淘汰
+    let result = luna_vdb.search(embeddings[0].embeddings.clone(), 1, None);
+    assert_eq!(result.neighbors[0].id, embeddings[0].id);
+
+    // 快照上的检索不影响数据库的淘汰顺序
+    let snapshot = luna_vdb.snapshot();
+    snapshot.search(embeddings[1].embeddings.clone(), 1, None);
+    drop(snapshot);
+
+    let evicted = luna_vdb
+        .add(
+            Resource {
+                embeddings: generate_test_data(2, 8),
+            },
+            None,
+        )
+        .unwrap();
+    assert_eq!(evicted, vec![embeddings[1].id.clone(), embeddings[2].id.clone()]);
+    assert_eq!(luna_vdb.size(None), 5);
+
+    // 分步任务同样返回被淘汰的 id
+    let mut job = IngestJob::add(
+        Resource {
+            embeddings: generate_test_data(1, 8),
+        },
+        None,
+        None,
+    );
+    while !job.step(&mut luna_vdb, 10).unwrap() {}
+    assert_eq!(job.take_evicted(), vec![embeddings[3].id.clone()]);
+    assert!(job.take_evicted().is_empty());
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_scan() {
+    console_log!("Starting test_luna_vdb_scan");
+
+    let embeddings = generate_test_data(30, 8);
+    let mut luna_vdb = LunaVDB::new(Some(Resource { embeddings: embeddings.clone() }));
+
+    let mut expected = embeddings
+        .iter()
+        .map(|resource| resource.id.clone())
+        .collect::<Vec<String>>();
+    expected.sort();
+
+    // 分页遍历全部条目，期间删除尚未遍历的条目
+    let mut ids = vec![];
+    let mut cursor = None;
+
+    loop {
+        let page = luna_vdb.scan(cursor, 7, None, None).unwrap();
+        ids.extend(page.entries.into_iter().map(|entry| entry.id));
+
+        if ids.len() == 7 {
+            luna_vdb.remove(vec![expected[29].clone()], None).unwrap();
+        }
+
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    assert_eq!(ids, expected[..29].to_vec());
+
+    // 返回向量
+    let page = luna_vdb.scan(
+        None,
+        1,
+        Some(ScanOptions {
+            with_vectors: true,
+            ..Default::default()
+        }),
+        None,
+    )
+    .unwrap();
+    let first = embeddings.iter().find(|resource| resource.id == expected[0]).unwrap();
+    assert_eq!(page.entries[0].vector, first.embeddings);
+    assert_eq!(page.cursor, Some(expected[0].clone()));
+
+    // 不存在的集合返回空页
+    let page = luna_vdb.scan(None, 10, None, Some("missing".to_string())).unwrap();
+    assert!(page.entries.is_empty() && page.cursor.is_none());
+
+    // limit 为 0 时抛出错误
+    assert!(luna_vdb.scan(None, 0, None, None).is_err());
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_content() {
+    console_log!("Starting test_luna_vdb_content");
+
+    let mut embeddings = generate_test_data(10, 8);
+    for resource in embeddings.iter_mut() {
+        resource.content = Some(format!("chunk {}", resource.id));
+    }
+
+    let mut luna_vdb = LunaVDB::new(Some(Resource { embeddings: embeddings.clone() }));
+    let query = embeddings[3].clone();
+
+    // 搜索时按需返回原文
+    let result = luna_vdb.search_with(
+        query.embeddings.clone(),
+        1,
+        SearchOptions {
+            with_content: true,
+            ..Default::default()
+        },
+        None,
+    );
+    assert_eq!(result.neighbors[0].content, query.content);
+    assert_eq!(result.neighbors[0].id, query.id);
+
+    let entry = luna_vdb.get(query.id.clone(), None).unwrap();
+    assert_eq!(entry.content, query.content);
+    assert!(luna_vdb.get("missing".to_string(), None).is_none());
+
+    // 序列化后原文仍然存在
+    let restored = LunaVDB::deserialize(luna_vdb.serialize(None).unwrap()).unwrap();
+    assert_eq!(restored.get(query.id.clone(), None).unwrap().content, query.content);
+
+    // upsert 更新原文
+    let mut updated = query.clone();
+    updated.content = Some("updated".to_string());
+    luna_vdb.upsert(Resource { embeddings: vec![updated] }, None).unwrap();
+    assert_eq!(luna_vdb.size(None), 10);
+    assert_eq!(
+        luna_vdb.get(query.id.clone(), None).unwrap().content.as_deref(),
+        Some("updated")
+    );
+
+    luna_vdb.remove(vec![query.id.clone()], None).unwrap();
+    assert!(luna_vdb.get(query.id.clone(), None).is_none());
+}
+
+#[wasm_bindgen_test]
+async fn test_luna_vdb_embedding() {
+    use wasm_bindgen_futures::JsFuture;
+
+    console_log!("Starting test_luna_vdb_embedding");
+
+    // 按字母计数的同步嵌入函数
+    let embedder = js_sys::Function::new_with_args(
+        "texts",
+        "return texts.map(t => ['a', 'e', 'i', 'o'].map(c => t.split(c).length - 1));",
+    );
+
+    // 未设置嵌入函数时 Promise 被拒绝
+    let mut luna_vdb = LunaVDB::new(None);
+    assert!(JsFuture::from(luna_vdb.embed_query("kiwi".to_string())).await.is_err());
+    let texts = vec![TextEntry {
+        id: "kiwi".to_string(),
+        text: "kiwi".to_string(),
+        metadata: None,
+    }];
+    assert!(JsFuture::from(luna_vdb.add_texts(TextResource { texts }, None)).await.is_err());
+
+    luna_vdb.set_embedder(
+        embedder,
+        Some(EmbedderOptions {
+            batch_size: 2,
+            cache_size: 8,
+        }),
+    );
+
+    let entries = |texts: &[&str]| {
+        texts
+            .iter()
+            .map(|text| TextEntry {
+                id: text.to_string(),
+                text: text.to_string(),
+                metadata: None,
+            })
+            .collect::<Vec<TextEntry>>()
+    };
+    let texts = entries(&["banana", "apple", "kiwi", "potato"]);
+    JsFuture::from(luna_vdb.add_texts(TextResource { texts }, None))
+        .await
+        .unwrap();
+    assert_eq!(luna_vdb.size(None), 4);
+
+    let result = JsFuture::from(luna_vdb.search_text("kiwi".to_string(), 1, None))
+        .await
+        .unwrap();
+    let result: SearchResult = serde_wasm_bindgen::from_value(result).unwrap();
+    assert_eq!(result.neighbors[0].id, "kiwi");
+    assert_eq!(result.neighbors[0].content.as_deref(), Some("kiwi"));
+
+    // 异步嵌入函数同样可用，失败时整批都不添加
+    let embedder = js_sys::Function::new_with_args(
+        "texts",
+        "return Promise.resolve(texts.map(t => [t.length, 1]));",
+    );
+    luna_vdb.set_embedder(embedder, None);
+
+    let texts = entries(&["melon", "fig"]);
+    JsFuture::from(luna_vdb.add_texts(TextResource { texts }, Some("async".to_string())))
+        .await
+        .unwrap();
+    assert_eq!(luna_vdb.size(Some("async".to_string())), 2);
+
+    let texts = entries(&["plum", "melon"]);
+    let added = JsFuture::from(luna_vdb.add_texts(TextResource { texts }, Some("async".to_string())));
+    assert!(added.await.is_err());
+    assert_eq!(luna_vdb.size(Some("async".to_string())), 2);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_remove_where() {
+    console_log!("Starting test_luna_vdb_remove_where");
+
+    let mut embeddings = generate_test_data(20, 8);
+    for (i, resource) in embeddings.iter_mut().enumerate() {
+        let mut metadata = std::collections::HashMap::new();
+        metadata.insert("user".to_string(), (i % 4).to_string());
+        resource.metadata = Some(metadata);
+    }
+
+    let mut luna_vdb = LunaVDB::new(Some(Resource { embeddings: embeddings.clone() }));
+
+    // 删除某个用户的所有条目
+    let filter = MetadataFilter::Eq {
+        key: "user".to_string(),
+        value: "1".to_string(),
+    };
+    let mut expected = embeddings
+        .iter()
+        .skip(1)
+        .step_by(4)
+        .map(|resource| resource.id.clone())
+        .collect::<Vec<String>>();
+    expected.sort();
+
+    assert_eq!(luna_vdb.remove_where(filter.clone(), None).unwrap(), expected);
+    assert_eq!(luna_vdb.size(None), 15);
+    assert!(luna_vdb.remove_where(filter, None).unwrap().is_empty());
+
+    let rest = MetadataFilter::In {
+        key: "user".to_string(),
+        values: vec!["0".to_string(), "2".to_string(), "3".to_string()],
+    };
+    assert_eq!(luna_vdb.remove_where(rest, None).unwrap().len(), 15);
+    assert_eq!(luna_vdb.size(None), 0);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_transaction() {
+    console_log!("Starting test_luna_vdb_transaction");
+
+    let embeddings = generate_test_data(10, 8);
+    let mut luna_vdb = LunaVDB::new(Some(Resource {
+        embeddings: embeddings[..5].to_vec(),
+    }));
+
+    // 一次事务中混合添加、更新和删除
+    let mut updated = embeddings[0].clone();
+    updated.content = Some("updated".to_string());
+    let operations = vec![
+        Operation::Add(embeddings[5].clone()),
+        Operation::Upsert(updated),
+        Operation::Remove(vec![embeddings[1].id.clone(), embeddings[2].id.clone()]),
+        Operation::Add(embeddings[6].clone()),
+    ];
+    let evicted = luna_vdb.transact(Transaction { operations }, None).unwrap();
+    assert!(evicted.is_empty());
+    assert_eq!(luna_vdb.size(None), 5);
+    assert!(luna_vdb.get(embeddings[1].id.clone(), None).is_none());
+    assert_eq!(
+        luna_vdb.get(embeddings[0].id.clone(), None).unwrap().content.as_deref(),
+        Some("updated")
+    );
+
+    let result = luna_vdb.search(embeddings[6].embeddings.clone(), 1, None);
+    assert_eq!(result.neighbors[0].id, embeddings[6].id);
+
+    // 失败的操作回滚整个事务并返回错误
+    let operations = vec![
+        Operation::Add(embeddings[7].clone()),
+        Operation::Add(embeddings[5].clone()),
+    ];
+    assert!(luna_vdb.transact(Transaction { operations }, None).is_err());
+    assert_eq!(luna_vdb.size(None), 5);
+    assert!(luna_vdb.get(embeddings[7].id.clone(), None).is_none());
+
+    // 失败的事务不会留下为它新建的空集合
+    let operations = vec![
+        Operation::Add(embeddings[7].clone()),
+        Operation::Add(embeddings[7].clone()),
+    ];
+    let collection = Some("new".to_string());
+    assert!(luna_vdb.transact(Transaction { operations }, collection).is_err());
+    assert!(!luna_vdb.has_collection("new".to_string()));
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_fork() {
+    console_log!("Starting test_luna_vdb_fork");
+
+    let embeddings = generate_test_data(20, 8);
+    let mut luna_vdb = LunaVDB::new(Some(Resource {
+        embeddings: embeddings[..10].to_vec(),
+    }));
+
+    // 快照不受之后的写入影响
+    let snapshot = luna_vdb.snapshot();
+    let serialized = snapshot.serialize(None).unwrap();
+    luna_vdb.add(Resource { embeddings: embeddings[10..15].to_vec() }, None).unwrap();
+    luna_vdb.remove(vec![embeddings[0].id.clone()], None).unwrap();
+    assert_eq!(luna_vdb.size(None), 14);
+    assert_eq!(snapshot.size(None), 10);
+    assert!(snapshot.get(embeddings[0].id.clone(), None).is_some());
+    assert_eq!(snapshot.serialize(None).unwrap(), serialized);
+
+    let result = snapshot.search(embeddings[12].embeddings.clone(), 1, None);
+    assert_ne!(result.neighbors[0].id, embeddings[12].id);
+
+    // 分支上的试验性写入可以直接丢弃
+    let mut fork = luna_vdb.fork();
+    fork.add(Resource { embeddings: embeddings[15..].to_vec() }, None).unwrap();
+    fork.create_collection("scratch".to_string(), None).unwrap();
+    assert_eq!(fork.size(None), 19);
+    assert_eq!(luna_vdb.size(None), 14);
+    assert!(!luna_vdb.has_collection("scratch".to_string()));
+    drop(fork);
+
+    assert_eq!(LunaVDB::deserialize(serialized).unwrap().size(None), 10);
+}
+
+#[wasm_bindgen_test]
+async fn test_luna_vdb_storage() {
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_futures::JsFuture;
+
+    console_log!("Starting test_luna_vdb_storage");
+
+    // 内存中的存储适配器，write 为异步回调，fail(n) 让之后的 n 次写入失败
+    let adapter = js_sys::Function::new_no_args(
+        "const data = new Map();
+        let failures = 0;
+        return {
+            data,
+            fail: n => { failures = n; },
+            read: key => data.get(key),
+            write: async (key, bytes) => {
+                if (failures > 0) {
+                    failures--;
+                    throw new Error('write failed');
+                }
+                data.set(key, bytes.slice());
+            },
+            delete: key => { data.delete(key); },
+        };",
+    )
+    .call0(&wasm_bindgen::JsValue::NULL)
+    .unwrap()
+    .unchecked_into::<StorageAdapter>();
+    let fail = js_sys::Reflect::get(&adapter, &"fail".into())
+        .unwrap()
+        .unchecked_into::<js_sys::Function>();
+
+    let sleep = |ms: u32| {
+        let sleep = js_sys::Function::new_with_args(
+            "ms",
+            "return new Promise(resolve => setTimeout(resolve, ms));",
+        );
+        let promise = sleep.call1(&wasm_bindgen::JsValue::NULL, &ms.into()).unwrap();
+        JsFuture::from(promise.unchecked_into::<js_sys::Promise>())
+    };
+
+    let options = AutosaveOptions {
+        max_changes: Some(2),
+        interval: None,
+    };
+    let mut luna_vdb = LunaVDB::open(adapter.clone(), "db".to_string(), Some(options))
+        .await
+        .unwrap();
+    assert_eq!(luna_vdb.size(None), 0);
+
+    // 第二次修改时自动保存，写入完成前修改仍计为未保存
+    let embeddings = generate_test_data(3, 8);
+    luna_vdb.add(Resource { embeddings: embeddings[..1].to_vec() }, None).unwrap();
+    assert_eq!(luna_vdb.unsaved_changes(), 1);
+    luna_vdb.add(Resource { embeddings: embeddings[1..2].to_vec() }, None).unwrap();
+    assert_eq!(luna_vdb.unsaved_changes(), 2);
+    sleep(0).await.unwrap();
+    assert_eq!(luna_vdb.unsaved_changes(), 0);
+
+    luna_vdb.add(Resource { embeddings: embeddings[2..].to_vec() }, None).unwrap();
+    JsFuture::from(luna_vdb.flush().unwrap()).await.unwrap();
+
+    // 重新打开时读取保存的数据
+    let reopened = LunaVDB::open(adapter.clone(), "db".to_string(), None)
+        .await
+        .unwrap();
+    assert_eq!(reopened.size(None), 3);
+
+    JsFuture::from(luna_vdb.delete_saved().unwrap()).await.unwrap();
+    let empty = LunaVDB::open(adapter.clone(), "db".to_string(), None).await.unwrap();
+    assert_eq!(empty.size(None), 0);
+
+    // 一次添加多条按条目数计数
+    luna_vdb.add(Resource { embeddings: generate_test_data(2, 8) }, None).unwrap();
+    sleep(0).await.unwrap();
+    assert_eq!(luna_vdb.unsaved_changes(), 0);
+    luna_vdb.add(Resource { embeddings: generate_test_data(1, 8) }, None).unwrap();
+    assert_eq!(luna_vdb.unsaved_changes(), 1);
+
+    // 间隔模式下最后一批修改在间隔结束时保存，不必等到下一次修改
+    let options = AutosaveOptions {
+        max_changes: None,
+        interval: Some(20.0),
+    };
+    let mut trailing = LunaVDB::open(adapter.clone(), "trailing".to_string(), Some(options))
+        .await
+        .unwrap();
+    trailing.add(Resource { embeddings: embeddings.clone() }, None).unwrap();
+    assert_eq!(trailing.unsaved_changes(), 3);
+    sleep(50).await.unwrap();
+    assert_eq!(trailing.unsaved_changes(), 0);
+
+    let reopened = LunaVDB::open(adapter.clone(), "trailing".to_string(), None)
+        .await
+        .unwrap();
+    assert_eq!(reopened.size(None), 3);
+
+    // 保存失败时修改仍计为未保存，并且不等新的修改就自动重试
+    let options = AutosaveOptions {
+        max_changes: Some(1),
+        interval: Some(20.0),
+    };
+    let mut retried = LunaVDB::open(adapter.clone(), "retried".to_string(), Some(options))
+        .await
+        .unwrap();
+    fail.call1(&adapter, &1.into()).unwrap();
+    retried.add(Resource { embeddings: embeddings[..2].to_vec() }, None).unwrap();
+    sleep(0).await.unwrap();
+    assert_eq!(retried.unsaved_changes(), 2);
+    let reopened = LunaVDB::open(adapter.clone(), "retried".to_string(), None)
+        .await
+        .unwrap();
+    assert_eq!(reopened.size(None), 0);
+
+    sleep(50).await.unwrap();
+    assert_eq!(retried.unsaved_changes(), 0);
+    let reopened = LunaVDB::open(adapter, "retried".to_string(), None)
+        .await
+        .unwrap();
+    assert_eq!(reopened.size(None), 2);
+
+    // 未连接存储适配器时 flush 和 delete_saved 抛出错误
+    let mut detached = LunaVDB::new(None);
+    assert!(detached.flush().is_err());
+    assert!(detached.delete_saved().is_err());
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_evaluate() {
+    console_log!("Starting test_luna_vdb_evaluate");
+
+    let embeddings = generate_test_data(100, 16);
+    let luna_vdb = LunaVDB::new(Some(Resource {
+        embeddings: embeddings.clone(),
+    }));
+    let queries = embeddings
+        .iter()
+        .step_by(10)
+        .map(|resource| resource.embeddings.clone())
+        .collect::<Vec<Vec<f32>>>();
+
+    // 默认的 kd-tree 是精确的
+    let evaluation = luna_vdb.evaluate(BatchQuery { queries: queries.clone() }, 5, None);
+    assert_eq!(evaluation.queries, 10);
+    assert_eq!(evaluation.recall, 1.0);
+    assert_eq!(evaluation.exact_mean_distances, 100.0);
+    assert!(evaluation.mean_distances <= 100.0);
+
+    // 不存在的集合没有结果，也没有距离计算
+    let missing = luna_vdb.evaluate(BatchQuery { queries }, 5, Some("missing".to_string()));
+    assert_eq!(missing.recall, 1.0);
+    assert_eq!(missing.exact_mean_distances, 0.0);
+}
+
+#[wasm_bindgen_test]
+fn test_luna_vdb_search_explain() {
+    console_log!("Starting test_luna_vdb_search_explain");
+
+    let embeddings = generate_test_data(100, 16);
+    let luna_vdb = LunaVDB::new(Some(Resource {
+        embeddings: embeddings.clone(),
+    }));
+    let query = embeddings[0].embeddings.clone();
+
+    // 请求解释时随结果返回搜索的工作量
+    let result = luna_vdb.search_with(
+        query.clone(),
+        5,
+        SearchOptions {
+            explain: true,
+            ..Default::default()
+        },
+        None,
+    );
+    let explain = result.explain.unwrap();
+    assert_eq!(result.neighbors.len(), 5);
+    assert_eq!(result.neighbors[0].id, embeddings[0].id);
+    assert_eq!(explain.rounds, 1);
+    assert_eq!(explain.candidates, 5);
+    assert!(explain.distances >= 5 && explain.distances <= 100);
+
+    // 默认不返回解释
+    assert!(luna_vdb.search(query, 5, None).explain.is_none());
+}